@@ -0,0 +1,335 @@
+use geom::{Aabb, Ray, Intersection};
+
+/// Number of buckets used when estimating split costs
+const NUM_BUCKETS: usize = 12;
+
+/// Primitives per leaf below which splitting stops
+const MAX_LEAF_SIZE: usize = 2;
+
+#[derive(Debug, Clone)]
+enum Node {
+    Leaf { bounds: Aabb, start: usize, count: usize },
+    Branch { bounds: Aabb, left: usize, right: usize }
+}
+
+impl Node {
+    fn bounds(&self) -> &Aabb {
+        match *self {
+            Node::Leaf { ref bounds, .. } => bounds,
+            Node::Branch { ref bounds, .. } => bounds
+        }
+    }
+}
+
+/// Bounding volume hierarchy over an indexed list of primitives.
+/// The hierarchy only stores indices, so the primitives themselves stay wherever the owner keeps them.
+/// Primitives with infinite bounds (planes and such) can't be partitioned and are kept in a flat list
+/// that every query tests.
+#[derive(Debug, Clone, Default)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+    unbounded: Vec<usize>,
+    len: usize
+}
+
+impl Bvh {
+
+    /// Builds hierarchy from the bounds of each primitive, using the surface area heuristic.
+    pub fn build(bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: Vec::new(),
+            unbounded: Vec::new(),
+            len: bounds.len()
+        };
+
+        // Separates the primitives that can be partitioned from those that can't
+        let mut indices = Vec::new();
+        for (i, b) in bounds.iter().enumerate() {
+            if b.is_finite() { indices.push(i); }
+            else { bvh.unbounded.push(i); }
+        }
+
+        if !indices.is_empty() {
+            let count = indices.len();
+            bvh.indices = indices;
+            bvh.build_node(bounds, 0, count);
+        }
+        bvh
+    }
+
+    /// Number of primitives the hierarchy was built over
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Bounds of every bounded primitive
+    pub fn bounds(&self) -> Aabb {
+        match self.nodes.first() {
+            Some(root) => *root.bounds(),
+            None => Aabb::empty()
+        }
+    }
+
+    /// Recomputes node bounds after primitives have moved, keeping the tree topology.
+    /// Cheaper than a rebuild, but the tree degrades if primitives move far.
+    /// Returns false if the primitives no longer fit the tree, in which case it must be rebuilt.
+    pub fn refit(&mut self, bounds: &[Aabb]) -> bool {
+        if bounds.len() != self.len { return false; }
+        if self.indices.iter().any(|&i| !bounds[i].is_finite()) { return false; }
+        if self.unbounded.iter().any(|&i| bounds[i].is_finite()) { return false; }
+
+        // Children always come after their parent, so walking backwards visits children first.
+        for n in (0..self.nodes.len()).rev() {
+            let new_bounds = match self.nodes[n] {
+                Node::Leaf { start, count, .. } => self.indices[start..start+count]
+                    .iter()
+                    .fold(Aabb::empty(), |acc, &i| acc.union(&bounds[i])),
+                Node::Branch { left, right, .. } => self.nodes[left].bounds().union(self.nodes[right].bounds())
+            };
+            match self.nodes[n] {
+                Node::Leaf { ref mut bounds, .. } => *bounds = new_bounds,
+                Node::Branch { ref mut bounds, .. } => *bounds = new_bounds
+            }
+        }
+        true
+    }
+
+    /// Finds the closest intersection along the ray.
+    /// 'intersect' is invoked with the index of each primitive the ray may hit.
+    pub fn intersect<'a, F>(&self, ray: &Ray, mut intersect: F) -> Option<Intersection<'a>>
+    where F: FnMut(usize) -> Option<Intersection<'a>> {
+
+        let mut closest: Option<Intersection> = None;
+        for &i in &self.unbounded {
+            keep_closest(&mut closest, intersect(i));
+        }
+
+        if self.nodes.is_empty() { return closest; }
+        let mut stack: Vec<usize> = vec![0];
+        while let Some(n) = stack.pop() {
+            let t_max = closest.map(|c| c.t).unwrap_or(1.0);
+            let node = &self.nodes[n];
+            if node.bounds().hit(ray, t_max).is_none() { continue; }
+            match *node {
+                Node::Leaf { start, count, .. } => {
+                    for &i in &self.indices[start..start+count] {
+                        keep_closest(&mut closest, intersect(i));
+                    }
+                },
+                Node::Branch { left, right, .. } => {
+
+                    // Visits nearer child first so that the farther one is more likely to be culled
+                    let t_left = self.nodes[left].bounds().hit(ray, t_max);
+                    let t_right = self.nodes[right].bounds().hit(ray, t_max);
+                    match (t_left, t_right) {
+                        (Some(tl), Some(tr)) => {
+                            if tl <= tr { stack.push(right); stack.push(left); }
+                            else { stack.push(left); stack.push(right); }
+                        },
+                        (Some(_), None) => stack.push(left),
+                        (None, Some(_)) => stack.push(right),
+                        (None, None) => {}
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    /// Returns true as soon as any primitive reports a hit along the ray.
+    pub fn intersects<F>(&self, ray: &Ray, mut intersects: F) -> bool
+    where F: FnMut(usize) -> bool {
+
+        if self.unbounded.iter().any(|&i| intersects(i)) { return true; }
+
+        if self.nodes.is_empty() { return false; }
+        let mut stack: Vec<usize> = vec![0];
+        while let Some(n) = stack.pop() {
+            let node = &self.nodes[n];
+            if node.bounds().hit(ray, 1.0).is_none() { continue; }
+            match *node {
+                Node::Leaf { start, count, .. } => {
+                    if self.indices[start..start+count].iter().any(|&i| intersects(i)) {
+                        return true;
+                    }
+                },
+                Node::Branch { left, right, .. } => {
+                    stack.push(right);
+                    stack.push(left);
+                }
+            }
+        }
+        false
+    }
+
+    /// Builds node over indices[start..start+count] and returns its index
+    fn build_node(&mut self, bounds: &[Aabb], start: usize, count: usize) -> usize {
+
+        // Bounds of the primitives and of their centroids
+        let slice = &self.indices[start..start+count];
+        let node_bounds = slice.iter().fold(Aabb::empty(), |acc, &i| acc.union(&bounds[i]));
+        let centroid_bounds = slice.iter().fold(Aabb::empty(), |acc, &i| acc.grow(&bounds[i].centroid()));
+
+        let node_index = self.nodes.len();
+        self.nodes.push(Node::Leaf { bounds: node_bounds, start, count });
+        if count <= MAX_LEAF_SIZE { return node_index; }
+
+        // All centroids in the same spot can't be split
+        let axis = centroid_bounds.longest_axis();
+        let axis_min = centroid_bounds.min.axis(axis);
+        let axis_extent = centroid_bounds.max.axis(axis) - axis_min;
+        if axis_extent <= 0.0 { return node_index; }
+
+        // Buckets primitives by centroid
+        let bucket_of = |b: &Aabb| -> usize {
+            let offset = (b.centroid().axis(axis) - axis_min) / axis_extent;
+            ((offset * NUM_BUCKETS as f64) as usize).min(NUM_BUCKETS - 1)
+        };
+        let mut bucket_counts = [0usize; NUM_BUCKETS];
+        let mut bucket_bounds = [Aabb::empty(); NUM_BUCKETS];
+        for &i in slice {
+            let bucket = bucket_of(&bounds[i]);
+            bucket_counts[bucket] += 1;
+            bucket_bounds[bucket] = bucket_bounds[bucket].union(&bounds[i]);
+        }
+
+        // Finds cheapest split between buckets
+        let mut best_cost = f64::INFINITY;
+        let mut best_split = 0;
+        for split in 0..NUM_BUCKETS-1 {
+            let (mut left_bounds, mut right_bounds) = (Aabb::empty(), Aabb::empty());
+            let (mut left_count, mut right_count) = (0, 0);
+            for b in 0..NUM_BUCKETS {
+                if b <= split {
+                    left_bounds = left_bounds.union(&bucket_bounds[b]);
+                    left_count += bucket_counts[b];
+                }
+                else {
+                    right_bounds = right_bounds.union(&bucket_bounds[b]);
+                    right_count += bucket_counts[b];
+                }
+            }
+            if left_count == 0 || right_count == 0 { continue; }
+            let cost = left_bounds.surface_area() * left_count as f64 + right_bounds.surface_area() * right_count as f64;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        // Keeps a leaf if splitting is no cheaper than testing everything
+        let leaf_cost = node_bounds.surface_area() * count as f64;
+        if best_cost >= leaf_cost && count <= 4 * MAX_LEAF_SIZE { return node_index; }
+
+        // Partitions indices around the split
+        let mut mid = start;
+        for j in start..start+count {
+            let i = self.indices[j];
+            if bucket_of(&bounds[i]) <= best_split {
+                self.indices.swap(j, mid);
+                mid += 1;
+            }
+        }
+        if mid == start || mid == start + count {
+            mid = start + count/2;
+        }
+
+        let left = self.build_node(bounds, start, mid - start);
+        let right = self.build_node(bounds, mid, start + count - mid);
+        self.nodes[node_index] = Node::Branch { bounds: node_bounds, left, right };
+        node_index
+    }
+}
+
+/// Replaces 'closest' with 'candidate' if the candidate is nearer
+fn keep_closest<'a>(closest: &mut Option<Intersection<'a>>, candidate: Option<Intersection<'a>>) {
+    if let Some(inter) = candidate {
+        match *closest {
+            Some(ref c) if c.t <= inter.t => {},
+            _ => *closest = Some(inter)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geom::Vector;
+    use material::Material;
+    use rand::Rng;
+    use sampling::pixel_rng;
+    use scene_file::parse_scene;
+    use shape::{Shape, Sphere, Floor};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    fn random_vector<R: Rng>(rng: &mut R, size: f64) -> Vector {
+        Vector::new(rng.gen_range(-size, size), rng.gen_range(-size, size), rng.gen_range(-size, size))
+    }
+
+    #[test]
+    fn agrees_with_testing_every_shape() {
+        let mut rng = pixel_rng(1, 2, 3);
+        let material = Arc::new(Material::new(Vector::new(1.0, 1.0, 1.0), 0.0, 1.0));
+        let mut shapes: Vec<Box<dyn Shape>> = (0..200)
+            .map(|_| Box::new(Sphere {
+                center: random_vector(&mut rng, 10.0),
+                radius: rng.gen_range(0.1, 1.0),
+                material: material.clone()
+            }) as Box<dyn Shape>)
+            .collect();
+        shapes.push(Box::new(Floor { position: Vector::new(0.0, -9.0, 0.0), material: material.clone() }));
+        let bounds: Vec<Aabb> = shapes.iter().map(|s| s.bounds()).collect();
+        let bvh = Bvh::build(&bounds);
+        assert_eq!(bvh.len(), shapes.len());
+
+        let mut hits = 0;
+        for _ in 0..2000 {
+            let ray = Ray::new(random_vector(&mut rng, 12.0), random_vector(&mut rng, 30.0));
+            let expected = shapes.iter()
+                .filter_map(|s| s.intersect(&ray))
+                .map(|inter| inter.t)
+                .fold(None, |closest: Option<f64>, t| Some(closest.map_or(t, |c| c.min(t))));
+            let found = bvh.intersect(&ray, |i| shapes[i].intersect(&ray)).map(|inter| inter.t);
+            assert_eq!(found, expected);
+            assert_eq!(bvh.intersects(&ray, |i| shapes[i].intersects(&ray)), expected.is_some());
+            if expected.is_some() { hits += 1; }
+        }
+
+        // Makes sure the rays tested both hits and misses
+        assert!(hits > 200 && hits < 1800, "{} of 2000 rays hit", hits);
+    }
+
+    #[test]
+    fn finds_moved_shapes_after_refit() {
+        let source = "color_background = [0.0, 0.0, 0.0]\ncolor_ambient = [0.0, 0.0, 0.0]\nbounce_limit = 1\n\
+            [camera]\norigin = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\nup = [0.0, 1.0, 0.0]\nnear_dist = 1.0\n\
+            far_dist = 100.0\nfrust_width = 1.0\nfrust_height = 1.0\n\
+            [materials.m]\ncolor = [1.0, 1.0, 1.0]\nreflectivity = 0.0\nexponent = 1.0\n\
+            [[shapes]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"m\"\n\
+            [[shapes]]\ntype = \"sphere\"\ncenter = [10.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"m\"\n\
+            [[shapes]]\ntype = \"sphere\"\ncenter = [-10.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"m\"\n";
+        let mut scene = parse_scene(source, Path::new("")).unwrap();
+        let ray = Ray::new(Vector::new(0.0, 0.0, 5.0), Vector::new(0.0, 0.0, -10.0));
+        let t = scene.intersect(&ray).unwrap().t;
+        assert!((t - 0.4).abs() < 1e-9);
+
+        // Moves the far right sphere between the camera and the one it saw
+        scene.shapes_mut()[1].set_position(&Vector::new(0.0, 0.0, 3.0));
+        scene.refit();
+        let t = scene.intersect(&ray).unwrap().t;
+        assert!((t - 0.1).abs() < 1e-9, "closest hit is at {}", t);
+
+        // And back out of the way, leaving the first sphere closest again
+        scene.shapes_mut()[1].set_position(&Vector::new(0.0, 50.0, 0.0));
+        scene.refit();
+        let t = scene.intersect(&ray).unwrap().t;
+        assert!((t - 0.4).abs() < 1e-9, "closest hit is at {}", t);
+    }
+}
@@ -0,0 +1,222 @@
+use geom::Vector;
+use light::LightSample;
+use sampling::{Distribution, SamplePattern};
+use texture::ImageTexture;
+use rand::Rng;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+/// Light arriving from infinitely far away in every direction, read from an equirectangular image.
+/// The image's columns run once around the y axis, with its middle column straight ahead along -z and the columns to
+/// its right turning towards +x. Its rows run from straight up at the top to straight down at the bottom.
+/// Rays that miss everything see the environment, and it lights the scene like any other light, through 'samples'
+/// shadow rays per point lit. These are aimed more often towards the brighter parts of the image.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    image: ImageTexture,
+
+    /// Scales the image's colors, so that images stored as 0 to 1 can light scenes more brightly
+    pub intensity: f64,
+
+    /// Turn about the y axis, in radians, anticlockwise seen from above
+    pub rotation: f64,
+
+    pub samples: u32,
+
+    /// Picks a row of texels in proportion to the light they bring, then a column within the row likewise
+    rows: Arc<Distribution>,
+    columns: Arc<Vec<Distribution>>
+}
+
+impl Environment {
+
+    pub fn new(image: ImageTexture, intensity: f64, rotation: f64, samples: u32) -> Environment {
+        let (width, height) = (image.width(), image.height());
+        let luminance: Vec<f64> = (0..width * height)
+            .map(|i| image.texel((i % width) as i64, (i / width) as i64).luminance())
+            .collect();
+        let mut row_weights = Vec::with_capacity(height);
+        let mut columns = Vec::with_capacity(height);
+        for y in 0..height {
+
+            // Rows towards the poles cover less of the sphere of directions
+            let latitude = (0.5 - (y as f64 + 0.5) / height as f64) * PI;
+
+            // Filtering spreads each texel into its neighbours, so each texel is weighted by the brightest around it.
+            // Otherwise a dim texel beside a bright sun would rarely be picked, yet bring much of the sun's light.
+            let weights: Vec<f64> = (0..width)
+                .map(|x| {
+                    let rows = y.saturating_sub(1)..(y + 2).min(height);
+                    let brightest = rows
+                        .flat_map(|ny| (x + width - 1..x + width + 2).map(move |nx| (ny, nx % width)))
+                        .map(|(ny, nx)| luminance[ny * width + nx])
+                        .fold(0.0, f64::max);
+                    brightest * latitude.cos()
+                })
+                .collect();
+            row_weights.push(weights.iter().sum());
+            columns.push(Distribution::new(&weights));
+        }
+        Environment {
+            image,
+            intensity,
+            rotation,
+            samples,
+            rows: Arc::new(Distribution::new(&row_weights)),
+            columns: Arc::new(columns)
+        }
+    }
+
+    pub fn image(&self) -> &ImageTexture { &self.image }
+
+    /// Light arriving from direction 'dir', which need not be of unit length
+    pub fn radiance(&self, dir: &Vector) -> Vector {
+        let local = rotate_y(&dir.to_unit(), -self.rotation);
+        let u = 0.5 + local.x.atan2(-local.z) / (2.0 * PI);
+        let v = 0.5 + local.y.clamp(-1.0, 1.0).asin() / PI;
+
+        // Keeps filtering from blending the top and bottom rows together across the poles
+        let margin = 0.5 / self.image.height().max(1) as f64;
+        self.image.sample([u, v.clamp(margin, 1.0 - margin)]) * self.intensity
+    }
+
+    /// Samples directions towards the environment, stratified over the image.
+    /// As with directional lights, samples reach 'reach' away, standing in for infinity.
+    /// Samples are weighted by how likely they were to be picked, so that lit surfaces come out as bright as they
+    /// would by gathering the light from every direction.
+    pub fn sample<R: Rng>(&self, reach: f64, rng: &mut R) -> Vec<LightSample> {
+        let positions = SamplePattern::Jittered.samples(self.samples.max(1), rng);
+        let weight = 1.0 / positions.len() as f64;
+        let mut samples = Vec::with_capacity(positions.len());
+        for (s, t) in positions {
+            let (down, row, row_density) = self.rows.sample(s);
+            let (u, _, column_density) = match self.columns.get(row) {
+                Some(columns) => columns.sample(t),
+                None => (t, 0, 1.0)
+            };
+            let latitude = (0.5 - down) * PI;
+            let longitude = (u - 0.5) * 2.0 * PI;
+            let (sin_lat, cos_lat) = latitude.sin_cos();
+            if cos_lat <= 0.0 { continue; }
+
+            // Converts the density over the image into one over directions
+            let pdf = row_density * column_density / (2.0 * PI * PI * cos_lat);
+            let local = Vector::new(cos_lat * longitude.sin(), sin_lat, -cos_lat * longitude.cos());
+            let dir = rotate_y(&local, self.rotation);
+            let light = self.radiance(&dir) * (weight / (PI * pdf));
+            let peak = light.x.max(light.y).max(light.z);
+            if peak <= 0.0 || !peak.is_finite() { continue; }
+
+            samples.push(LightSample {
+                dir: dir * reach,
+                color: light / peak,
+                brightness: peak,
+                falloff: 1.0,
+                weight
+            });
+        }
+        samples
+    }
+}
+
+/// Turns 'v' by 'angle' radians about the y axis, anticlockwise seen from above
+fn rotate_y(v: &Vector, angle: f64) -> Vector {
+    let (sin, cos) = angle.sin_cos();
+    Vector::new(v.x * cos + v.z * sin, v.y, -v.x * sin + v.z * cos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use framebuffer::Framebuffer;
+    use texture::{TextureFilter, Wrap};
+    use sampling::pixel_rng;
+
+    /// 16 x 8 environment, turned a little and dimly lit all around, with a few brighter texels
+    fn environment() -> Environment {
+        let mut image = Framebuffer::new(16, 8);
+        for y in 0..8 {
+            for x in 0..16 {
+                image.set(x, y, Vector::new(0.1, 0.2, 0.3));
+            }
+        }
+        image.set(5, 1, Vector::new(40.0, 30.0, 20.0));
+        image.set(12, 6, Vector::new(5.0, 5.0, 5.0));
+        image.set(0, 0, Vector::new(10.0, 0.0, 0.0));
+        Environment::new(ImageTexture::from_framebuffer(&image, TextureFilter::Bilinear, Wrap::Repeat), 2.0, 0.4, 64)
+    }
+
+    /// Column and row of the texel seen along 'dir', following the layout described on Environment
+    fn texel_towards(env: &Environment, dir: &Vector) -> (usize, usize) {
+        let local = rotate_y(&dir.to_unit(), -env.rotation);
+        let u = 0.5 + local.x.atan2(-local.z) / (2.0 * PI);
+        let down = 0.5 - local.y.asin() / PI;
+        let (width, height) = (env.image.width() as f64, env.image.height() as f64);
+        (((u * width) as usize).min(15), ((down * height) as usize).min(7))
+    }
+
+    #[test]
+    fn sampled_light_adds_up_to_the_environment() {
+        let env = environment();
+        let mut rng = pixel_rng(0, 0, 0);
+        let rounds = 2000;
+
+        // Each sample's light is radiance / (pi * pdf), divided between the samples. So light over radiance averages
+        // the inverse of the pdf, which comes to the solid angle of the sphere when the pdf is the density directions
+        // are picked with, and the light itself averages the integral of the radiance.
+        let (mut inverse_pdf, mut light) = (0.0, Vector::new(0.0, 0.0, 0.0));
+        for _ in 0..rounds {
+            for sample in env.sample(1.0, &mut rng) {
+                let radiance = env.radiance(&sample.dir);
+                inverse_pdf += sample.color.y * sample.brightness / radiance.y;
+                light = light + sample.color * sample.brightness;
+            }
+        }
+        let solid_angle = PI * inverse_pdf / rounds as f64;
+        assert!((solid_angle / (4.0 * PI) - 1.0).abs() < 0.02, "pdf integrates to {}", solid_angle / (4.0 * PI));
+
+        // Integrates the radiance over a fine grid of directions instead
+        let steps = 1000;
+        let mut expected = Vector::new(0.0, 0.0, 0.0);
+        for i in 0..steps {
+            let latitude = ((i as f64 + 0.5) / steps as f64 - 0.5) * PI;
+            for j in 0..2 * steps {
+                let longitude = (j as f64 + 0.5) / (2 * steps) as f64 * 2.0 * PI;
+                let dir = Vector::new(latitude.cos() * longitude.sin(), latitude.sin(), latitude.cos() * longitude.cos());
+                expected = expected + env.radiance(&dir) * latitude.cos();
+            }
+        }
+        let expected = expected * (PI / steps as f64) * (PI / steps as f64) / PI;
+        let light = light / rounds as f64;
+        for &(found, wanted) in &[(light.x, expected.x), (light.y, expected.y), (light.z, expected.z)] {
+            assert!((found / wanted - 1.0).abs() < 0.02, "expected {:?}, found {:?}", expected, light);
+        }
+    }
+
+    #[test]
+    fn samples_come_from_the_texels_picked() {
+
+        // Without filtering, only the texels themselves give light, so every sample kept must point at one of them
+        let mut image = Framebuffer::new(16, 8);
+        image.set(5, 1, Vector::new(1.0, 1.0, 1.0));
+        image.set(14, 6, Vector::new(0.0, 3.0, 0.0));
+        let texture = ImageTexture::from_framebuffer(&image, TextureFilter::Nearest, Wrap::Repeat);
+        for &rotation in &[0.0, 1.3] {
+            let env = Environment::new(texture.clone(), 1.0, rotation, 64);
+            let mut rng = pixel_rng(1, 0, 0);
+            let mut found = [0, 0];
+            for _ in 0..100 {
+                for sample in env.sample(1.0, &mut rng) {
+                    match texel_towards(&env, &sample.dir) {
+                        (5, 1) => found[0] += 1,
+                        (14, 6) => found[1] += 1,
+                        texel => panic!("sample towards {:?} at rotation {}", texel, rotation)
+                    }
+                }
+            }
+
+            // Texels are picked in proportion to their luminance, which is about twice as high for the green one
+            assert!(found[0] > 0 && found[1] > found[0] * 3 / 2, "found {:?} at rotation {}", found, rotation);
+        }
+    }
+}
@@ -0,0 +1,388 @@
+use framebuffer::Framebuffer;
+use flate2;
+use flate2::write::ZlibEncoder;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// How each value of a channel is stored
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PixelType {
+
+    /// 16-bit float, with about 3 significant digits, enough for colors
+    Half,
+
+    /// 32-bit float, for values that need more precision, such as depth
+    Float
+}
+
+impl FromStr for PixelType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<PixelType, String> {
+        match s {
+            "half" => Ok(PixelType::Half),
+            "float" => Ok(PixelType::Float),
+            _ => Err(format!("unknown pixel type '{}', expected half or float", s))
+        }
+    }
+}
+
+/// How the pixel data is compressed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Compression {
+    None,
+
+    /// Deflate, 16 rows at a time, losslessly
+    Zip
+}
+
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Compression, String> {
+        match s {
+            "none" => Ok(Compression::None),
+            "zip" => Ok(Compression::Zip),
+            _ => Err(format!("unknown compression '{}', expected none or zip", s))
+        }
+    }
+}
+
+impl Compression {
+
+    /// Rows stored together in each chunk of the file
+    fn rows_per_chunk(&self) -> usize {
+        match *self {
+            Compression::None => 1,
+            Compression::Zip => 16
+        }
+    }
+}
+
+/// Named channel of an OpenEXR file, taking one component of each pixel of a framebuffer
+#[derive(Copy, Clone, Debug, new)]
+pub struct Channel<'a> {
+
+    /// Name in the file. Layers are named with a prefix, as in "normal.X".
+    pub name: &'a str,
+    pub image: &'a Framebuffer,
+
+    /// 0, 1 or 2 for the x, y or z of each pixel
+    pub component: usize,
+    pub pixel_type: PixelType
+}
+
+impl<'a> Channel<'a> {
+
+    /// Red, green and blue channels holding a framebuffer's colors: "R", "G" and "B" for the main image
+    pub fn rgb(image: &'a Framebuffer, pixel_type: PixelType) -> Vec<Channel<'a>> {
+        vec![
+            Channel::new("R", image, 0, pixel_type),
+            Channel::new("G", image, 1, pixel_type),
+            Channel::new("B", image, 2, pixel_type)
+        ]
+    }
+}
+
+/// Saves channels to an OpenEXR file, which must all be the same size
+pub fn save<P: AsRef<Path>>(channels: &[Channel], compression: Compression, path: P) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    write(channels, compression, &mut bytes)?;
+    fs::write(path, bytes)
+}
+
+/// Writes channels as a single-part scanline OpenEXR image.
+/// Channels are stored sorted by name, as the format requires, and rows run from the top down.
+pub fn write<W: Write>(channels: &[Channel], compression: Compression, out: &mut W) -> io::Result<()> {
+    let first = channels.first().ok_or_else(|| invalid("an OpenEXR image needs at least one channel"))?;
+    let (width, height) = (first.image.width, first.image.height);
+    if width == 0 || height == 0 { return Err(invalid("an OpenEXR image can't be empty")); }
+    let mut channels = channels.to_vec();
+    channels.sort_by(|a, b| a.name.cmp(b.name));
+    for (i, channel) in channels.iter().enumerate() {
+        if channel.name.is_empty() || channel.name.len() > 31 {
+            return Err(invalid(&format!("channel name '{}' must be 1 to 31 bytes long", channel.name)));
+        }
+        if i > 0 && channels[i - 1].name == channel.name {
+            return Err(invalid(&format!("channel '{}' is given twice", channel.name)));
+        }
+        if (channel.image.width, channel.image.height) != (width, height) {
+            return Err(invalid(&format!("channel '{}' isn't the same size as the others", channel.name)));
+        }
+        if channel.component > 2 {
+            return Err(invalid(&format!("channel '{}' takes component {} of 0 to 2", channel.name, channel.component)));
+        }
+    }
+
+    let mut file = Vec::new();
+    file.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+    write_header(&channels, width, height, compression, &mut file);
+
+    // Chunks follow a table of their offsets from the start of the file
+    let rows = compression.rows_per_chunk();
+    let chunk_count = height.div_ceil(rows);
+    let table_start = file.len();
+    file.resize(table_start + chunk_count * 8, 0);
+    for chunk in 0..chunk_count {
+        let offset = file.len() as u64;
+        file[table_start + chunk * 8..table_start + chunk * 8 + 8].copy_from_slice(&offset.to_le_bytes());
+
+        let y_start = chunk * rows;
+        let mut data = Vec::new();
+        for y in y_start..(y_start + rows).min(height) {
+            for channel in &channels {
+                for x in 0..width {
+                    let pixel = channel.image.get(x, y);
+                    let value = [pixel.x, pixel.y, pixel.z][channel.component] as f32;
+                    match channel.pixel_type {
+                        PixelType::Half => data.extend_from_slice(&to_half(value).to_le_bytes()),
+                        PixelType::Float => data.extend_from_slice(&value.to_le_bytes())
+                    }
+                }
+            }
+        }
+        let data = match compression {
+            Compression::None => data,
+            Compression::Zip => {
+
+                // Chunks that don't shrink are stored as they are, which readers tell apart by their size
+                let compressed = zip(&data)?;
+                if compressed.len() < data.len() { compressed } else { data }
+            }
+        };
+        file.extend_from_slice(&(y_start as i32).to_le_bytes());
+        file.extend_from_slice(&(data.len() as i32).to_le_bytes());
+        file.extend_from_slice(&data);
+    }
+    out.write_all(&file)
+}
+
+/// Writes the attributes every OpenEXR file needs, ending with a null byte
+fn write_header(channels: &[Channel], width: usize, height: usize, compression: Compression, out: &mut Vec<u8>) {
+    let mut list = Vec::new();
+    for channel in channels {
+        list.extend_from_slice(channel.name.as_bytes());
+        list.push(0);
+        let pixel_type: i32 = match channel.pixel_type {
+            PixelType::Half => 1,
+            PixelType::Float => 2
+        };
+        list.extend_from_slice(&pixel_type.to_le_bytes());
+
+        // Linear flag and reserved bytes, then one sample per pixel in x and in y
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+
+    let mut window = Vec::new();
+    for &n in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&n.to_le_bytes());
+    }
+    let compression: u8 = match compression {
+        Compression::None => 0,
+        Compression::Zip => 3
+    };
+    let mut center = 0f32.to_le_bytes().to_vec();
+    center.extend_from_slice(&0f32.to_le_bytes());
+
+    attribute(out, "channels", "chlist", &list);
+    attribute(out, "compression", "compression", &[compression]);
+    attribute(out, "dataWindow", "box2i", &window);
+    attribute(out, "displayWindow", "box2i", &window);
+    attribute(out, "lineOrder", "lineOrder", &[0]);
+    attribute(out, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    attribute(out, "screenWindowCenter", "v2f", &center);
+    attribute(out, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    out.push(0);
+}
+
+fn attribute(out: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    out.extend_from_slice(name.as_bytes());
+    out.push(0);
+    out.extend_from_slice(kind.as_bytes());
+    out.push(0);
+    out.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    out.extend_from_slice(value);
+}
+
+/// Compresses a chunk the way OpenEXR's ZIP compression does.
+/// Bytes are split into even and odd halves, so that the high and low bytes of values sit together, then replaced by
+/// their difference from the byte before, before being deflated.
+fn zip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let half = data.len().div_ceil(2);
+    let mut split = vec![0u8; data.len()];
+    for (i, &byte) in data.iter().enumerate() {
+        split[if i % 2 == 0 { i / 2 } else { half + i / 2 }] = byte;
+    }
+    let mut previous = split.first().cloned().unwrap_or(0);
+    for byte in split.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = (current as i32 - previous as i32 + (128 + 256)) as u8;
+        previous = current;
+    }
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::Default);
+    encoder.write_all(&split)?;
+    encoder.finish()
+}
+
+/// Rounds a float to the nearest half-precision float, as its bits.
+/// Values too large for a half become infinite, and those too small fade through subnormals to 0.
+fn to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    // Infinity stays infinite, and NaN stays NaN
+    if exponent == 0xff {
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f { return sign | 0x7c00; }
+    if exponent <= 0 {
+
+        // Subnormal, with the implicit leading 1 shifted down into the mantissa
+        if exponent < -10 { return sign; }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        return sign | round_shift(mantissa, shift) as u16;
+    }
+
+    // Rounding may carry into the exponent, which correctly rounds up to the next power of two or to infinity
+    sign | (((exponent as u32) << 10) + round_shift(mantissa, 13)) as u16
+}
+
+/// Shifts 'value' right by 'shift' bits, rounding to the nearest result, with ties going to the even one
+fn round_shift(value: u32, shift: u32) -> u32 {
+    let shifted = value >> shift;
+    let remainder = value & ((1 << shift) - 1);
+    let halfway = 1 << (shift - 1);
+    if remainder > halfway || (remainder == halfway && shifted & 1 == 1) { shifted + 1 } else { shifted }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geom::Vector;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn i32_at(bytes: &[u8], pos: usize) -> i32 {
+        i32::from_le_bytes([bytes[pos], bytes[pos + 1], bytes[pos + 2], bytes[pos + 3]])
+    }
+
+    fn u64_at(bytes: &[u8], pos: usize) -> u64 {
+        let mut value = [0u8; 8];
+        value.copy_from_slice(&bytes[pos..pos + 8]);
+        u64::from_le_bytes(value)
+    }
+
+    /// Null terminated string at 'pos', and the position after it
+    fn string_at(bytes: &[u8], pos: usize) -> (String, usize) {
+        let end = pos + bytes[pos..].iter().position(|&b| b == 0).unwrap();
+        (String::from_utf8(bytes[pos..end].to_vec()).unwrap(), end + 1)
+    }
+
+    #[test]
+    fn rounds_to_half() {
+        assert_eq!(to_half(0.0), 0x0000);
+        assert_eq!(to_half(-0.0), 0x8000);
+        assert_eq!(to_half(1.0), 0x3c00);
+        assert_eq!(to_half(-2.0), 0xc000);
+
+        // Smallest subnormal, and halfway between it and 0 or 2, which round to the even neighbour
+        assert_eq!(to_half(2f32.powi(-24)), 0x0001);
+        assert_eq!(to_half(2f32.powi(-25)), 0x0000);
+        assert_eq!(to_half(3.0 * 2f32.powi(-25)), 0x0002);
+        assert_eq!(to_half(2f32.powi(-14)), 0x0400);
+
+        // Largest normal, and values beyond it, which round up to infinity
+        assert_eq!(to_half(65504.0), 0x7bff);
+        assert_eq!(to_half(65519.0), 0x7bff);
+        assert_eq!(to_half(65520.0), 0x7c00);
+        assert_eq!(to_half(1e10), 0x7c00);
+        assert_eq!(to_half(f32::NEG_INFINITY), 0xfc00);
+        let nan = to_half(f32::NAN);
+        assert!(nan & 0x7c00 == 0x7c00 && nan & 0x3ff != 0);
+
+        // Ties between normals go to the even mantissa
+        assert_eq!(to_half(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(to_half(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+    }
+
+    #[test]
+    fn zip_splits_bytes_and_stores_differences() {
+        let zipped = zip(&[1, 2, 3, 4, 5, 0]).unwrap();
+        let mut decoder = ZlibDecoder::new(&zipped[..]);
+        let mut unzipped = Vec::new();
+        decoder.read_to_end(&mut unzipped).unwrap();
+
+        // Even bytes 1, 3, 5 then odd bytes 2, 4, 0, each stored as its difference from the one before plus 128
+        assert_eq!(unzipped, vec![1, 130, 130, 125, 130, 124]);
+    }
+
+    #[test]
+    fn writes_header_and_offsets() {
+        let mut image = Framebuffer::new(2, 3);
+        for (i, pixel) in image.pixels.iter_mut().enumerate() {
+            *pixel = Vector::new(i as f64, 0.5, -1.0);
+        }
+        let channels = [
+            Channel::new("Z", &image, 0, PixelType::Float),
+            Channel::new("B", &image, 2, PixelType::Half),
+            Channel::new("G", &image, 1, PixelType::Half)
+        ];
+        let mut bytes = Vec::new();
+        write(&channels, Compression::None, &mut bytes).unwrap();
+        assert_eq!(bytes[..8], [0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+
+        // Attributes are a name, a type, a size and a value, until an empty name
+        let mut pos = 8;
+        let mut names = Vec::new();
+        let mut channel_list = Vec::new();
+        loop {
+            let (name, next) = string_at(&bytes, pos);
+            if name.is_empty() { pos = next; break; }
+            let (_, next) = string_at(&bytes, next);
+            let size = i32_at(&bytes, next) as usize;
+            if name == "channels" { channel_list = bytes[next + 4..next + 4 + size].to_vec(); }
+            names.push(name);
+            pos = next + 4 + size;
+        }
+        assert_eq!(names, vec![
+            "channels", "compression", "dataWindow", "displayWindow", "lineOrder", "pixelAspectRatio",
+            "screenWindowCenter", "screenWindowWidth"
+        ]);
+
+        // Channels come sorted by name, each with its pixel type
+        let mut listed = Vec::new();
+        let mut at = 0;
+        while channel_list[at] != 0 {
+            let (name, next) = string_at(&channel_list, at);
+            listed.push((name, i32_at(&channel_list, next)));
+            at = next + 16;
+        }
+        assert_eq!(listed, vec![("B".to_string(), 1), ("G".to_string(), 1), ("Z".to_string(), 2)]);
+
+        // One row per chunk, each two halves and a float per pixel, with the last chunk ending the file
+        let row_size = 2 * (2 + 2 + 4);
+        for y in 0..3 {
+            let offset = u64_at(&bytes, pos + y * 8) as usize;
+            assert_eq!(offset, pos + 3 * 8 + y * (8 + row_size));
+            assert_eq!(i32_at(&bytes, offset), y as i32);
+            assert_eq!(i32_at(&bytes, offset + 4), row_size as i32);
+
+            // Blue comes first, as -1 in half precision
+            assert_eq!(bytes[offset + 8..offset + 10], [0x00, 0xbc]);
+        }
+        assert_eq!(bytes.len(), pos + 3 * 8 + 3 * (8 + row_size));
+    }
+}
@@ -0,0 +1,39 @@
+use geom::Vector;
+use scene::vector_to_color;
+use raster::Image;
+
+/// Image of linear colors as traced, before they are clamped to 0 to 1 for saving as an 8-bit image.
+/// Pixels are stored row by row from the top, like raster::Image.
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vector>
+}
+
+impl Framebuffer {
+
+    /// Black framebuffer
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer { width, height, pixels: vec![Vector::new(0.0, 0.0, 0.0); width * height] }
+    }
+
+    /// Color at column x and row y, counting from the top-left corner
+    pub fn get(&self, x: usize, y: usize) -> Vector {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Vector) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    /// Clamps the colors into an 8-bit image
+    pub fn to_image(&self) -> Image {
+        let mut image = Image::blank(self.width as i32, self.height as i32);
+        for (i, color) in self.pixels.iter().enumerate() {
+            let (x, y) = ((i % self.width) as i32, (i / self.width) as i32);
+            image.set_pixel(x, y, vector_to_color(color)).unwrap();
+        }
+        image
+    }
+}
@@ -1,11 +1,10 @@
 use std::ops::{Add, Sub, Mul, Div, Neg};
 use rand::Rng;
+use material::Material;
 
 
 pub fn clamp(num: f64) -> f64 {
-    if num < 0.0 { 0.0 }
-    else if num > 1.0 { 1.0 }
-    else { num }
+    num.clamp(0.0, 1.0)
 }
 
 #[derive(Copy, Clone, Debug, new)]
@@ -55,6 +54,44 @@ impl Vector {
         Vector { x: clamp(self.x), y: clamp(self.y), z: clamp(self.z)}
     }
 
+    /// Perceived brightness of the vector as a linear color
+    pub fn luminance(&self) -> f64 {
+        0.2126*self.x + 0.7152*self.y + 0.0722*self.z
+    }
+
+    pub fn min(&self, other: &Vector) -> Vector {
+        Vector { x: self.x.min(other.x), y: self.y.min(other.y), z: self.z.min(other.z) }
+    }
+
+    pub fn max(&self, other: &Vector) -> Vector {
+        Vector { x: self.x.max(other.x), y: self.y.max(other.y), z: self.z.max(other.z) }
+    }
+
+    /// Component along axis 0 (x), 1 (y) or 2 (z)
+    pub fn axis(&self, axis: usize) -> f64 {
+        match axis {
+            0 => self.x,
+            1 => self.y,
+            _ => self.z
+        }
+    }
+
+    /// Mirrors this direction about a unit normal
+    pub fn reflect(&self, normal: &Vector) -> Vector {
+        *self - *normal * 2.0 * normal.dot(self)
+    }
+
+    /// Bends this unit direction through a surface with a unit normal facing against it, following Snell's law.
+    /// 'eta' is the ratio of the index of refraction being left to the one being entered.
+    /// Returns None on total internal reflection.
+    pub fn refract(&self, normal: &Vector, eta: f64) -> Option<Vector> {
+        let cos_i = -normal.dot(self);
+        let sin2_t = eta*eta * (1.0 - cos_i*cos_i);
+        if sin2_t > 1.0 { return None; }
+        let cos_t = (1.0 - sin2_t).sqrt();
+        Some(*self * eta + *normal * (eta*cos_i - cos_t))
+    }
+
     /// Random unit vector
     pub fn rand<T>(rng: &mut T) -> Vector
     where T: Rng {
@@ -190,12 +227,201 @@ impl Plane {
     }
 }
 
+/// Orthonormal frame of reference
+#[derive(Copy, Clone, Debug, new)]
+pub struct Basis {
+    pub u: Vector,
+    pub v: Vector,
+    pub w: Vector
+}
+
+impl Basis {
+
+    /// Basis whose 'w' axis points along the given direction.
+    pub fn from_w(dir: &Vector) -> Basis {
+        let w = dir.to_unit();
+        let helper = if w.x.abs() > 0.9 { Vector::new(0.0, 1.0, 0.0) } else { Vector::new(1.0, 0.0, 0.0) };
+        let v = w.cross(&helper).to_unit();
+        let u = v.cross(&w);
+        Basis { u, v, w }
+    }
+
+    /// Basis rotated by euler angles in radians, applied about x, then y, then z.
+    pub fn from_euler(angles: &Vector) -> Basis {
+        let (sx, cx) = angles.x.sin_cos();
+        let (sy, cy) = angles.y.sin_cos();
+        let (sz, cz) = angles.z.sin_cos();
+        let rotate = |p: Vector| -> Vector {
+            let p = Vector::new(p.x, p.y*cx - p.z*sx, p.y*sx + p.z*cx);
+            let p = Vector::new(p.x*cy + p.z*sy, p.y, -p.x*sy + p.z*cy);
+            Vector::new(p.x*cz - p.y*sz, p.x*sz + p.y*cz, p.z)
+        };
+        Basis {
+            u: rotate(Vector::new(1.0, 0.0, 0.0)),
+            v: rotate(Vector::new(0.0, 1.0, 0.0)),
+            w: rotate(Vector::new(0.0, 0.0, 1.0))
+        }
+    }
+
+    /// Euler angles in radians that reproduce this basis through Basis::from_euler
+    pub fn euler(&self) -> Vector {
+        let y = (-self.u.z).clamp(-1.0, 1.0).asin();
+        let x = self.v.z.atan2(self.w.z);
+        let z = self.u.y.atan2(self.u.x);
+        Vector::new(x, y, z)
+    }
+
+    /// Expresses a world-space vector in this basis
+    pub fn to_local(&self, vec: &Vector) -> Vector {
+        Vector::new(vec.dot(&self.u), vec.dot(&self.v), vec.dot(&self.w))
+    }
+
+    /// Expresses a vector in this basis in world-space
+    pub fn to_world(&self, vec: &Vector) -> Vector {
+        self.u*vec.x + self.v*vec.y + self.w*vec.z
+    }
+
+    pub fn axis(&self, axis: usize) -> Vector {
+        match axis {
+            0 => self.u,
+            1 => self.v,
+            _ => self.w
+        }
+    }
+}
+
+/// Axis-aligned bounding box
+#[derive(Copy, Clone, Debug, new)]
+pub struct Aabb {
+    pub min: Vector,
+    pub max: Vector
+}
+
+impl Aabb {
+
+    /// Box containing nothing. Grows to fit whatever is unioned with it.
+    pub fn empty() -> Aabb {
+        let inf = f64::INFINITY;
+        Aabb::new(Vector::new(inf, inf, inf), Vector::new(-inf, -inf, -inf))
+    }
+
+    /// Box containing everything. Used by shapes that have no finite extent.
+    pub fn infinite() -> Aabb {
+        let inf = f64::INFINITY;
+        Aabb::new(Vector::new(-inf, -inf, -inf), Vector::new(inf, inf, inf))
+    }
+
+    pub fn is_finite(&self) -> bool {
+        self.min.x.is_finite() && self.min.y.is_finite() && self.min.z.is_finite() &&
+        self.max.x.is_finite() && self.max.y.is_finite() && self.max.z.is_finite()
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.min(&other.min), self.max.max(&other.max))
+    }
+
+    pub fn grow(&self, point: &Vector) -> Aabb {
+        Aabb::new(self.min.min(point), self.max.max(point))
+    }
+
+    pub fn centroid(&self) -> Vector {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vector {
+        self.max - self.min
+    }
+
+    pub fn surface_area(&self) -> f64 {
+        let size = self.size();
+        if size.x < 0.0 || size.y < 0.0 || size.z < 0.0 { return 0.0; }
+        2.0 * (size.x*size.y + size.y*size.z + size.z*size.x)
+    }
+
+    /// Index of the axis along which the box is longest
+    pub fn longest_axis(&self) -> usize {
+        let size = self.size();
+        if size.x >= size.y && size.x >= size.z { 0 }
+        else if size.y >= size.z { 1 }
+        else { 2 }
+    }
+
+    /// Slab test. Returns the ray length at which the ray enters the box,
+    /// if it does so somewhere in [0, t_max].
+    pub fn hit(&self, ray: &Ray, t_max: f64) -> Option<f64> {
+        let mut t0: f64 = 0.0;
+        let mut t1: f64 = t_max;
+        for axis in 0..3 {
+            let inv = 1.0 / ray.dir.axis(axis);
+            let origin = ray.origin.axis(axis);
+            let mut near = (self.min.axis(axis) - origin) * inv;
+            let mut far = (self.max.axis(axis) - origin) * inv;
+            if near > far { ::std::mem::swap(&mut near, &mut far); }
+
+            // NaN-safe: comparisons with NaN leave the interval untouched
+            if near > t0 { t0 = near; }
+            if far < t1 { t1 = far; }
+            if t0 > t1 { return None; }
+        }
+        Some(t0)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
-pub struct Intersection {
+pub struct Intersection<'a> {
     pub t: f64,
     pub position: Vector,
     pub normal: Vector,
-    pub color: Vector,
-    pub reflectivity: f64,
-    pub exponent: f64
-}
\ No newline at end of file
+    pub material: &'a Material,
+
+    /// Whether the ray hit the surface from inside the shape, or from behind for shapes without an inside.
+    /// Normals always face the ray, so this is the only way to tell a ray entering a shape from one leaving it.
+    pub inside: bool,
+
+    /// Texture coordinates (u, v) of the hit point, as mapped by the shape.
+    /// Bounded shapes map their surface onto 0 to 1, while Floor tiles one unit of u and v per unit of distance.
+    pub uv: [f64; 2],
+
+    /// How far and which way the hit point moves along the surface per unit of u and of v.
+    /// Together with the normal, these give the tangent space that normal and bump maps are described in.
+    /// Either may be zero where the mapping pinches to a point, such as at the poles of a sphere.
+    pub dpdu: Vector,
+    pub dpdv: Vector
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &Vector, b: &Vector) {
+        assert!((*a - *b).len() < 1e-9, "expected {:?}, found {:?}", b, a);
+    }
+
+    #[test]
+    fn refracts_by_snells_law() {
+        let normal = Vector::new(0.0, 1.0, 0.0);
+
+        // Straight on, light passes through unbent
+        let down = Vector::new(0.0, -1.0, 0.0);
+        assert_close(&down.refract(&normal, 1.0 / 1.5).unwrap(), &down);
+
+        // At 45 degrees into glass, the sine of the angle shrinks by the ratio of the indices
+        let dir = Vector::new(1.0, -1.0, 0.0).to_unit();
+        let refracted = dir.refract(&normal, 1.0 / 1.5).unwrap();
+        assert!((refracted.len() - 1.0).abs() < 1e-9);
+        assert!((refracted.x - (0.5f64).sqrt() / 1.5).abs() < 1e-9);
+        assert!(refracted.y < 0.0 && refracted.z == 0.0);
+    }
+
+    #[test]
+    fn reflects_totally_past_the_critical_angle() {
+
+        // Leaving glass, the critical angle is asin(1/1.5), about 41.8 degrees
+        let normal = Vector::new(0.0, 1.0, 0.0);
+        let steep = Vector::new((40f64).to_radians().sin(), -(40f64).to_radians().cos(), 0.0);
+        let shallow = Vector::new((43f64).to_radians().sin(), -(43f64).to_radians().cos(), 0.0);
+        assert!(steep.refract(&normal, 1.5).is_some());
+        assert!(shallow.refract(&normal, 1.5).is_none());
+        assert_close(&shallow.reflect(&normal), &Vector::new(shallow.x, -shallow.y, 0.0));
+    }
+}
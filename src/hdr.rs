@@ -0,0 +1,397 @@
+use geom::Vector;
+use framebuffer::Framebuffer;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Floating point image file format, holding linear colors with no limit on their brightness
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HdrFormat {
+
+    /// Radiance RGBE (.hdr or .pic): 8-bit colors sharing an 8-bit exponent, run-length encoded
+    Rgbe,
+
+    /// Portable FloatMap (.pfm): uncompressed 32-bit floats
+    Pfm
+}
+
+impl HdrFormat {
+
+    /// Format named by the extension of 'path', if any
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<HdrFormat> {
+        let extension = path.as_ref().extension()?.to_string_lossy().to_lowercase();
+        match extension.as_str() {
+            "hdr" | "pic" => Some(HdrFormat::Rgbe),
+            "pfm" => Some(HdrFormat::Pfm),
+            _ => None
+        }
+    }
+}
+
+/// Loads an image in the format named by the extension of 'path'
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Framebuffer> {
+    let path = path.as_ref();
+    let format = HdrFormat::from_path(path).ok_or_else(|| invalid("expected a .hdr, .pic or .pfm file"))?;
+    let bytes = fs::read(path)?;
+    match format {
+        HdrFormat::Rgbe => read_rgbe(&bytes),
+        HdrFormat::Pfm => read_pfm(&bytes)
+    }
+}
+
+/// Saves an image in the format named by the extension of 'path'
+pub fn save<P: AsRef<Path>>(image: &Framebuffer, path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let format = HdrFormat::from_path(path).ok_or_else(|| invalid("expected a .hdr, .pic or .pfm file"))?;
+    let mut bytes = Vec::new();
+    match format {
+        HdrFormat::Rgbe => write_rgbe(image, &mut bytes)?,
+        HdrFormat::Pfm => write_pfm(image, &mut bytes)?
+    }
+    fs::write(path, bytes)
+}
+
+/// Reads a Radiance RGBE file, either flat or run-length encoded.
+/// Only the usual orientation, with rows running down from the top, is supported.
+pub fn read_rgbe(bytes: &[u8]) -> io::Result<Framebuffer> {
+    let mut lines = HeaderLines { bytes, pos: 0 };
+
+    // Header lines run until a blank line, followed by the resolution
+    let magic = lines.next()?;
+    if !magic.starts_with("#?") { return Err(invalid("not a Radiance file")); }
+    loop {
+        let line = lines.next()?;
+        if line.is_empty() { break; }
+        if let Some(format) = line.strip_prefix("FORMAT=") {
+            if format != "32-bit_rle_rgbe" { return Err(invalid(&format!("unsupported pixel format '{}'", format))); }
+        }
+    }
+    let resolution = lines.next()?;
+    let (width, height) = match resolution.split_whitespace().collect::<Vec<_>>()[..] {
+        ["-Y", h, "+X", w] => (parse_size(w)?, parse_size(h)?),
+        _ => return Err(invalid(&format!("unsupported resolution line '{}'", resolution)))
+    };
+
+    // Checks the file could hold that many rows before making room for them, as the sizes come from the file.
+    // The shortest rows are run-length encoded ones that repeat a byte over and over.
+    let mut data = &bytes[lines.pos..];
+    if width == 0 || height == 0 { return Err(invalid("image has no pixels")); }
+    let shortest_row = if (8..0x8000).contains(&width) { Some(4 + 4 * 2 * width.div_ceil(127)) }
+        else { width.checked_mul(4) };
+    let needed = shortest_row.and_then(|row| row.checked_mul(height)).ok_or_else(|| invalid("image is too large"))?;
+    if needed > data.len() { return Err(invalid("pixel data ends early")); }
+    let mut image = Framebuffer::new(width, height);
+    let mut scanline = vec![[0u8; 4]; width];
+    for y in 0..height {
+        data = read_scanline(data, &mut scanline)?;
+        for (x, rgbe) in scanline.iter().enumerate() {
+            image.set(x, y, from_rgbe(rgbe));
+        }
+    }
+    Ok(image)
+}
+
+/// Writes a Radiance RGBE file, run-length encoding rows that are wide enough for it
+pub fn write_rgbe<W: Write>(image: &Framebuffer, out: &mut W) -> io::Result<()> {
+    write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", image.height, image.width)?;
+    let encode = (8..0x8000).contains(&image.width);
+    let mut channel = vec![0u8; image.width];
+    for row in image.pixels.chunks(image.width.max(1)) {
+        let rgbe: Vec<[u8; 4]> = row.iter().map(to_rgbe).collect();
+        if !encode {
+            for pixel in &rgbe { out.write_all(pixel)?; }
+            continue;
+        }
+
+        // Each row starts with a marker holding its width, then holds each channel in turn as runs and literals
+        out.write_all(&[2, 2, (image.width >> 8) as u8, (image.width & 0xff) as u8])?;
+        for c in 0..4 {
+            for (byte, pixel) in channel.iter_mut().zip(&rgbe) { *byte = pixel[c]; }
+            write_runs(&channel, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a Portable FloatMap, in color or grayscale and either byte order
+pub fn read_pfm(bytes: &[u8]) -> io::Result<Framebuffer> {
+
+    // The header is three whitespace separated fields after the magic, and ends with a single whitespace character
+    let mut pos = 0;
+    let mut fields = Vec::new();
+    while fields.len() < 4 {
+        while pos < bytes.len() && bytes[pos].is_ascii_whitespace() { pos += 1; }
+        let start = pos;
+        while pos < bytes.len() && !bytes[pos].is_ascii_whitespace() { pos += 1; }
+        if start == pos { return Err(invalid("header ends early")); }
+        fields.push(String::from_utf8_lossy(&bytes[start..pos]).into_owned());
+    }
+    pos += 1;
+
+    let channels = match fields[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("not a PFM file"))
+    };
+    let (width, height) = (parse_size(&fields[1])?, parse_size(&fields[2])?);
+    let scale: f64 = fields[3].parse().map_err(|_| invalid(&format!("bad scale '{}'", fields[3])))?;
+    let little_endian = scale < 0.0;
+
+    // Checks the sizes from the file against the data there is before making room for the pixels
+    let end = width.checked_mul(height)
+        .and_then(|count| count.checked_mul(channels * 4))
+        .and_then(|size| size.checked_add(pos))
+        .ok_or_else(|| invalid("image is too large"))?;
+    let data = bytes.get(pos..end).ok_or_else(|| invalid("pixel data ends early"))?;
+    let floats: Vec<f64> = data
+        .chunks(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            (if little_endian { f32::from_le_bytes(b) } else { f32::from_be_bytes(b) }) as f64
+        })
+        .collect();
+
+    // Rows are stored from the bottom up
+    let mut image = Framebuffer::new(width, height);
+    for (i, pixel) in floats.chunks(channels).enumerate() {
+        let (x, y) = (i % width, height - 1 - i / width);
+        let color = if channels == 3 { Vector::new(pixel[0], pixel[1], pixel[2]) }
+            else { Vector::new(pixel[0], pixel[0], pixel[0]) };
+        image.set(x, y, color);
+    }
+    Ok(image)
+}
+
+/// Writes a color Portable FloatMap in little-endian byte order
+pub fn write_pfm<W: Write>(image: &Framebuffer, out: &mut W) -> io::Result<()> {
+    write!(out, "PF\n{} {}\n-1.0\n", image.width, image.height)?;
+    let mut data = Vec::with_capacity(image.width * image.height * 12);
+    for row in image.pixels.chunks(image.width.max(1)).rev() {
+        for color in row {
+            for &c in &[color.x, color.y, color.z] {
+                data.extend_from_slice(&(c as f32).to_le_bytes());
+            }
+        }
+    }
+    out.write_all(&data)
+}
+
+/// Shared exponent encoding of a color. Negative channels are stored as 0.
+fn to_rgbe(color: &Vector) -> [u8; 4] {
+    let peak = color.x.max(color.y).max(color.z);
+    if peak < 1e-32 || !peak.is_finite() { return [0, 0, 0, 0]; }
+
+    // Splits the peak into a mantissa between 0.5 and 1 and a power of two
+    let mut exponent = peak.log2().floor() as i32 + 1;
+    if peak / 2f64.powi(exponent) >= 1.0 { exponent += 1; }
+    let scale = 256.0 / 2f64.powi(exponent);
+    let byte = |c: f64| (c.max(0.0) * scale).min(255.0) as u8;
+    [byte(color.x), byte(color.y), byte(color.z), (exponent + 128).clamp(0, 255) as u8]
+}
+
+fn from_rgbe(rgbe: &[u8; 4]) -> Vector {
+    if rgbe[3] == 0 { return Vector::new(0.0, 0.0, 0.0); }
+    let scale = 2f64.powi(rgbe[3] as i32 - (128 + 8));
+    Vector::new((rgbe[0] as f64 + 0.5) * scale, (rgbe[1] as f64 + 0.5) * scale, (rgbe[2] as f64 + 0.5) * scale)
+}
+
+/// Reads one row of RGBE pixels from the start of 'data', returning what follows
+fn read_scanline<'a>(data: &'a [u8], scanline: &mut [[u8; 4]]) -> io::Result<&'a [u8]> {
+    let width = scanline.len();
+    let early = || invalid("pixel data ends early");
+    let encoded = data.len() >= 4 && data[0] == 2 && data[1] == 2 && data[2] & 0x80 == 0 && (8..0x8000).contains(&width);
+    if !encoded {
+        let flat = data.get(..width * 4).ok_or_else(early)?;
+        for (pixel, bytes) in scanline.iter_mut().zip(flat.chunks(4)) {
+            pixel.copy_from_slice(bytes);
+        }
+        return Ok(&data[width * 4..]);
+    }
+
+    if ((data[2] as usize) << 8 | data[3] as usize) != width { return Err(invalid("row width doesn't match the image")); }
+    let mut pos = 4;
+    for c in 0..4 {
+        let mut x = 0;
+        while x < width {
+            let count = *data.get(pos).ok_or_else(early)? as usize;
+            pos += 1;
+            if count > 128 {
+
+                // Run of one repeated byte
+                let count = count - 128;
+                let value = *data.get(pos).ok_or_else(early)?;
+                pos += 1;
+                if count > width - x { return Err(invalid("run overflows its row")); }
+                for pixel in &mut scanline[x..x + count] { pixel[c] = value; }
+                x += count;
+            }
+            else {
+
+                // Literal bytes
+                if count == 0 || count > width - x { return Err(invalid("bad literal length")); }
+                let bytes = data.get(pos..pos + count).ok_or_else(early)?;
+                pos += count;
+                for (pixel, &value) in scanline[x..x + count].iter_mut().zip(bytes) { pixel[c] = value; }
+                x += count;
+            }
+        }
+    }
+    Ok(&data[pos..])
+}
+
+/// Run-length encodes one channel of a row, using runs for 4 or more repeated bytes and literals elsewhere
+fn write_runs<W: Write>(bytes: &[u8], out: &mut W) -> io::Result<()> {
+    const MIN_RUN: usize = 4;
+    let mut pos = 0;
+    while pos < bytes.len() {
+
+        // Finds the next run long enough to be worth encoding
+        let mut run_start = pos;
+        let mut run_len = 0;
+        while run_start < bytes.len() {
+            run_len = bytes[run_start..].iter().take(127).take_while(|&&b| b == bytes[run_start]).count();
+            if run_len >= MIN_RUN { break; }
+            run_start += run_len;
+        }
+        if run_len < MIN_RUN { run_start = bytes.len(); }
+
+        // Writes literals up to the run
+        while pos < run_start {
+            let count = (run_start - pos).min(128);
+            out.write_all(&[count as u8])?;
+            out.write_all(&bytes[pos..pos + count])?;
+            pos += count;
+        }
+        if run_start < bytes.len() {
+            out.write_all(&[128 + run_len as u8, bytes[run_start]])?;
+            pos = run_start + run_len;
+        }
+    }
+    Ok(())
+}
+
+/// Splits the header of a Radiance file into lines, keeping track of where they end
+struct HeaderLines<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> HeaderLines<'a> {
+    fn next(&mut self) -> io::Result<String> {
+        let rest = &self.bytes[self.pos..];
+        let end = rest.iter().position(|&b| b == b'\n').ok_or_else(|| invalid("header ends early"))?;
+        self.pos += end + 1;
+        Ok(String::from_utf8_lossy(&rest[..end]).trim_end().to_string())
+    }
+}
+
+fn parse_size(field: &str) -> io::Result<usize> {
+    field.parse().map_err(|_| invalid(&format!("bad image size '{}'", field)))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image with smooth gradients, which run-length encoding turns into runs, and noisy pixels, which it can't
+    fn test_image(width: usize, height: usize) -> Framebuffer {
+        let mut image = Framebuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let noise = ((x * 7919 + y * 104_729) % 13) as f64;
+                let color = if x < width / 2 { Vector::new(0.25, 0.5, 1.0) * (y + 1) as f64 }
+                    else { Vector::new(noise * 3.0, x as f64 * 0.01, 1000.0 / (noise + 1.0)) };
+                image.set(x, y, color);
+            }
+        }
+        image
+    }
+
+    fn assert_kind<T>(result: io::Result<T>, message: &str) {
+        match result {
+            Err(ref error) => assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}: {}", message, error),
+            Ok(_) => panic!("{}: read without an error", message)
+        }
+    }
+
+    #[test]
+    fn rgbe_round_trip() {
+
+        // Widths outside of 8 to 0x7fff are stored flat, and those within it run-length encoded
+        for &(width, encoded) in &[(3, false), (8, true), (300, true), (0x7fff, true), (0x8000, false)] {
+            let image = test_image(width, 2);
+            let mut bytes = Vec::new();
+            write_rgbe(&image, &mut bytes).unwrap();
+            let header = format!("#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X {}\n", width);
+            assert!(bytes.starts_with(header.as_bytes()));
+            let marker = [2, 2, (width >> 8) as u8, (width & 0xff) as u8];
+            assert_eq!(bytes[header.len()..].starts_with(&marker), encoded, "width {}", width);
+
+            // Shared exponents keep 8 bits of each color relative to its brightest channel
+            let read = read_rgbe(&bytes).unwrap();
+            assert_eq!((read.width, read.height), (width, 2));
+            for (a, b) in image.pixels.iter().zip(&read.pixels) {
+                let peak = a.x.max(a.y).max(a.z);
+                assert!((*a - *b).len() <= peak * 2.0 / 256.0, "{:?} came back as {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn pfm_round_trip() {
+        let image = test_image(5, 3);
+        let mut bytes = Vec::new();
+        write_pfm(&image, &mut bytes).unwrap();
+
+        // The first pixel stored is the bottom left one
+        let header = b"PF\n5 3\n-1.0\n";
+        assert!(bytes.starts_with(header));
+        let first = &bytes[header.len()..header.len() + 4];
+        let first = f32::from_le_bytes([first[0], first[1], first[2], first[3]]);
+        assert_eq!(first, image.get(0, 2).x as f32);
+
+        let read = read_pfm(&bytes).unwrap();
+        assert_eq!((read.width, read.height), (5, 3));
+        for (a, b) in image.pixels.iter().zip(&read.pixels) {
+            assert_eq!((a.x as f32, a.y as f32, a.z as f32), (b.x as f32, b.y as f32, b.z as f32));
+        }
+    }
+
+    #[test]
+    fn reads_big_endian_grayscale_pfm() {
+
+        // Rows from the bottom up, most significant byte first
+        let mut bytes = b"Pf\n2 2\n1.0\n".to_vec();
+        for &value in &[1.0f32, 2.0, 3.0, 4.0] {
+            bytes.extend_from_slice(&value.to_be_bytes());
+        }
+        let image = read_pfm(&bytes).unwrap();
+        let values: Vec<f64> = image.pixels.iter().map(|p| p.y).collect();
+        assert_eq!(values, vec![3.0, 4.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let image = test_image(20, 4);
+        let mut rgbe = Vec::new();
+        write_rgbe(&image, &mut rgbe).unwrap();
+        let mut pfm = Vec::new();
+        write_pfm(&image, &mut pfm).unwrap();
+        assert_kind(read_rgbe(&rgbe[..rgbe.len() - 1]), "truncated RGBE");
+        assert_kind(read_rgbe(&rgbe[..40]), "RGBE without most of its rows");
+        assert_kind(read_pfm(&pfm[..pfm.len() - 1]), "truncated PFM");
+    }
+
+    #[test]
+    fn rejects_oversized_headers() {
+        assert_kind(read_rgbe(b"#?RADIANCE\n\n-Y 1000000000 +X 1000000000\n\x02\x02"), "huge RGBE");
+        assert_kind(read_rgbe(b"#?RADIANCE\n\n-Y 18446744073709551615 +X 16\n"), "overflowing RGBE");
+        assert_kind(read_rgbe(b"#?RADIANCE\n\n-Y 2 +X 4611686018427387904\n"), "overflowing flat RGBE");
+        assert_kind(read_pfm(b"PF\n1000000000 1000000000\n-1.0\n\0\0\0\0"), "huge PFM");
+        assert_kind(read_pfm(b"PF\n18446744073709551615 2\n-1.0\n"), "overflowing PFM");
+    }
+}
@@ -0,0 +1,187 @@
+use geom::{Vector, Ray, Intersection};
+use scene::Scene;
+use sampling::cosine_hemisphere;
+use rand::Rng;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// Bounces after which paths may be cut short by Russian roulette.
+/// Roulette only decides whether to follow a path further, so it has no effect unless the bounce limit is above this.
+/// Starting it early keeps the scenes' low bounce limits useful, while letting higher limits cost little, as most
+/// paths end long before reaching them.
+const ROULETTE_DEPTH: u32 = 1;
+
+/// Highest chance of a path surviving Russian roulette, so that paths through white surfaces still end
+const MAX_SURVIVAL: f64 = 0.95;
+
+/// Method of finding the light arriving along a camera ray
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Integrator {
+
+    /// Recursive ray tracing with Phong shading, as done by Scene::trace_color.
+    /// Deterministic and quick, but light bouncing between diffuse surfaces is faked by the ambient color.
+    Whitted,
+
+    /// Monte Carlo path tracing.
+    /// Follows light bouncing between surfaces, so needs many samples per pixel to converge.
+    Path
+}
+
+impl FromStr for Integrator {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Integrator, String> {
+        match s {
+            "whitted" => Ok(Integrator::Whitted),
+            "path" => Ok(Integrator::Path),
+            _ => Err(format!("unknown integrator '{}', expected whitted or path", s))
+        }
+    }
+}
+
+impl Integrator {
+
+    /// Estimates the color seen along a ray.
+    /// Random choices are drawn from 'rng', so the same sequence of numbers always gives the same color.
+    pub fn trace<R: Rng>(&self, scene: &Scene, ray: &Ray, rng: &mut R) -> Vector {
+        match *self {
+            Integrator::Whitted => scene.trace_color(ray, scene.bounce_limit),
+            Integrator::Path => trace_path(scene, ray, rng)
+        }
+    }
+}
+
+/// Follows a single path of light back from a ray, bouncing off at most bounce_limit surfaces.
+///
+/// At each surface, one of the material's parts is picked at random in proportion to its weight: the dielectric part
+/// by transparency, the mirror part by reflectivity and the diffuse part by what is left.
+/// Diffuse surfaces gather light directly from the scene's lights and continue in a cosine-weighted direction.
+/// Microfacet materials replace the mirror and diffuse parts, gathering light the same way and continuing in a
+/// direction importance sampled from their reflection.
+/// The background color lights the scene from every direction, and the ambient color is not used.
+/// An environment map takes the background's place, and is gathered from like a light.
+/// Point lights are scaled by PI, so that directly lit surfaces are as bright as with the Whitted integrator.
+/// Glowing shapes that stand in for lights, and environment maps, are only seen directly, through mirrors and through
+/// dielectrics, as the light they cast on other surfaces is gathered along with that of the scene's lights.
+/// Other glowing shapes can't be gathered from, so light them by being hit like any other surface.
+/// After ROULETTE_DEPTH bounces, paths end at random with a chance that grows as they carry less light, so the bounce
+/// limit is where paths that keep going are cut off, rather than how far every path goes.
+pub fn trace_path<R: Rng>(scene: &Scene, ray: &Ray, rng: &mut R) -> Vector {
+    let mut radiance = Vector::new(0.0, 0.0, 0.0);
+    let mut throughput = Vector::new(1.0, 1.0, 1.0);
+    let mut ray = *ray;
+    let mut gathered = false;
+    for depth in 0..=scene.bounce_limit {
+
+        // Escaped paths see the background
+        let (inter, sampled) = match scene.intersect_sampled(&ray) {
+            Some(hit) => hit,
+            None => {
+                if !(gathered && scene.environment.is_some()) {
+                    radiance = radiance + throughput * scene.background(&ray.dir);
+                }
+                break;
+            }
+        };
+
+        // Paths reaching a surface from inside have travelled through its medium
+        let material = inter.material;
+        if inter.inside {
+            throughput = throughput * material.absorb(ray.dir.len() * inter.t);
+        }
+
+        // Adds the surface's glow, unless the light it casts here was gathered at the last bounce
+        if !(gathered && sampled) {
+            radiance = radiance + throughput * material.emitted(inter.inside);
+        }
+        gathered = false;
+
+        // Picks a direction to continue in
+        let dir_unit = ray.dir.to_unit();
+        let normal_unit = material.shading_normal(&inter).to_unit();
+        let color = material.color_at(&inter);
+        let next_dir = if rng.next_f64() < material.transparency {
+            let reflectance = material.fresnel(-normal_unit.dot(&dir_unit), inter.inside);
+            let refracted = if rng.next_f64() < reflectance { None }
+                else { dir_unit.refract(&normal_unit, material.eta(inter.inside)) };
+            refracted.unwrap_or_else(|| dir_unit.reflect(&normal_unit))
+        }
+        else if let Some(ref microfacet) = material.microfacet {
+            let eye_dir_unit = -dir_unit;
+            radiance = radiance + throughput * direct_light(scene, &inter, rng, |light_dir_unit| {
+                microfacet.eval(&normal_unit, &eye_dir_unit, light_dir_unit, &color) * PI
+            });
+            gathered = true;
+            match microfacet.sample(&normal_unit, &eye_dir_unit, &color, rng) {
+                Some((next_dir, weight)) => {
+                    throughput = throughput * weight;
+                    next_dir
+                },
+                None => break
+            }
+        }
+        else if rng.next_f64() < material.reflectivity_at(&inter) {
+            dir_unit.reflect(&normal_unit)
+        }
+        else {
+            radiance = radiance + throughput * direct_light(scene, &inter, rng, |light_dir_unit| {
+                color * normal_unit.dot(light_dir_unit).max(0.0)
+            });
+            gathered = true;
+            throughput = throughput * color;
+            cosine_hemisphere(&normal_unit, rng)
+        };
+
+        // Randomly ends paths carrying little light, boosting the survivors to make up for it
+        if depth >= ROULETTE_DEPTH {
+            let survival = throughput.x.max(throughput.y).max(throughput.z).min(MAX_SURVIVAL);
+            if rng.next_f64() >= survival { break; }
+            throughput = throughput / survival;
+        }
+
+        ray = Ray::new(inter.position, next_dir * scene.camera.far_dist);
+    }
+    radiance
+}
+
+/// Light reflected towards the viewer straight from the scene's lights.
+/// 'reflect' gives the fraction of light arriving from a unit direction that the surface reflects, including the cosine
+/// of its angle to the normal.
+fn direct_light<F, R>(scene: &Scene, inter: &Intersection, rng: &mut R, reflect: F) -> Vector
+where F: Fn(&Vector) -> Vector, R: Rng {
+    let mut total = Vector::new(0.0, 0.0, 0.0);
+    for light in scene.sample_lights(&inter.position, rng) {
+        let reflected = reflect(&light.dir.to_unit());
+        if reflected.len_squared() == 0.0 { continue; }
+        let transmittance = scene.transmittance(&light.shadow_ray(&inter.position));
+        total = total + light.color * transmittance * reflected * (light.brightness * light.falloff);
+    }
+    total
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampling::pixel_rng;
+    use scene_file::parse_scene;
+    use std::path::Path;
+
+    #[test]
+    fn glow_of_shapes_without_lights_reaches_other_surfaces() {
+
+        // A white sphere resting on a glowing floor, which has no lights to gather from, under a black sky
+        let source = "color_background = [0.0, 0.0, 0.0]\ncolor_ambient = [0.0, 0.0, 0.0]\nbounce_limit = 1\n\
+            [camera]\norigin = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\nup = [0.0, 1.0, 0.0]\nnear_dist = 1.0\n\
+            far_dist = 100.0\nfrust_width = 1.0\nfrust_height = 1.0\n\
+            [materials.white]\ncolor = [1.0, 1.0, 1.0]\nreflectivity = 0.0\nexponent = 1.0\n\
+            [materials.glow]\ncolor = [0.0, 0.0, 0.0]\nreflectivity = 0.0\nexponent = 1.0\nemission = [1.0, 1.0, 1.0]\n\
+            [[shapes]]\ntype = \"floor\"\nposition = [0.0, -1.0, 0.0]\nmaterial = \"glow\"\n\
+            [[shapes]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = 1.0\nmaterial = \"white\"\n";
+        let scene = parse_scene(source, Path::new("")).unwrap();
+
+        // Looks at the underside of the sphere, which only the floor lights
+        let ray = Ray::new(Vector::new(0.0, 0.0, 5.0), Vector::new(0.0, -0.15, -1.0).to_len(100.0));
+        let mut rng = pixel_rng(0, 0, 0);
+        let total = (0..256).fold(0.0, |sum, _| sum + Integrator::Path.trace(&scene, &ray, &mut rng).luminance());
+        assert!(total / 256.0 > 0.1, "the floor lit the sphere with {}", total / 256.0);
+    }
+}
@@ -1,7 +1,29 @@
 #[macro_use]
 extern crate derive_new;
+extern crate flate2;
 extern crate rand;
+extern crate raster;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 
+pub mod bvh;
+pub mod environment;
+pub mod exr;
+pub mod framebuffer;
 pub mod geom;
+pub mod hdr;
+pub mod integrator;
+pub mod light;
+pub mod material;
+pub mod mesh;
+pub mod microfacet;
+pub mod noise;
+pub mod obj;
+pub mod render;
+pub mod sampling;
 pub mod scene;
+pub mod scene_file;
 pub mod shape;
+pub mod texture;
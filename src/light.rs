@@ -0,0 +1,334 @@
+use geom::{Vector, Ray, Basis};
+use sampling::{SamplePattern, Distribution};
+use rand::Rng;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+/// Shadow samples taken of each glowing shape, shared between its parts
+pub const SURFACE_SAMPLES: u32 = 16;
+
+/// Fraction of the way to a light at which shadow rays stop, so that the surface of a glowing shape doesn't shadow
+/// its own light
+const SHADOW_REACH: f64 = 0.9999;
+
+/// Shape of a light
+#[derive(Debug, Clone)]
+pub enum LightKind {
+
+    /// Infinitely small point, casting hard shadows
+    Point,
+
+    /// Rectangle centered on the light's position with edges 'u' and 'v'.
+    /// Shines from the side that u x v faces.
+    Rect { u: Vector, v: Vector },
+
+    /// Disk centered on the light's position, shining from the side that 'normal' faces
+    Disk { normal: Vector, radius: f64 },
+
+    /// Sphere centered on the light's position, shining in every direction
+    Sphere { radius: f64 },
+
+    /// Triangle with a corner at the light's position and edges 'u' and 'v' leaving it.
+    /// Shines from the side that u x v faces.
+    Triangle { u: Vector, v: Vector },
+
+    /// Triangles placed relative to the light's position, each shining from the side its edges' cross product faces.
+    /// Samples are spread over all of them, so that larger triangles get more.
+    Triangles(Arc<TriangleSet>),
+
+    /// Light from infinitely far away, such as the sun, shining along 'direction' everywhere.
+    /// Doesn't fade with distance, and ignores the light's position.
+    Directional { direction: Vector },
+
+    /// Point shining along 'direction' in a cone.
+    /// Full brightness within 'inner_angle' of the direction fades smoothly to nothing at 'outer_angle'.
+    /// Angles are in radians.
+    Spot { direction: Vector, inner_angle: f64, outer_angle: f64 }
+}
+
+/// Triangles of a light, picked between in proportion to their area
+#[derive(Debug, Clone)]
+pub struct TriangleSet {
+
+    /// Corner and the two edges leaving it of each triangle
+    triangles: Vec<(Vector, Vector, Vector)>,
+    distribution: Distribution,
+    area: f64
+}
+
+impl TriangleSet {
+
+    /// Triangles given by their corners. Triangles without area are left out, as no light can come from them.
+    pub fn new(corners: &[[Vector; 3]]) -> TriangleSet {
+        let triangles: Vec<(Vector, Vector, Vector)> = corners.iter()
+            .map(|&[a, b, c]| (a, b - a, c - a))
+            .filter(|&(_, u, v)| u.cross(&v).len_squared() > 0.0)
+            .collect();
+        let areas: Vec<f64> = triangles.iter().map(|&(_, u, v)| u.cross(&v).len() / 2.0).collect();
+        TriangleSet { triangles, distribution: Distribution::new(&areas), area: areas.iter().sum() }
+    }
+
+    pub fn len(&self) -> usize { self.triangles.len() }
+    pub fn is_empty(&self) -> bool { self.triangles.is_empty() }
+
+    /// Total area of the triangles
+    pub fn area(&self) -> f64 { self.area }
+
+    /// Point at (s, t) in the unit square mapped onto the triangles, and the unit normal there.
+    /// 's' picks a triangle by area, and what is left of it places the point within the triangle along with 't'.
+    fn point(&self, s: f64, t: f64) -> (Vector, Vector) {
+        let (point, index, _) = self.distribution.sample(s);
+        let s = (point * self.triangles.len() as f64 - index as f64).clamp(0.0, 1.0);
+        let (a, u, v) = self.triangles[index];
+        (a + triangle_point(&u, &v, s, t), u.cross(&v).to_unit())
+    }
+}
+
+/// Source of light. Lights themselves are invisible, and only show by what they light, unless they stand in for a
+/// glowing shape. Area lights cast soft shadows, found by tracing 'samples' shadow rays towards points spread over the light from
+/// every point being lit.
+#[derive(Debug, Clone, new)]
+pub struct Light {
+    pub position: Vector,
+    pub color: Vector,
+    pub brightness: f64,
+    pub kind: LightKind,
+    pub samples: u32
+}
+
+/// Light arriving at a point from one point sampled on a light
+#[derive(Debug, Clone, Copy)]
+pub struct LightSample {
+
+    /// From the lit point to the point on the light. Also the direction of the shadow ray.
+    pub dir: Vector,
+
+    /// Color of the light
+    pub color: Vector,
+
+    /// The light's brightness, shared between its samples and dimmed where the light is seen edge on
+    pub brightness: f64,
+
+    /// How much the light fades with distance
+    pub falloff: f64,
+
+    /// Share of the light's samples this sample stands for
+    pub weight: f64
+}
+
+impl Light {
+
+    /// Point light
+    pub fn point(position: Vector, color: Vector, brightness: f64) -> Light {
+        Light::new(position, color, brightness, LightKind::Point, 1)
+    }
+
+    /// Light standing in for the surface of a glowing shape, which gives off 'emission' from every point of its
+    /// 'area'. Spheres count the area of their outline.
+    /// Lit surfaces then come out as bright as they would by gathering the light of every point of the shape.
+    pub fn surface(position: Vector, kind: LightKind, emission: Vector, area: f64, samples: u32) -> Light {
+        let peak = emission.x.max(emission.y).max(emission.z);
+        let color = if peak > 0.0 { emission / peak } else { emission };
+        Light::new(position, color, peak * area / PI, kind, samples)
+    }
+
+    /// Whether the light has a surface to spread shadow samples over
+    pub fn is_area(&self) -> bool {
+        match self.kind {
+            LightKind::Rect { .. }
+            | LightKind::Disk { .. }
+            | LightKind::Sphere { .. }
+            | LightKind::Triangle { .. }
+            | LightKind::Triangles(_) => true,
+            LightKind::Point | LightKind::Directional { .. } | LightKind::Spot { .. } => false
+        }
+    }
+
+    /// Samples points on the light as seen from 'point', stratifying them over the light's surface.
+    /// Samples of directional lights reach 'reach' away, standing in for infinity.
+    /// Points on the back of one-sided lights and outside of spot lights' cones are left out, as are all points of
+    /// spheres surrounding 'point'.
+    pub fn sample<R: Rng>(&self, point: &Vector, reach: f64, rng: &mut R) -> Vec<LightSample> {
+        if let LightKind::Directional { direction } = self.kind {
+            let sample = LightSample {
+                dir: -direction.to_unit() * reach,
+                color: self.color,
+                brightness: self.brightness,
+                falloff: 1.0,
+                weight: 1.0
+            };
+            return vec![sample];
+        }
+
+        // Spheres fade with the solid angle they cover rather than with the distance to each sample, which stays
+        // finite close up
+        let sphere_falloff = match self.kind {
+            LightKind::Sphere { radius } => {
+                let dist_squared = (*point - self.position).len_squared();
+                if dist_squared <= radius*radius { return Vec::new(); }
+                let cos_max = (1.0 - radius*radius / dist_squared).sqrt();
+                Some(2.0 / (dist_squared * (1.0 + cos_max)))
+            },
+            _ => None
+        };
+
+        let positions = if self.is_area() { SamplePattern::Jittered.samples(self.samples.max(1), rng) }
+            else { vec![(0.5, 0.5)] };
+        let weight = 1.0 / positions.len() as f64;
+        let mut samples = Vec::with_capacity(positions.len());
+        for (s, t) in positions {
+            let (target, facing) = self.sample_point(point, s, t);
+            let dir = target - *point;
+
+            // Dims light seen edge on, or towards the edge of a spot light's cone
+            let dimming = match (&self.kind, facing) {
+                (&LightKind::Spot { direction, inner_angle, outer_angle }, _) => {
+                    let cos_angle = -direction.to_unit().dot(&dir.to_unit());
+                    smoothstep(outer_angle.cos(), inner_angle.cos(), cos_angle)
+                },
+                (_, Some(normal)) => -normal.dot(&dir.to_unit()),
+                (_, None) => 1.0
+            };
+            if dimming <= 0.0 { continue; }
+
+            samples.push(LightSample {
+                dir,
+                color: self.color,
+                brightness: self.brightness * weight * dimming,
+                falloff: sphere_falloff.unwrap_or_else(|| 1.0 / dir.len_squared()),
+                weight
+            });
+        }
+        samples
+    }
+
+    /// Point at (s, t) in the unit square mapped onto the light as seen from 'point', and the unit normal of the
+    /// light there for lights that only shine from one side.
+    fn sample_point(&self, point: &Vector, s: f64, t: f64) -> (Vector, Option<Vector>) {
+        match self.kind {
+            LightKind::Point | LightKind::Directional { .. } | LightKind::Spot { .. } => (self.position, None),
+            LightKind::Rect { u, v } => {
+                let target = self.position + u * (s - 0.5) + v * (t - 0.5);
+                (target, Some(u.cross(&v).to_unit()))
+            },
+            LightKind::Disk { normal, radius } => {
+                (self.position + disk_point(&normal, radius, s, t), Some(normal.to_unit()))
+            },
+            LightKind::Sphere { radius } => {
+
+                // Spheres look like disks facing the viewer, so samples a disk and lifts it onto the sphere's surface.
+                // The disk is shrunk to the outline of the cap that can be seen from 'point'.
+                let towards = *point - self.position;
+                let visible = (1.0 - radius*radius / towards.len_squared()).max(0.0).sqrt();
+                let offset = disk_point(&towards, radius * visible, s, t);
+                let lift = (radius*radius - offset.len_squared()).max(0.0).sqrt();
+                (self.position + offset + towards.to_unit() * lift, None)
+            },
+            LightKind::Triangle { u, v } => {
+                (self.position + triangle_point(&u, &v, s, t), Some(u.cross(&v).to_unit()))
+            },
+            LightKind::Triangles(ref triangles) => {
+                let (offset, normal) = triangles.point(s, t);
+                (self.position + offset, Some(normal))
+            }
+        }
+    }
+}
+
+/// Point at (s, t) in the unit square mapped onto the triangle with edges 'u' and 'v' leaving the origin
+fn triangle_point(u: &Vector, v: &Vector, s: f64, t: f64) -> Vector {
+
+    // Folds the far half of the square back onto the triangle
+    let (s, t) = if s + t > 1.0 { (1.0 - s, 1.0 - t) } else { (s, t) };
+    *u * s + *v * t
+}
+
+impl LightSample {
+
+    /// Ray from 'point' towards the sample, for finding what shadows it
+    pub fn shadow_ray(&self, point: &Vector) -> Ray {
+        Ray::new(*point, self.dir * SHADOW_REACH)
+    }
+}
+
+/// Point on a disk of radius 'radius' centered on the origin and facing 'normal', at (s, t) in the unit square.
+/// Evenly spread (s, t) give evenly spread points.
+fn disk_point(normal: &Vector, radius: f64, s: f64, t: f64) -> Vector {
+    let r = radius * s.sqrt();
+    let phi = 2.0 * PI * t;
+    Basis::from_w(normal).to_world(&Vector::new(r * phi.cos(), r * phi.sin(), 0.0))
+}
+
+/// 0 below 'edge0', 1 above 'edge1' and a smooth curve between
+fn smoothstep(edge0: f64, edge1: f64, x: f64) -> f64 {
+    if edge1 <= edge0 { return if x >= edge1 { 1.0 } else { 0.0 }; }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampling::pixel_rng;
+
+    /// Spot light at the origin shining down -z, at full brightness within 10 degrees and dark past 30
+    fn spot() -> Light {
+        let kind = LightKind::Spot {
+            direction: Vector::new(0.0, 0.0, -2.0),
+            inner_angle: (10f64).to_radians(),
+            outer_angle: (30f64).to_radians()
+        };
+        Light::new(Vector::new(0.0, 0.0, 0.0), Vector::new(1.0, 1.0, 1.0), 3.0, kind, 1)
+    }
+
+    /// Samples the light from a point 'distance' away at 'degrees' off the -z axis
+    fn sample_at(light: &Light, degrees: f64, distance: f64) -> Vec<LightSample> {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let point = Vector::new(sin * distance, 0.0, -cos * distance);
+        light.sample(&point, 100.0, &mut pixel_rng(0, 0, 0))
+    }
+
+    #[test]
+    fn spot_lights_fade_smoothly_between_their_angles() {
+        let light = spot();
+        for &degrees in &[0.0, 5.0, 10.0] {
+            let samples = sample_at(&light, degrees, 2.0);
+            assert_eq!(samples.len(), 1);
+            assert!((samples[0].brightness - 3.0).abs() < 1e-9, "{} degrees gives {}", degrees, samples[0].brightness);
+            assert!((samples[0].falloff - 0.25).abs() < 1e-9);
+        }
+
+        // Fades with the cosine of the angle, along a smoothstep curve
+        let t = ((20f64).to_radians().cos() - (30f64).to_radians().cos())
+            / ((10f64).to_radians().cos() - (30f64).to_radians().cos());
+        let halfway = sample_at(&light, 20.0, 2.0);
+        assert!((halfway[0].brightness - 3.0 * t * t * (3.0 - 2.0 * t)).abs() < 1e-9);
+
+        let mut last = 3.0;
+        for degrees in 11..30 {
+            let brightness = sample_at(&light, degrees as f64, 2.0)[0].brightness;
+            assert!(brightness < last && brightness > 0.0, "{} degrees gives {}", degrees, brightness);
+            last = brightness;
+        }
+    }
+
+    #[test]
+    fn spot_lights_leave_the_outside_of_their_cone_dark() {
+        let light = spot();
+        for &degrees in &[30.0, 45.0, 90.0, 180.0] {
+            assert!(sample_at(&light, degrees, 2.0).is_empty(), "{} degrees is lit", degrees);
+        }
+    }
+
+    #[test]
+    fn directional_lights_ignore_distance_and_position() {
+        let kind = LightKind::Directional { direction: Vector::new(0.0, -3.0, 0.0) };
+        let light = Light::new(Vector::new(5.0, 5.0, 5.0), Vector::new(1.0, 0.5, 0.25), 2.0, kind, 1);
+        for point in &[Vector::new(0.0, 0.0, 0.0), Vector::new(-40.0, 1000.0, 7.0)] {
+            let samples = light.sample(point, 50.0, &mut pixel_rng(0, 0, 0));
+            assert_eq!(samples.len(), 1);
+            assert!((samples[0].dir - Vector::new(0.0, 50.0, 0.0)).len() < 1e-9);
+            assert_eq!((samples[0].brightness, samples[0].falloff, samples[0].weight), (2.0, 1.0, 1.0));
+        }
+    }
+}
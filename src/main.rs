@@ -1,20 +1,28 @@
-#[macro_use]
-extern crate derive_new;
 extern crate raster;
 extern crate rand;
 extern crate pad;
 extern crate ray_tracer;
 
-pub mod geom;
-pub mod scene;
-pub mod shape;
+mod options;
 
-use geom::{Vector, Ray};
-use shape::{Shape, Sphere, Floor};
-use scene::{Scene, Camera, Light};
+use ray_tracer::geom::{Vector, Ray};
+use ray_tracer::shape::{Shape, Sphere, Floor};
+use ray_tracer::scene::{Scene, Camera};
+use ray_tracer::light::{Light, LightKind};
+use ray_tracer::material::{Material};
+use ray_tracer::render::{Renderer, Passes, heatmap};
+use ray_tracer::scene_file;
+use ray_tracer::framebuffer::Framebuffer;
+use ray_tracer::hdr::{self, HdrFormat};
+use ray_tracer::exr::{self, Channel, PixelType};
+use options::{Options, USAGE};
 use raster::{Image};
-use std::time::{Duration, Instant};
+use std::time::{Instant};
 use std::fs;
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::path::Path;
 use std::f64::consts::{PI};
 use rand::{Rng, SeedableRng, StdRng};
 use pad::{PadStr};
@@ -32,9 +40,7 @@ where T: Rng {
     Sphere {
         center: Vector::new(0.0, 0.0, 0.0),
         radius,
-        color,
-        reflectivity: 0.5,
-        exponent: 30.0
+        material: Arc::new(Material::new(color, 0.5, 30.0))
     }
 }
 
@@ -47,7 +53,48 @@ where T: Rng {
     }
 }
 
-fn main() {
+/// Number of frames in one loop of the demo animation
+const DEMO_FRAMES: u32 = 320;
+
+/// Motion of the demo's camera and spheres
+struct Animation {
+    positions: Vec<Vector>,
+    directions: Vec<Vector>
+}
+
+impl Animation {
+
+    /// Moves camera and spheres to where they are at the given frame
+    fn apply(&self, scene: &mut Scene, frame: u32) {
+
+        // Sets camera position
+        let camera_dist = 25.0;
+        let t: f64 = (frame as f64) / (DEMO_FRAMES as f64);
+        let theta: f64 = t * PI;
+        let sin_theta2 = (theta*2.0).sin();
+        let cos_theta2 = (theta*2.0).cos();
+        let camera = &mut scene.camera;
+        camera.eye.origin = Vector {
+            x: sin_theta2 * camera_dist,
+            y: 10.0 + sin_theta2 * 10.0,
+            z: cos_theta2 * camera_dist
+        };
+        camera.look_at(Vector::new(0.0, 0.0, 0.0));
+
+        // Moves shapes for next frame
+        for i in 0..self.positions.len() {
+            let shape: &mut Box<dyn Shape> = &mut scene.shapes_mut()[i];
+            let position: Vector = self.positions[i];
+            let direction: Vector = self.directions[i];
+            let new_pos = position + direction * sin_theta2;
+            shape.set_position(&new_pos);
+        }
+        scene.refit();
+    }
+}
+
+/// Builds the demo scene of randomly colored spheres, along with their motion
+fn demo(seed: &[usize]) -> (Scene, Animation) {
 
     // Creates Camera that will be used in the scene
     let origin = Vector::new(0.0, 2.0, 30.0);
@@ -62,12 +109,11 @@ fn main() {
     };
 
     // Creates random number generator for creating objects in scene
-    let seed: &[_] = &[1, 2, 3, 4];
     let mut rng:StdRng = SeedableRng::from_seed(seed);
     let num_spheres = 20;
 
     // Creates sphere(s) using rng
-    let mut shapes: Vec<Box<Shape>> = Vec::new();
+    let mut shapes: Vec<Box<dyn Shape>> = Vec::new();
     for _ in 0..num_spheres {
         shapes.push(
             Box::new(
@@ -86,9 +132,7 @@ fn main() {
             Sphere {
                 center: Vector::new(0.0, 0.0, 0.0),
                 radius: 400.0,
-                color: Vector::new(0.0, 1.0, 0.5),
-                reflectivity: 0.0,
-                exponent: 100.0
+                material: Arc::new(Material::new(Vector::new(0.0, 1.0, 0.5), 0.0, 100.0))
             }
         )
     );
@@ -99,9 +143,7 @@ fn main() {
         Box::new (
             Floor {
                 position: Vector::new(0.0, -5.0, 0.0),
-                color: Vector::new(0.0, 1.0, 0.5),
-                reflectivity: 0.7,
-                exponent: 15.0
+                material: Arc::new(Material::new(Vector::new(0.0, 1.0, 0.5), 0.7, 15.0))
             }
         )
     );
@@ -128,80 +170,178 @@ fn main() {
         directions.push(rand_vel);
     }
 
-    // Adds zero velocity for floor
-    let zero = Vector::new(0.0, 0.0, 0.0);
-
-    // Creates lights
-    let num_lights = 80;
-    let mut lights = Vec::new();
-    for _ in 0..num_lights {
-        let rand_vec =  Vector::rand(&mut rng) * 4.0;
-        lights.push(
-            Light {
-                position: Vector::new(0.0, 80.0, 0.0) + rand_vec,
-                color: Vector::new(1.0, 1.0, 1.0)  / (num_lights as f64),
-                brightness: 20000.0
-            }
+    // Creates a light above the scene, big enough to cast soft shadows
+    let lights = vec![
+        Light::new(
+            Vector::new(0.0, 80.0, 0.0),
+            Vector::new(1.0, 1.0, 1.0),
+            20000.0,
+            LightKind::Sphere { radius: 4.0 },
+            16
         )
-    }
+    ];
 
     // Builds scene that will use camera
-    let mut scene = Scene {
-        color_background: Vector::new(0.2, 0.2, 0.2),
-        color_ambient: Vector::new(0.1, 0.1, 0.1),
+    let scene = Scene::new(
+        Vector::new(0.2, 0.2, 0.2),
+        Vector::new(0.1, 0.1, 0.1),
         camera,
         shapes,
         lights,
-        bounce_limit: 2
+        2
+    );
+
+    (scene, Animation { positions, directions })
+}
+
+fn main() {
+
+    // Reads options, bailing out with usage on bad input
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match Options::parse(&args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        },
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
     };
 
-    // Create canvas image
-    let mut canvas = Image::blank(1920, 1080);
+    // Loads scene file, or builds the demo if there is none
+    let (mut scene, animation) = match options.scene {
+        Some(ref path) => match scene_file::load_scene_with_warnings(path) {
+            Ok((scene, warnings)) => {
+                for warning in warnings {
+                    eprintln!("warning: {}", warning);
+                }
+                (scene, None)
+            },
+            Err(err) => {
+                eprintln!("error: {}", err);
+                process::exit(1);
+            }
+        },
+        None => {
+            let (scene, animation) = demo(&options.seed);
+            (scene, Some(animation))
+        }
+    };
+    if let Some(bounce_limit) = options.bounce_limit {
+        scene.bounce_limit = bounce_limit;
+    }
 
-    // For a number of frames...
-    let frames = 320;
-    let camera_dist = 25.0;
-    for frame in 0..frames {
+    // Create canvas, kept as linear colors until saved
+    let mut canvas = Framebuffer::new(options.width as usize, options.height as usize);
+
+    let mut renderer = Renderer::default();
+    if let Some(threads) = options.threads {
+        renderer.threads = threads;
+    }
+    renderer.integrator = options.integrator;
+    renderer.samples = options.samples;
+    renderer.pattern = options.sampling;
+    renderer.filter = options.filter;
+    renderer.adaptive = options.adaptive();
+    renderer.seed = options.sample_seed();
+
+    // For the requested frames...
+    // Scene files are stills, so only need one frame by default.
+    let default_frames = if animation.is_some() { DEMO_FRAMES } else { 1 };
+    for frame in options.frames(default_frames) {
 
         let now = Instant::now();
         println!("Rendering frame {}", frame);
 
-        {
-            // Sets camera position
-            let t: f64 = (frame as f64) / (frames as f64);
-            let theta: f64 = t * PI;
-            let sin_theta2 = (theta*2.0).sin();
-            let cos_theta2 = (theta*2.0).cos();
-            let mut camera = &mut scene.camera;
-            camera.eye.origin = Vector {
-                x: sin_theta2 * camera_dist,
-                y: 10.0 + sin_theta2 * 10.0,
-                z: cos_theta2 * camera_dist
-            };
-            camera.look_at(Vector::new(0.0, 0.0, 0.0));
-
-            // Moves shapes for next frame
-            for i in 0..num_moveable {
-                let mut shape: &mut Box<Shape> = &mut scene.shapes[i];
-                let position: Vector = positions[i];
-                let direction: Vector = directions[i];
-                let new_pos = position + direction * sin_theta2;
-                shape.set_position(&new_pos);
-            }
+        if let Some(ref animation) = animation {
+            animation.apply(&mut scene, frame);
         }
 
         // Trace scene
-        scene.render(&mut canvas);
+        let counts = renderer.render_framebuffer(&scene, &mut canvas);
 
         // Finishes rendering
         println!("Finished frame {} in {} seconds.", frame, now.elapsed().as_secs());
 
-        // Save image
-        fs::create_dir_all("images").unwrap();
+        // Save image, and the samples spent on it if requested
         let number_str = format!("{}", frame).pad(5, '0', Alignment::Right, false);
-        let filename: String = format!("images/frame_{}.png", number_str);
-        raster::save(&canvas, &filename).unwrap();
+        let name = options.pattern.replace("{frame}", &number_str);
+        if is_exr(&name) {
+            let passes = renderer.render_passes(&scene, canvas.width, canvas.height);
+            save_exr(&canvas, &passes, &options, &name);
+        }
+        else {
+            save_frame(&canvas, &options.output_dir, &name);
+        }
+        if options.heatmap {
+            let max_samples = options.adaptive.as_ref().map_or(options.samples, |range| range.end);
+            let heatmap = heatmap(&counts, options.width, options.height, max_samples);
+
+            // The heatmap is an 8-bit image, so is saved as a PNG beside HDR frames
+            let heatmap_name = if HdrFormat::from_path(&name).is_some() || is_exr(&name) {
+                Path::new(&name).with_extension("png").to_string_lossy().into_owned()
+            }
+            else {
+                name.clone()
+            };
+            save(&heatmap, &options.output_dir, &format!("heatmap_{}", heatmap_name));
+        }
     }
 
     println!("Done!!!!!");
-}
\ No newline at end of file
+}
+
+/// Saves a rendered frame to dir/name, exiting on failure.
+/// Names ending in .hdr, .pic or .pfm keep the frame's unclamped linear colors; others are clamped to 8 bits.
+fn save_frame(framebuffer: &Framebuffer, dir: &Path, name: &str) {
+    if HdrFormat::from_path(name).is_none() {
+        return save(&framebuffer.to_image(), dir, name);
+    }
+    let filename = dir.join(name);
+    let saved = fs::create_dir_all(dir).and_then(|_| hdr::save(framebuffer, &filename));
+    if let Err(err) = saved {
+        eprintln!("error: could not save {}: {}", filename.display(), err);
+        process::exit(1);
+    }
+}
+
+/// Whether 'name' is of an OpenEXR file
+fn is_exr(name: &str) -> bool {
+    Path::new(name).extension().is_some_and(|e| e.eq_ignore_ascii_case("exr"))
+}
+
+/// Saves a rendered frame and its passes to an OpenEXR file in the output directory, exiting on failure.
+/// Colors go in the R, G and B channels, with depth in Z and the other passes in layers named after them.
+fn save_exr(framebuffer: &Framebuffer, passes: &Passes, options: &Options, name: &str) {
+    let pixels = options.exr_pixels;
+    let mut channels = Channel::rgb(framebuffer, pixels);
+    channels.push(Channel::new("Z", &passes.depth, 0, PixelType::Float));
+    channels.push(Channel::new("normal.X", &passes.normal, 0, pixels));
+    channels.push(Channel::new("normal.Y", &passes.normal, 1, pixels));
+    channels.push(Channel::new("normal.Z", &passes.normal, 2, pixels));
+    channels.push(Channel::new("albedo.R", &passes.albedo, 0, pixels));
+    channels.push(Channel::new("albedo.G", &passes.albedo, 1, pixels));
+    channels.push(Channel::new("albedo.B", &passes.albedo, 2, pixels));
+
+    let filename = options.output_dir.join(name);
+    let saved = fs::create_dir_all(&options.output_dir)
+        .and_then(|_| exr::save(&channels, options.exr_compression, &filename));
+    if let Err(err) = saved {
+        eprintln!("error: could not save {}: {}", filename.display(), err);
+        process::exit(1);
+    }
+}
+
+/// Saves image to dir/name, exiting on failure
+fn save(image: &Image, dir: &Path, name: &str) {
+    let filename = dir.join(name);
+    let saved = fs::create_dir_all(dir)
+        .map_err(|e| e.to_string())
+        .and_then(|_| raster::save(image, &filename.to_string_lossy()).map_err(|e| format!("{:?}", e)));
+    if let Err(err) = saved {
+        eprintln!("error: could not save {}: {}", filename.display(), err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,332 @@
+use geom::{Vector, Basis, Intersection};
+use microfacet::Microfacet;
+use texture::Texture;
+
+/// Step in texture coordinates over which bump maps are differentiated
+const BUMP_STEP: f64 = 0.001;
+
+/// Describes how a surface responds to light.
+/// Shapes hold materials through an Arc, so any number of shapes can share one.
+#[derive(Debug, Clone)]
+pub struct Material {
+
+    /// Diffuse color
+    pub color: Vector,
+
+    /// Fraction of light mirrored off the surface. Also scales specular highlights.
+    pub reflectivity: f64,
+
+    /// Phong exponent. Higher values give smaller, sharper highlights.
+    pub exponent: f64,
+
+    /// Fraction of the surface that behaves as a clear dielectric, such as glass or water, rather than as the
+    /// opaque surface described above. Light hitting it is split between reflection and refraction by Fresnel's equations.
+    pub transparency: f64,
+
+    /// Index of refraction of the inside of the shape. The outside is taken to be air, with an index of 1.
+    pub ior: f64,
+
+    /// Fraction of each color channel absorbed per unit of distance travelled inside the shape, following the
+    /// Beer-Lambert law. Tints transparent shapes more deeply the thicker they are.
+    pub absorption: Vector,
+
+    /// If set, the opaque part of the surface follows this physically based model instead of Phong's, with 'color' as
+    /// its base color. Reflectivity and exponent are then unused.
+    pub microfacet: Option<Microfacet>,
+
+    /// Light given off by the surface, added on top of the light it reflects. Channels may go above 1.
+    /// Only the outside of shapes glows. Spheres, quads, triangles and meshes also light the rest of the scene, while
+    /// other shapes only show their glow to rays that hit them.
+    pub emission: Vector,
+
+    /// If set, gives the color of each point of the surface in place of 'color'
+    pub texture: Option<Texture>,
+
+    /// If set, gives the reflectivity of each point of the surface in place of 'reflectivity', as the mean of its
+    /// color channels
+    pub reflectivity_texture: Option<Texture>,
+
+    /// If set, scales 'exponent' at each point of the surface by the mean of its color channels
+    pub exponent_texture: Option<Texture>,
+
+    /// If set, bends the shading normal towards the direction its colors hold in tangent space, with red, green and
+    /// blue running from -1 to 1 along increasing u, increasing v and the normal
+    pub normal_map: Option<Texture>,
+
+    /// If set, bends the shading normal as if the surface were raised by the mean of its color channels times
+    /// 'bump_height', in units of distance. Applies on top of the normal map.
+    pub bump_map: Option<Texture>,
+    pub bump_height: f64
+}
+
+impl Material {
+    pub fn new(color: Vector, reflectivity: f64, exponent: f64) -> Material {
+        Material {
+            color,
+            reflectivity,
+            exponent,
+            transparency: 0.0,
+            ior: 1.0,
+            absorption: Vector::new(0.0, 0.0, 0.0),
+            microfacet: None,
+            emission: Vector::new(0.0, 0.0, 0.0),
+            texture: None,
+            reflectivity_texture: None,
+            exponent_texture: None,
+            normal_map: None,
+            bump_map: None,
+            bump_height: 1.0
+        }
+    }
+
+    /// Physically based material with base color 'color'. See Microfacet.
+    pub fn metallic_roughness(color: Vector, metallic: f64, roughness: f64) -> Material {
+        Material { microfacet: Some(Microfacet::new(metallic, roughness)), ..Material::new(color, 0.0, 1.0) }
+    }
+
+    /// Clear, fully transparent material with index of refraction 'ior'
+    pub fn dielectric(ior: f64) -> Material {
+        Material { transparency: 1.0, ior, ..Material::new(Vector::new(1.0, 1.0, 1.0), 0.0, 100.0) }
+    }
+
+    /// Fraction of light reflected off the dielectric part of the surface, rather than refracted through it.
+    /// 'cos_i' is the cosine of the angle between the incoming direction and the normal, and 'inside' is whether the
+    /// light arrives from inside the shape.
+    /// Returns 1 on total internal reflection.
+    pub fn fresnel(&self, cos_i: f64, inside: bool) -> f64 {
+        let (n1, n2) = if inside { (self.ior, 1.0) } else { (1.0, self.ior) };
+        let sin2_t = (n1/n2) * (n1/n2) * (1.0 - cos_i*cos_i);
+        if sin2_t >= 1.0 { return 1.0; }
+        let cos_t = (1.0 - sin2_t).sqrt();
+        let rs = (n1*cos_i - n2*cos_t) / (n1*cos_i + n2*cos_t);
+        let rp = (n1*cos_t - n2*cos_i) / (n1*cos_t + n2*cos_i);
+        (rs*rs + rp*rp) / 2.0
+    }
+
+    /// Fraction of each color channel left after travelling 'distance' inside the shape
+    pub fn absorb(&self, distance: f64) -> Vector {
+        let a = self.absorption;
+        Vector::new((-a.x * distance).exp(), (-a.y * distance).exp(), (-a.z * distance).exp())
+    }
+
+    /// Color of the surface at the point hit
+    pub fn color_at(&self, inter: &Intersection) -> Vector {
+        match self.texture {
+            Some(ref texture) => texture.color(inter),
+            None => self.color
+        }
+    }
+
+    /// Reflectivity of the surface at the point hit
+    pub fn reflectivity_at(&self, inter: &Intersection) -> f64 {
+        match self.reflectivity_texture {
+            Some(ref texture) => texture.value(inter),
+            None => self.reflectivity
+        }
+    }
+
+    /// Phong exponent of the surface at the point hit
+    pub fn exponent_at(&self, inter: &Intersection) -> f64 {
+        match self.exponent_texture {
+            Some(ref texture) => self.exponent * texture.value(inter),
+            None => self.exponent
+        }
+    }
+
+    /// Normal to shade the point hit with, bent by the normal and bump maps.
+    /// Bumps are worked out on the front of the surface, then flipped to face the ray with the rest of the normal, so
+    /// that they look the same from either side.
+    /// Without either map, the normal is returned as is, which may not be of unit length.
+    pub fn shading_normal(&self, inter: &Intersection) -> Vector {
+        if self.normal_map.is_none() && self.bump_map.is_none() { return inter.normal; }
+        let side = if inter.inside { -1.0 } else { 1.0 };
+        let mut normal = inter.normal.to_unit() * side;
+
+        if let Some(ref map) = self.normal_map {
+            let (tangent, bitangent) = tangent_frame(&normal, inter);
+            let c = map.color(inter);
+            let bent = tangent * (2.0*c.x - 1.0) + bitangent * (2.0*c.y - 1.0) + normal * (2.0*c.z - 1.0);
+            if bent.len_squared() > 0.0 { normal = bent.to_unit(); }
+        }
+
+        if let Some(ref map) = self.bump_map {
+
+            // Differentiates the height along u and v, then tilts the surface's tangents up the slope
+            let height = |du: f64, dv: f64| {
+                let uv = [inter.uv[0] + du, inter.uv[1] + dv];
+                let position = inter.position + inter.dpdu * du + inter.dpdv * dv;
+                map.value(&Intersection { uv, position, ..*inter }) * self.bump_height
+            };
+            let base = height(0.0, 0.0);
+            let dhdu = (height(BUMP_STEP, 0.0) - base) / BUMP_STEP;
+            let dhdv = (height(0.0, BUMP_STEP) - base) / BUMP_STEP;
+            let bent = (inter.dpdu + normal * dhdu).cross(&(inter.dpdv + normal * dhdv));
+            if bent.len_squared() > 0.0 {
+                normal = if bent.dot(&normal) < 0.0 { -bent.to_unit() } else { bent.to_unit() };
+            }
+        }
+        normal * side
+    }
+
+    /// Whether the surface glows
+    pub fn is_emissive(&self) -> bool {
+        self.emission.len_squared() > 0.0
+    }
+
+    /// Light given off towards a ray hitting the surface from 'inside' the shape or not
+    pub fn emitted(&self, inside: bool) -> Vector {
+        if inside { Vector::new(0.0, 0.0, 0.0) } else { self.emission }
+    }
+
+    /// Ratio of the index of refraction being left to the one being entered, for a ray that is 'inside' or not
+    pub fn eta(&self, inside: bool) -> f64 {
+        if inside { self.ior } else { 1.0 / self.ior }
+    }
+}
+
+/// Unit tangent and bitangent at the point hit, running along increasing u and v as nearly as they can while at right
+/// angles to 'normal' and each other
+fn tangent_frame(normal: &Vector, inter: &Intersection) -> (Vector, Vector) {
+    let flatten = |v: &Vector| *v - *normal * normal.dot(v);
+    let tangent = flatten(&inter.dpdu);
+    let tangent = if tangent.len_squared() > 0.0 { tangent.to_unit() } else { Basis::from_w(normal).u };
+    let bitangent = flatten(&inter.dpdv);
+    let bitangent = bitangent - tangent * tangent.dot(&bitangent);
+    let bitangent = if bitangent.len_squared() > 0.0 { bitangent.to_unit() } else { normal.cross(&tangent) };
+    (tangent, bitangent)
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material::new(Vector::new(0.8, 0.8, 0.8), 0.0, 10.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use framebuffer::Framebuffer;
+    use texture::{ImageTexture, TextureFilter, Wrap};
+
+    #[test]
+    fn absorbs_exponentially_with_distance() {
+        let mut glass = Material::dielectric(1.5);
+        glass.absorption = Vector::new(0.5, 1.0, 0.0);
+        let absorbed = glass.absorb(2.0);
+        assert!((absorbed.x - (-1.0f64).exp()).abs() < 1e-12);
+        assert!((absorbed.y - (-2.0f64).exp()).abs() < 1e-12);
+        assert_eq!(absorbed.z, 1.0);
+        assert!((glass.absorb(0.0) - Vector::new(1.0, 1.0, 1.0)).len() < 1e-12);
+
+        // Crossing twice the distance is the same as crossing it twice over
+        let twice = glass.absorb(1.0) * glass.absorb(1.0);
+        assert!((twice - absorbed).len() < 1e-12);
+    }
+
+    #[test]
+    fn reflects_little_head_on() {
+        let glass = Material::dielectric(1.5);
+        let head_on = ((1.5 - 1.0) / (1.5 + 1.0)) * ((1.5 - 1.0) / (1.5 + 1.0));
+        assert!((glass.fresnel(1.0, false) - head_on).abs() < 1e-12);
+        assert!((glass.fresnel(1.0, true) - head_on).abs() < 1e-12);
+
+        // Reflection grows towards grazing angles
+        assert!(glass.fresnel(0.5, false) > head_on);
+        assert!(glass.fresnel(1e-6, false) > 0.99);
+    }
+
+    #[test]
+    fn reflects_everything_inside_past_the_critical_angle() {
+        let glass = Material::dielectric(1.5);
+        let critical_cos = (1.0f64 - 1.0 / (1.5 * 1.5)).sqrt();
+        assert!(glass.fresnel(critical_cos + 0.01, true) < 1.0);
+        assert_eq!(glass.fresnel(critical_cos - 0.01, true), 1.0);
+
+        // Light entering from outside always gets through
+        assert!(glass.fresnel(critical_cos - 0.01, false) < 1.0);
+    }
+
+    #[test]
+    fn bends_the_other_way_leaving_the_shape() {
+        let glass = Material::dielectric(1.5);
+        assert!((glass.eta(false) - 1.0 / 1.5).abs() < 1e-12);
+        assert!((glass.eta(true) - 1.5).abs() < 1e-12);
+
+        // Normals face the ray, so a ray leaving through the top of a shape sees a normal pointing down.
+        // It bends away from the normal, the reverse of entering.
+        let normal = Vector::new(0.0, -1.0, 0.0);
+        let dir = Vector::new((20f64).to_radians().sin(), (20f64).to_radians().cos(), 0.0);
+        let out = dir.refract(&normal, glass.eta(true)).unwrap();
+        assert!((out.x - dir.x * 1.5).abs() < 1e-9 && out.y > 0.0);
+        let back = Vector::new(-out.x, -out.y, 0.0).refract(&-normal, glass.eta(false)).unwrap();
+        assert!((back.x + dir.x).abs() < 1e-9 && (back.y + dir.y).abs() < 1e-9);
+    }
+
+    /// Texture that is 'color' everywhere
+    fn flat(color: Vector) -> Texture {
+        let mut image = Framebuffer::new(1, 1);
+        image.set(0, 0, color);
+        Texture::Image(ImageTexture::from_framebuffer(&image, TextureFilter::Nearest, Wrap::Repeat))
+    }
+
+    /// Hit on a tilted surface whose texture directions lie in it but are neither unit length nor at right angles
+    fn tilted_hit(material: &Material, inside: bool) -> Intersection<'_> {
+        let normal = Vector::new(1.0, 2.0, 2.0) / 3.0;
+        Intersection {
+            t: 0.5,
+            position: Vector::new(0.3, -0.2, 0.1),
+            normal: if inside { -normal } else { normal },
+            material,
+            inside,
+            uv: [0.25, 0.75],
+            dpdu: Vector::new(2.0, -1.0, 0.0),
+            dpdv: Vector::new(1.0, 0.0, -0.5)
+        }
+    }
+
+    #[test]
+    fn flat_maps_keep_the_geometric_normal() {
+        let normal_mapped = Material { normal_map: Some(flat(Vector::new(0.5, 0.5, 1.0))), ..Material::default() };
+        let bumped = Material { bump_map: Some(flat(Vector::new(0.7, 0.7, 0.7))), bump_height: 3.0, ..Material::default() };
+        let both = Material { bump_map: bumped.bump_map.clone(), ..normal_mapped.clone() };
+
+        for material in &[normal_mapped, bumped, both] {
+            for &inside in &[false, true] {
+                let inter = tilted_hit(material, inside);
+                let shading = material.shading_normal(&inter);
+                assert!((shading - inter.normal).len() < 1e-9, "expected {:?}, found {:?}", inter.normal, shading);
+            }
+        }
+    }
+
+    #[test]
+    fn tangent_frames_are_orthonormal() {
+        let material = Material::default();
+        let mut inter = tilted_hit(&material, false);
+        let check = |inter: &Intersection| {
+            let (tangent, bitangent) = tangent_frame(&inter.normal, inter);
+            for &(a, b, expected) in &[
+                (tangent, tangent, 1.0),
+                (bitangent, bitangent, 1.0),
+                (inter.normal, inter.normal, 1.0),
+                (tangent, bitangent, 0.0),
+                (tangent, inter.normal, 0.0),
+                (bitangent, inter.normal, 0.0)
+            ] {
+                assert!((a.dot(&b) - expected).abs() < 1e-9, "{:?} . {:?} isn't {}", a, b, expected);
+            }
+
+            // Right-handed, so that normal maps don't come out mirrored
+            assert!(tangent.cross(&bitangent).dot(&inter.normal) > 0.0);
+            (tangent, bitangent)
+        };
+        let (tangent, _) = check(&inter);
+        assert!(tangent.dot(&inter.dpdu) > 0.0);
+
+        // Falls back on some frame where the texture directions pinch to a point or run along the normal
+        inter.dpdu = Vector::new(0.0, 0.0, 0.0);
+        check(&inter);
+        inter.dpdv = inter.normal * 2.0;
+        check(&inter);
+    }
+}
@@ -0,0 +1,244 @@
+use geom::{Vector, Ray, Aabb, Intersection};
+use shape::{Shape, intersect_triangle};
+use light::{Light, LightKind, TriangleSet, SURFACE_SAMPLES};
+use bvh::Bvh;
+use material::Material;
+use std::sync::Arc;
+
+/// Triangle mesh built from shared vertex buffers and a list of indexed triangles.
+/// Triangles are kept in their own bounding volume hierarchy, so large meshes cost about as much to
+/// intersect as a single shape.
+/// Moving the mesh translates it as a whole without rebuilding its hierarchy.
+#[derive(Debug, Clone)]
+pub struct Mesh {
+    pub material: Arc<Material>,
+    position: Vector,
+    positions: Vec<Vector>,
+    normals: Option<Vec<Vector>>,
+    uvs: Option<Vec<[f64; 2]>>,
+    triangles: Vec<[usize; 3]>,
+    bvh: Bvh
+}
+
+impl Mesh {
+
+    /// Creates mesh from vertex positions and triangles indexing into them.
+    /// If present, 'normals' holds one normal per vertex, which are interpolated across each triangle for smooth shading.
+    /// Likewise, 'uvs' holds one texture coordinate per vertex.
+    /// Panics if an index is out of range or if the number of normals or uvs differs from the number of positions.
+    pub fn new(
+        positions: Vec<Vector>,
+        normals: Option<Vec<Vector>>,
+        uvs: Option<Vec<[f64; 2]>>,
+        triangles: Vec<[usize; 3]>,
+        material: Arc<Material>
+    ) -> Mesh {
+        if let Some(ref normals) = normals {
+            assert_eq!(normals.len(), positions.len(), "Mesh needs one normal per vertex");
+        }
+        if let Some(ref uvs) = uvs {
+            assert_eq!(uvs.len(), positions.len(), "Mesh needs one uv per vertex");
+        }
+        assert!(
+            triangles.iter().all(|tri| tri.iter().all(|&i| i < positions.len())),
+            "Mesh triangle index out of range"
+        );
+
+        let bounds: Vec<Aabb> = triangles
+            .iter()
+            .map(|tri| Aabb::new(positions[tri[0]], positions[tri[0]])
+                .grow(&positions[tri[1]])
+                .grow(&positions[tri[2]]))
+            .collect();
+        let bvh = Bvh::build(&bounds);
+
+        Mesh {
+            material,
+            position: Vector::new(0.0, 0.0, 0.0),
+            positions,
+            normals,
+            uvs,
+            triangles,
+            bvh
+        }
+    }
+
+    pub fn positions(&self) -> &[Vector] { &self.positions }
+    pub fn normals(&self) -> Option<&[Vector]> { self.normals.as_ref().map(|n| &n[..]) }
+    pub fn uvs(&self) -> Option<&[[f64; 2]]> { self.uvs.as_ref().map(|n| &n[..]) }
+    pub fn triangles(&self) -> &[[usize; 3]] { &self.triangles }
+
+    /// Intersects triangle at index 'tri' with a ray in the mesh's local space.
+    fn intersect_triangle(&self, tri: usize, local_ray: &Ray, ray: &Ray) -> Option<Intersection<'_>> {
+        let [i0, i1, i2] = self.triangles[tri];
+        let (a, b, c) = (self.positions[i0], self.positions[i1], self.positions[i2]);
+        let (t, u, v) = intersect_triangle(local_ray, &a, &b, &c)?;
+
+        // Interpolates vertex normals if there are any, facing them the same way as the geometric normal
+        let face_normal = (b - a).cross(&(c - a));
+        let back_facing = face_normal.dot(&ray.dir) > 0.0;
+        let normal = match self.normals {
+            Some(ref normals) => {
+                let smooth = normals[i0] * (1.0 - u - v) + normals[i1] * u + normals[i2] * v;
+                if smooth.dot(&face_normal) < 0.0 { -smooth } else { smooth }
+            },
+            None => face_normal
+        };
+        let normal = if back_facing { -normal } else { normal };
+
+        // Interpolates vertex uvs likewise, falling back on the barycentric coordinates.
+        // Tangents solve for how the edges move in texture space, which fails where the uvs are degenerate.
+        let (edge1, edge2) = (b - a, c - a);
+        let (uv, dpdu, dpdv) = match self.uvs {
+            Some(ref uvs) => {
+                let w = 1.0 - u - v;
+                let (uv0, uv1, uv2) = (uvs[i0], uvs[i1], uvs[i2]);
+                let uv = [uv0[0]*w + uv1[0]*u + uv2[0]*v, uv0[1]*w + uv1[1]*u + uv2[1]*v];
+                let (du1, dv1) = (uv1[0] - uv0[0], uv1[1] - uv0[1]);
+                let (du2, dv2) = (uv2[0] - uv0[0], uv2[1] - uv0[1]);
+                let det = du1*dv2 - du2*dv1;
+                if det.abs() > f64::EPSILON {
+                    (uv, (edge1 * dv2 - edge2 * dv1) / det, (edge2 * du1 - edge1 * du2) / det)
+                }
+                else {
+                    (uv, edge1, edge2)
+                }
+            },
+            None => ([u, v], edge1, edge2)
+        };
+
+        let inter = Intersection {
+            t,
+            position: ray.interp(t),
+            normal,
+            material: &self.material,
+            inside: back_facing,
+            uv,
+            dpdu,
+            dpdv
+        };
+        Some(inter)
+    }
+}
+
+impl Shape for Mesh {
+
+    fn get_position(&self) -> Vector { self.position }
+    fn set_position(&mut self, pos: &Vector) { self.position = *pos; }
+
+    fn bounds(&self) -> Aabb {
+        let bounds = self.bvh.bounds();
+        Aabb::new(bounds.min + self.position, bounds.max + self.position)
+    }
+
+    /// A single light covering every triangle, whose shadow samples land on each triangle in proportion to its area
+    fn lights(&self) -> Vec<Light> {
+        if !self.material.is_emissive() { return Vec::new(); }
+        let corners: Vec<[Vector; 3]> = self.triangles
+            .iter()
+            .map(|&[a, b, c]| [self.positions[a], self.positions[b], self.positions[c]])
+            .collect();
+        let triangles = TriangleSet::new(&corners);
+        if triangles.is_empty() { return Vec::new(); }
+        let area = triangles.area();
+        let kind = LightKind::Triangles(Arc::new(triangles));
+        vec![Light::surface(self.position, kind, self.material.emission, area, SURFACE_SAMPLES)]
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let local_ray = Ray::new(ray.origin - self.position, ray.dir);
+        self.bvh.intersect(&local_ray, |tri| self.intersect_triangle(tri, &local_ray, ray))
+    }
+
+    fn intersects(&self, ray: &Ray) -> bool {
+        let local_ray = Ray::new(ray.origin - self.position, ray.dir);
+        self.bvh.intersects(&local_ray, |tri| {
+            let [i0, i1, i2] = self.triangles[tri];
+            intersect_triangle(&local_ray, &self.positions[i0], &self.positions[i1], &self.positions[i2]).is_some()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampling::pixel_rng;
+
+    #[test]
+    fn glows_as_one_light_sampled_by_area() {
+
+        // Two triangles facing up, the second three times the size of the first
+        let positions = vec![
+            Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, -1.0), Vector::new(1.0, 0.0, 0.0),
+            Vector::new(2.0, 0.0, 0.0), Vector::new(2.0, 0.0, -3.0), Vector::new(3.0, 0.0, 0.0)
+        ];
+        let mut material = Material::new(Vector::new(1.0, 1.0, 1.0), 0.0, 1.0);
+        material.emission = Vector::new(2.0, 2.0, 2.0);
+        let mut mesh = Mesh::new(positions, None, None, vec![[0, 2, 1], [3, 5, 4]], Arc::new(material));
+        mesh.set_position(&Vector::new(0.0, 1.0, 0.0));
+
+        let lights = mesh.lights();
+        assert_eq!(lights.len(), 1);
+        assert_eq!(lights[0].samples, SURFACE_SAMPLES);
+        assert!((lights[0].brightness - 2.0 * 2.0 / ::std::f64::consts::PI).abs() < 1e-9);
+
+        let point = Vector::new(1.5, 5.0, 0.0);
+        let mut rng = pixel_rng(0, 0, 0);
+        let mut on_larger = 0;
+        let mut total = 0;
+        for _ in 0..64 {
+            for sample in lights[0].sample(&point, 100.0, &mut rng) {
+                let target = point + sample.dir;
+                assert!((target.y - 1.0).abs() < 1e-9);
+                if target.x >= 2.0 { on_larger += 1; }
+                total += 1;
+            }
+        }
+        assert_eq!(total, 64 * SURFACE_SAMPLES);
+
+        // Samples are stratified, so the larger triangle gets exactly its share
+        assert_eq!(on_larger * 4, total * 3);
+    }
+
+    /// Single triangle with a normal and uv at each corner
+    fn smooth_triangle() -> Mesh {
+        let positions = vec![Vector::new(0.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0)];
+        let normals = vec![Vector::new(0.0, 0.0, 1.0), Vector::new(1.0, 0.0, 1.0), Vector::new(0.0, 1.0, 1.0)];
+        let uvs = vec![[0.0, 0.0], [2.0, 0.0], [0.0, 4.0]];
+        let material = Material::new(Vector::new(1.0, 1.0, 1.0), 0.0, 1.0);
+        Mesh::new(positions, Some(normals), Some(uvs), vec![[0, 1, 2]], Arc::new(material))
+    }
+
+    fn assert_close(a: &Vector, b: &Vector) {
+        assert!((*a - *b).len() < 1e-9, "expected {:?}, found {:?}", b, a);
+    }
+
+    #[test]
+    fn interpolates_normals_and_uvs() {
+
+        // Hits where the barycentric weights of the corners are 0.25, 0.5 and 0.25
+        let mut mesh = smooth_triangle();
+        let inter = mesh.intersect(&Ray::new(Vector::new(0.5, 0.25, 5.0), Vector::new(0.0, 0.0, -10.0))).unwrap();
+        assert!((inter.t - 0.5).abs() < 1e-9);
+        assert!(!inter.inside);
+        assert_close(&inter.normal, &Vector::new(0.5, 0.25, 1.0));
+        assert!((inter.uv[0] - 1.0).abs() < 1e-9 && (inter.uv[1] - 1.0).abs() < 1e-9, "uv is {:?}", inter.uv);
+        assert_close(&inter.dpdu, &Vector::new(0.5, 0.0, 0.0));
+        assert_close(&inter.dpdv, &Vector::new(0.0, 0.25, 0.0));
+
+        // Moving the mesh moves the hit, but not what is interpolated
+        mesh.set_position(&Vector::new(1.0, 2.0, 3.0));
+        let inter = mesh.intersect(&Ray::new(Vector::new(1.5, 2.25, 8.0), Vector::new(0.0, 0.0, -10.0))).unwrap();
+        assert_close(&inter.position, &Vector::new(1.5, 2.25, 3.0));
+        assert_close(&inter.normal, &Vector::new(0.5, 0.25, 1.0));
+    }
+
+    #[test]
+    fn flips_normals_seen_from_behind() {
+        let mesh = smooth_triangle();
+        let inter = mesh.intersect(&Ray::new(Vector::new(0.5, 0.25, -5.0), Vector::new(0.0, 0.0, 10.0))).unwrap();
+        assert!(inter.inside);
+        assert_close(&inter.normal, &Vector::new(-0.5, -0.25, -1.0));
+        assert!(mesh.intersect(&Ray::new(Vector::new(-1.0, 0.25, 0.0), Vector::new(10.0, 0.0, 0.0))).is_none());
+    }
+}
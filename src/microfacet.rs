@@ -0,0 +1,223 @@
+use geom::{Vector, Basis};
+use sampling::cosine_hemisphere;
+use rand::Rng;
+use std::f64::consts::PI;
+
+/// Roughness below which surfaces are treated as this rough, as perfectly smooth facets would make the
+/// distribution infinitely sharp
+const MIN_ROUGHNESS: f64 = 0.02;
+
+/// Reflectance of non-metals when seen head-on
+const DIELECTRIC_REFLECTANCE: f64 = 0.04;
+
+/// Metallic/roughness surface made up of tiny mirror-like facets.
+/// Facets are oriented following the GGX distribution, hide each other following Smith's model and reflect following
+/// Schlick's approximation of the Fresnel equations.
+/// Non-metals reflect their base color diffusely beneath a clear specular layer, while metals tint their reflections
+/// with the base color instead.
+#[derive(Debug, Clone, Copy, PartialEq, new)]
+pub struct Microfacet {
+
+    /// 0 for non-metals and 1 for metals
+    pub metallic: f64,
+
+    /// 0 for polished surfaces, giving sharp reflections, up to 1 for rough ones
+    pub roughness: f64
+}
+
+impl Microfacet {
+
+    /// Width of the facet distribution
+    fn alpha(&self) -> f64 {
+        let roughness = self.roughness.max(MIN_ROUGHNESS);
+        roughness * roughness
+    }
+
+    /// Reflectance of a surface with base color 'color' when seen head-on
+    pub fn base_reflectance(&self, color: &Vector) -> Vector {
+        let r = DIELECTRIC_REFLECTANCE;
+        Vector::new(r, r, r).interp(color, self.metallic)
+    }
+
+    /// Schlick's approximation of the fraction of light reflected at 'cos' to the normal
+    pub fn fresnel(&self, color: &Vector, cos: f64) -> Vector {
+        let f0 = self.base_reflectance(color);
+        f0 + (Vector::new(1.0, 1.0, 1.0) - f0) * (1.0 - cos.max(0.0)).powi(5)
+    }
+
+    /// Fraction of light arriving from unit direction 'wi' that leaves towards unit direction 'wo', times the cosine of
+    /// 'wi' to the unit normal. Both directions point away from the surface.
+    pub fn eval(&self, normal: &Vector, wo: &Vector, wi: &Vector, color: &Vector) -> Vector {
+        let cos_i = normal.dot(wi);
+        let cos_o = normal.dot(wo);
+        if cos_i <= 0.0 || cos_o <= 0.0 { return Vector::new(0.0, 0.0, 0.0); }
+
+        let half = (*wi + *wo).to_unit();
+        let fresnel = self.fresnel(color, wo.dot(&half));
+        let specular = fresnel * (self.distribution(normal.dot(&half)) * self.geometry(cos_i, cos_o) / (4.0 * cos_i * cos_o));
+        let diffuse = (Vector::new(1.0, 1.0, 1.0) - fresnel) * *color * ((1.0 - self.metallic) / PI);
+        (diffuse + specular) * cos_i
+    }
+
+    /// Picks a direction for light to arrive from, favouring those reflecting the most light towards 'wo'.
+    /// Returns the direction along with eval divided by the chance of picking it, or None if it's below the surface.
+    pub fn sample<R: Rng>(&self, normal: &Vector, wo: &Vector, color: &Vector, rng: &mut R) -> Option<(Vector, Vector)> {
+        let wi = if rng.next_f64() < self.specular_chance() {
+
+            // Reflects off a facet picked in proportion to its area as seen from the normal
+            let a2 = self.alpha() * self.alpha();
+            let phi = 2.0 * PI * rng.next_f64();
+            let u = rng.next_f64();
+            let cos_theta = ((1.0 - u) / (1.0 + (a2 - 1.0) * u)).sqrt();
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let local = Vector::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+            let half = Basis::from_w(normal).to_world(&local);
+            -wo.reflect(&half)
+        }
+        else {
+            cosine_hemisphere(normal, rng)
+        };
+
+        let pdf = self.pdf(normal, wo, &wi);
+        if pdf <= 0.0 { return None; }
+        Some((wi, self.eval(normal, wo, &wi, color) / pdf))
+    }
+
+    /// Probability density of sample picking unit direction 'wi'
+    pub fn pdf(&self, normal: &Vector, wo: &Vector, wi: &Vector) -> f64 {
+        let cos_i = normal.dot(wi);
+        if cos_i <= 0.0 || normal.dot(wo) <= 0.0 { return 0.0; }
+        let half = (*wi + *wo).to_unit();
+        let cos_h = normal.dot(&half);
+        let specular = self.distribution(cos_h) * cos_h / (4.0 * wo.dot(&half).abs());
+        let diffuse = cos_i / PI;
+        let chance = self.specular_chance();
+        chance * specular + (1.0 - chance) * diffuse
+    }
+
+    /// Share of reflected light that a single mirror reflection stands in for, when looking along unit direction 'wo'.
+    /// Falls away as surfaces get rougher and their reflections blur.
+    pub fn mirror_reflectance(&self, normal: &Vector, wo: &Vector, color: &Vector) -> Vector {
+        let smoothness = 1.0 - self.roughness;
+        self.fresnel(color, normal.dot(wo)) * (smoothness * smoothness)
+    }
+
+    /// Share of light from all around reflected towards unit direction 'wo', other than mirror_reflectance.
+    /// Used to reflect ambient light.
+    pub fn ambient_reflectance(&self, normal: &Vector, wo: &Vector, color: &Vector) -> Vector {
+        let fresnel = self.fresnel(color, normal.dot(wo));
+        let smoothness = 1.0 - self.roughness;
+        let diffuse = (Vector::new(1.0, 1.0, 1.0) - fresnel) * *color * (1.0 - self.metallic);
+        diffuse + fresnel * (1.0 - smoothness * smoothness)
+    }
+
+    /// GGX density of facets whose normals are at 'cos_h' to the surface normal
+    fn distribution(&self, cos_h: f64) -> f64 {
+        let a2 = self.alpha() * self.alpha();
+        let d = cos_h * cos_h * (a2 - 1.0) + 1.0;
+        a2 / (PI * d * d)
+    }
+
+    /// Smith's fraction of facets that are neither hidden from the light nor from the viewer
+    fn geometry(&self, cos_i: f64, cos_o: f64) -> f64 {
+        let a2 = self.alpha() * self.alpha();
+        let g1 = |cos: f64| 2.0 * cos / (cos + (a2 + (1.0 - a2) * cos * cos).sqrt());
+        g1(cos_i) * g1(cos_o)
+    }
+
+    /// Chance of sample reflecting off a facet rather than picking a diffuse direction
+    fn specular_chance(&self) -> f64 {
+        0.5 + 0.5 * self.metallic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampling::pixel_rng;
+
+    fn viewed_at(degrees: f64) -> Vector {
+        Vector::new(degrees.to_radians().sin(), 0.0, degrees.to_radians().cos())
+    }
+
+    #[test]
+    fn samples_follow_their_pdf() {
+
+        // Averaging cos / pdf over sampled directions integrates the cosine over the hemisphere, which is pi, as long as
+        // pdf is the density sample picks from. The diffuse lobe makes sure every direction can be picked.
+        let normal = Vector::new(0.0, 0.0, 1.0);
+        let white = Vector::new(1.0, 1.0, 1.0);
+        let mut rng = pixel_rng(0, 0, 0);
+        for &(metallic, roughness) in &[(0.0, 0.3), (0.5, 0.5), (0.0, 1.0)] {
+            let surface = Microfacet::new(metallic, roughness);
+            for &degrees in &[0.0, 60.0] {
+                let wo = viewed_at(degrees);
+                let n = 100_000;
+                let mut total = 0.0;
+                for _ in 0..n {
+                    if let Some((wi, weight)) = surface.sample(&normal, &wo, &white, &mut rng) {
+                        let pdf = surface.pdf(&normal, &wo, &wi);
+                        let expected = surface.eval(&normal, &wo, &wi, &white) / pdf;
+                        assert!((weight - expected).len() < 1e-9);
+                        total += normal.dot(&wi) / pdf;
+                    }
+                }
+                let integral = total / n as f64;
+                assert!(
+                    (integral / PI - 1.0).abs() < 0.02,
+                    "metallic {} roughness {} at {} degrees integrates to {}", metallic, roughness, degrees, integral
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rough_surfaces_do_not_create_energy() {
+
+        // A white surface lit equally from all around reflects at most as much light as it receives
+        let normal = Vector::new(0.0, 0.0, 1.0);
+        let white = Vector::new(1.0, 1.0, 1.0);
+        let mut rng = pixel_rng(1, 0, 0);
+        for &metallic in &[0.0, 1.0] {
+            let surface = Microfacet::new(metallic, 1.0);
+            for &degrees in &[0.0, 45.0, 80.0, 89.0] {
+                let wo = viewed_at(degrees);
+                let n = 100_000;
+                let mut total = 0.0;
+                for _ in 0..n {
+                    if let Some((_, weight)) = surface.sample(&normal, &wo, &white, &mut rng) {
+                        total += weight.x;
+                    }
+                }
+                let reflected = total / n as f64;
+                assert!(
+                    reflected > 0.0 && reflected <= 1.0,
+                    "metallic {} at {} degrees reflects {}", metallic, degrees, reflected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn smooth_surfaces_stay_finite() {
+        let normal = Vector::new(0.0, 0.0, 1.0);
+        let white = Vector::new(1.0, 1.0, 1.0);
+        let wo = viewed_at(30.0);
+        let mirror = Vector::new(-wo.x, -wo.y, wo.z);
+        let mut rng = pixel_rng(2, 0, 0);
+        for &roughness in &[0.0, 1e-12, 1e-3] {
+            let surface = Microfacet::new(0.5, roughness);
+            for wi in &[mirror, viewed_at(-31.0), normal, viewed_at(60.0)] {
+                let eval = surface.eval(&normal, &wo, wi, &white);
+                let pdf = surface.pdf(&normal, &wo, wi);
+                assert!(eval.x.is_finite() && eval.x >= 0.0, "eval is {:?} at roughness {}", eval, roughness);
+                assert!(pdf.is_finite() && pdf > 0.0, "pdf is {} at roughness {}", pdf, roughness);
+            }
+            for _ in 0..1000 {
+                if let Some((_, weight)) = surface.sample(&normal, &wo, &white, &mut rng) {
+                    assert!(weight.x.is_finite(), "weight is {:?} at roughness {}", weight, roughness);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use geom::Vector;
+
+/// Perlin's gradient noise at 'point'.
+/// Varies smoothly between about -1 and 1, with features roughly a unit of distance apart, and is 0 at every point
+/// with integer coordinates. The same point always gives the same value.
+pub fn perlin(point: &Vector) -> f64 {
+    let (x0, y0, z0) = (point.x.floor(), point.y.floor(), point.z.floor());
+    let (x, y, z) = (point.x - x0, point.y - y0, point.z - z0);
+    let (ix, iy, iz) = (x0 as i64, y0 as i64, z0 as i64);
+
+    // Blends the gradients at the cell's eight corners
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        gradient(hash(ix + dx, iy + dy, iz + dz), x - dx as f64, y - dy as f64, z - dz as f64)
+    };
+    let lerp = |t: f64, a: f64, b: f64| a + t * (b - a);
+    lerp(w,
+        lerp(v, lerp(u, corner(0, 0, 0), corner(1, 0, 0)), lerp(u, corner(0, 1, 0), corner(1, 1, 0))),
+        lerp(v, lerp(u, corner(0, 0, 1), corner(1, 0, 1)), lerp(u, corner(0, 1, 1), corner(1, 1, 1))))
+}
+
+/// Fractal Brownian motion: 'octaves' layers of Perlin noise, each twice as fine and half as strong as the last.
+/// Stays between about -1 and 1.
+pub fn fbm(point: &Vector, octaves: u32) -> f64 {
+    layers(point, octaves, perlin)
+}
+
+/// Like fbm, but sums the absolute value of each layer, giving creases where the noise crosses 0.
+/// Stays between 0 and about 1.
+pub fn turbulence(point: &Vector, octaves: u32) -> f64 {
+    layers(point, octaves, |p| perlin(p).abs())
+}
+
+/// Sums 'octaves' layers of 'noise', scaled so that the weights add up to 1
+fn layers<F: Fn(&Vector) -> f64>(point: &Vector, octaves: u32, noise: F) -> f64 {
+    let mut total = 0.0;
+    let mut weight = 1.0;
+    let mut weights = 0.0;
+    let mut p = *point;
+    for _ in 0..octaves.max(1) {
+        total += weight * noise(&p);
+        weights += weight;
+        weight *= 0.5;
+        p = p * 2.0;
+    }
+    total / weights
+}
+
+/// Perlin's quintic curve, easing 't' from 0 to 1 with no jump in slope or curvature at either end
+fn fade(t: f64) -> f64 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+/// Dot product of (x, y, z) with one of the twelve edge directions of a cube, picked by 'hash'
+fn gradient(hash: u32, x: f64, y: f64, z: f64) -> f64 {
+    match hash % 12 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x + z,
+        5 => -x + z,
+        6 => x - z,
+        7 => -x - z,
+        8 => y + z,
+        9 => -y + z,
+        10 => y - z,
+        _ => -y - z
+    }
+}
+
+/// Scrambles the coordinates of a lattice point, standing in for Perlin's permutation table
+fn hash(x: i64, y: i64, z: i64) -> u32 {
+    let mut h = (x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        ^ (y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+        ^ (z as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+    h ^= h >> 29;
+    h = h.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h ^= h >> 32;
+    h as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampling::pixel_rng;
+    use rand::Rng;
+
+    fn random_points(count: usize) -> Vec<Vector> {
+        let mut rng = pixel_rng(0, 0, 0);
+        (0..count)
+            .map(|_| Vector::new(rng.gen_range(-50.0, 50.0), rng.gen_range(-50.0, 50.0), rng.gen_range(-50.0, 50.0)))
+            .collect()
+    }
+
+    #[test]
+    fn gives_the_same_value_for_the_same_point() {
+        let points = random_points(1000);
+        let first: Vec<f64> = points.iter().map(|p| fbm(p, 4)).collect();
+        let second: Vec<f64> = points.iter().map(|p| fbm(&Vector::new(p.x, p.y, p.z), 4)).collect();
+        assert_eq!(first, second);
+
+        // Values differ between points, so the noise isn't trivially constant
+        assert!(first.iter().any(|&n| (n - first[0]).abs() > 0.1));
+    }
+
+    #[test]
+    fn stays_within_its_range() {
+        for p in &random_points(100_000) {
+            assert!(perlin(p).abs() <= 1.1, "perlin({:?}) is {}", p, perlin(p));
+            assert!(fbm(p, 3).abs() <= 1.1, "fbm({:?}) is {}", p, fbm(p, 3));
+            let t = turbulence(p, 3);
+            assert!((0.0..=1.1).contains(&t), "turbulence({:?}) is {}", p, t);
+        }
+    }
+
+    #[test]
+    fn is_zero_on_lattice_points() {
+        for x in -3..4 {
+            for y in -3..4 {
+                for z in -3..4 {
+                    let p = Vector::new(x as f64, y as f64, z as f64);
+                    assert_eq!(perlin(&p), 0.0);
+
+                    // Finer octaves land on lattice points too
+                    assert_eq!(fbm(&p, 4), 0.0);
+                    assert_eq!(turbulence(&p, 4), 0.0);
+                }
+            }
+        }
+        assert!(perlin(&Vector::new(0.5, 0.25, 0.75)) != 0.0);
+    }
+}
@@ -0,0 +1,601 @@
+use geom::{Vector, clamp};
+use mesh::Mesh;
+use material::Material;
+use microfacet::Microfacet;
+use shape::Shape;
+use texture::{Texture, ImageTexture, TextureFilter, TextureError, Wrap};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::SplitWhitespace;
+use std::sync::Arc;
+
+/// Error raised while loading an OBJ or MTL file
+#[derive(Debug)]
+pub enum ObjError {
+
+    /// File could not be read
+    Io(PathBuf, io::Error),
+
+    /// File is malformed. 'line' starts at 1.
+    Parse { file: String, line: usize, message: String },
+
+    /// Texture map referenced by an MTL file could not be loaded
+    Texture(TextureError)
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjError::Io(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            ObjError::Parse { ref file, line, ref message } => write!(f, "{}:{}: {}", file, line, message),
+            ObjError::Texture(ref err) => write!(f, "{}", err)
+        }
+    }
+}
+
+impl Error for ObjError {}
+
+/// Material as described by an MTL file
+#[derive(Debug, Clone)]
+pub struct ObjMaterial {
+
+    /// Kd
+    pub diffuse: Vector,
+
+    /// Ks
+    pub specular: Vector,
+
+    /// Ke
+    pub emissive: Vector,
+
+    /// Ns
+    pub shininess: f64,
+
+    /// d, or 1 - Tr. 1 is fully opaque.
+    pub dissolve: f64,
+
+    /// Ni, the index of refraction
+    pub optical_density: f64,
+
+    /// Pm, from the PBR extension
+    pub metallic: Option<f64>,
+
+    /// Pr, from the PBR extension
+    pub roughness: Option<f64>,
+
+    /// map_Kd, an image giving Kd over the surface
+    pub diffuse_map: Option<PathBuf>,
+
+    /// map_Ks
+    pub specular_map: Option<PathBuf>,
+
+    /// map_Ns
+    pub shininess_map: Option<PathBuf>,
+
+    /// bump or map_Bump, an image of heights
+    pub bump_map: Option<PathBuf>,
+
+    /// -bm option of the bump map, scaling its heights
+    pub bump_multiplier: f64,
+
+    /// norm, from the PBR extension, a tangent space normal map
+    pub normal_map: Option<PathBuf>
+}
+
+impl Default for ObjMaterial {
+    fn default() -> ObjMaterial {
+        ObjMaterial {
+            diffuse: Vector::new(0.8, 0.8, 0.8),
+            specular: Vector::new(0.0, 0.0, 0.0),
+            emissive: Vector::new(0.0, 0.0, 0.0),
+            shininess: 10.0,
+            dissolve: 1.0,
+            optical_density: 1.0,
+            metallic: None,
+            roughness: None,
+            diffuse_map: None,
+            specular_map: None,
+            shininess_map: None,
+            bump_map: None,
+            bump_multiplier: 1.0,
+            normal_map: None
+        }
+    }
+}
+
+impl ObjMaterial {
+
+    /// Ks collapsed into the single reflectivity value used by materials
+    pub fn reflectivity(&self) -> f64 {
+        let s = self.specular.clamp();
+        (s.x + s.y + s.z) / 3.0
+    }
+
+    /// Maps Kd onto color, Ks onto reflectivity, Ns onto exponent, d onto transparency, Ni onto ior and Ke onto emission.
+    /// Exporters often write Ni 0 for opaque materials, which is read as 1.
+    /// If either Pm or Pr is given, the material uses the microfacet model instead of Ks and Ns.
+    /// Texture maps are loaded onto the material's textures, with map_Kd taken to hold sRGB colors.
+    /// Bump map heights are taken to be in units of distance, scaled by the -bm option.
+    pub fn to_material(&self) -> Result<Material, ObjError> {
+        let microfacet = match (self.metallic, self.roughness) {
+            (None, None) => None,
+            (metallic, roughness) => Some(Microfacet::new(
+                clamp(metallic.unwrap_or(0.0)),
+                clamp(roughness.unwrap_or(0.5))
+            ))
+        };
+        let load = |map: &Option<PathBuf>, srgb: bool| -> Result<Option<Texture>, ObjError> {
+            match *map {
+                Some(ref path) => ImageTexture::load(path, TextureFilter::Bilinear, Wrap::Repeat, srgb)
+                    .map(|image| Some(Texture::Image(image)))
+                    .map_err(ObjError::Texture),
+                None => Ok(None)
+            }
+        };
+        Ok(Material {
+            transparency: 1.0 - clamp(self.dissolve),
+            ior: if self.optical_density > 0.0 { self.optical_density } else { 1.0 },
+            microfacet,
+            emission: self.emissive,
+            texture: load(&self.diffuse_map, true)?,
+            reflectivity_texture: load(&self.specular_map, false)?,
+            exponent_texture: load(&self.shininess_map, false)?,
+            normal_map: load(&self.normal_map, false)?,
+            bump_map: load(&self.bump_map, false)?,
+            bump_height: self.bump_multiplier,
+            ..Material::new(self.diffuse, self.reflectivity(), self.shininess)
+        })
+    }
+}
+
+/// Mesh for one group or object of an OBJ file.
+/// A group that uses several materials is split into one ObjObject per material.
+/// Meshes that use the same MTL material share one Material. 'material' is the name given to 'usemtl', even if no MTL
+/// file defined it, in which case the mesh has the default material.
+#[derive(Debug)]
+pub struct ObjObject {
+    pub name: String,
+    pub material: Option<String>,
+    pub mesh: Mesh
+}
+
+/// Contents of an OBJ file
+#[derive(Debug)]
+pub struct ObjFile {
+    pub objects: Vec<ObjObject>,
+
+    /// Problems that loading carried on past, such as 'usemtl' naming a material no MTL file defined, for the caller
+    /// to report or ignore. Each starts with the file and line it was found on.
+    pub warnings: Vec<String>
+}
+
+/// Loads an OBJ file, along with any MTL files it references relative to its directory.
+pub fn load_obj<P: AsRef<Path>>(path: P) -> Result<ObjFile, ObjError> {
+    let path = path.as_ref();
+    let source = read(path)?;
+    let dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    parse_obj(&source, &path.display().to_string(), |mtl| load_mtl(dir.join(mtl)))
+}
+
+/// Loads an OBJ file as shapes ready to be added to a scene, ignoring any warnings.
+pub fn load_shapes<P: AsRef<Path>>(path: P) -> Result<Vec<Box<dyn Shape>>, ObjError> {
+    let file = load_obj(path)?;
+    Ok(file.objects
+        .into_iter()
+        .map(|o| Box::new(o.mesh) as Box<dyn Shape>)
+        .collect())
+}
+
+/// Loads the materials in an MTL file, keyed by name.
+/// Texture maps are relative to the file's directory.
+pub fn load_mtl<P: AsRef<Path>>(path: P) -> Result<HashMap<String, ObjMaterial>, ObjError> {
+    let path = path.as_ref();
+    let source = read(path)?;
+    let dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    let mut materials = parse_mtl(&source, &path.display().to_string())?;
+    for mat in materials.values_mut() {
+        let maps = [&mut mat.diffuse_map, &mut mat.specular_map, &mut mat.shininess_map, &mut mat.bump_map, &mut mat.normal_map];
+        for map in maps {
+            if let Some(ref mut map) = *map {
+                *map = dir.join(&*map);
+            }
+        }
+    }
+    Ok(materials)
+}
+
+/// Parses the contents of an OBJ file.
+/// 'file' names the source in errors. 'load_mtl' is invoked with the name given to each 'mtllib' statement.
+/// Polygons are triangulated as fans.
+pub fn parse_obj<F>(source: &str, file: &str, mut load_mtl: F) -> Result<ObjFile, ObjError>
+where F: FnMut(&str) -> Result<HashMap<String, ObjMaterial>, ObjError> {
+
+    let mut positions: Vec<Vector> = Vec::new();
+    let mut normals: Vec<Vector> = Vec::new();
+    let mut uvs: Vec<[f64; 2]> = Vec::new();
+    let mut materials: HashMap<String, ObjMaterial> = HashMap::new();
+
+    // Meshes under construction, keyed by group and material
+    let mut builders: Vec<MeshBuilder> = Vec::new();
+    let mut builder_indices: HashMap<(String, Option<String>), usize> = HashMap::new();
+    let mut group = String::from("default");
+    let mut material: Option<String> = None;
+    let mut warnings = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let mut parser = LineParser::new(file, i + 1, line);
+        let keyword = match parser.next_token() {
+            Some(keyword) => keyword,
+            None => continue
+        };
+        match keyword {
+            "v" => positions.push(parser.vector()?),
+            "vn" => normals.push(parser.vector()?),
+            "vt" => {
+                let u = parser.float()?;
+                let v = parser.optional_float()?.unwrap_or(0.0);
+                uvs.push([u, v]);
+            },
+            "f" => {
+                let mut corners = Vec::new();
+                while let Some(token) = parser.next_token() {
+                    corners.push(parser.face_vertex(token, positions.len(), uvs.len(), normals.len())?);
+                }
+                if corners.len() < 3 {
+                    return Err(parser.error(format!("face needs at least 3 vertices, found {}", corners.len())));
+                }
+                let key = (group.clone(), material.clone());
+                let index = *builder_indices.entry(key).or_insert_with(|| {
+                    builders.push(MeshBuilder::new(group.clone(), material.clone()));
+                    builders.len() - 1
+                });
+                builders[index].add_polygon(&corners);
+            },
+            "o" | "g" => {
+                let name: Vec<&str> = parser.rest().collect();
+                group = if name.is_empty() { String::from("default") } else { name.join(" ") };
+            },
+            "usemtl" => {
+                // Like other loaders, carries on with the default material rather than failing over a missing name
+                let name = parser.name()?;
+                if !materials.contains_key(&name) {
+                    warnings.push(format!("{}:{}: undefined material '{}', using the default", file, i + 1, name));
+                }
+                material = Some(name);
+            },
+            "mtllib" => {
+                for name in parser.rest() {
+                    materials.extend(load_mtl(name)?);
+                }
+            },
+
+            // Smoothing groups, lines, points and free-form geometry aren't supported
+            _ => {}
+        }
+    }
+
+    // Converts each MTL material once, so that meshes share them
+    let materials: HashMap<String, Arc<Material>> = materials
+        .iter()
+        .map(|(name, mat)| Ok((name.clone(), Arc::new(mat.to_material()?))))
+        .collect::<Result<_, ObjError>>()?;
+    let default_material = Arc::new(ObjMaterial::default().to_material()?);
+
+    let objects = builders
+        .into_iter()
+        .map(|b| b.build(&positions, &normals, &uvs, &materials, &default_material))
+        .collect();
+    Ok(ObjFile { objects, warnings })
+}
+
+/// Parses the contents of an MTL file.
+/// 'file' names the source in errors.
+pub fn parse_mtl(source: &str, file: &str) -> Result<HashMap<String, ObjMaterial>, ObjError> {
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, ObjMaterial)> = None;
+    for (i, line) in source.lines().enumerate() {
+        let mut parser = LineParser::new(file, i + 1, line);
+        let keyword = match parser.next_token() {
+            Some(keyword) => keyword,
+            None => continue
+        };
+        if keyword == "newmtl" {
+            if let Some((name, mat)) = current.take() {
+                materials.insert(name, mat);
+            }
+            current = Some((parser.name()?, ObjMaterial::default()));
+            continue;
+        }
+        let mat = match current {
+            Some((_, ref mut mat)) => mat,
+            None => return Err(parser.error(format!("'{}' before any 'newmtl'", keyword)))
+        };
+        match keyword {
+            "Kd" => mat.diffuse = parser.vector()?,
+            "Ks" => mat.specular = parser.vector()?,
+            "Ke" => mat.emissive = parser.vector()?,
+            "Ns" => mat.shininess = parser.float()?,
+            "d" => mat.dissolve = parser.float()?,
+            "Tr" => mat.dissolve = 1.0 - parser.float()?,
+            "Ni" => mat.optical_density = parser.float()?,
+            "Pm" => mat.metallic = Some(parser.float()?),
+            "Pr" => mat.roughness = Some(parser.float()?),
+            "map_Kd" => mat.diffuse_map = Some(parser.map_path()?),
+            "map_Ks" => mat.specular_map = Some(parser.map_path()?),
+            "map_Ns" => mat.shininess_map = Some(parser.map_path()?),
+            "bump" | "map_Bump" | "map_bump" => {
+                mat.bump_map = Some(parser.map_path()?);
+                mat.bump_multiplier = parser.map_option("-bm")?.unwrap_or(1.0);
+            },
+            "norm" => mat.normal_map = Some(parser.map_path()?),
+
+            // Ambient, transmission filters, illumination models and other texture maps aren't supported
+            _ => {}
+        }
+    }
+    if let Some((name, mat)) = current.take() {
+        materials.insert(name, mat);
+    }
+    Ok(materials)
+}
+
+fn read(path: &Path) -> Result<String, ObjError> {
+    fs::read_to_string(path).map_err(|e| ObjError::Io(path.to_path_buf(), e))
+}
+
+/// Indices of a face vertex into the position, uv and normal lists
+type Corner = (usize, Option<usize>, Option<usize>);
+
+/// Accumulates triangles for one mesh, creating a vertex for each distinct combination of position, uv and normal.
+struct MeshBuilder {
+    name: String,
+    material: Option<String>,
+    corners: Vec<Corner>,
+    vertex_indices: HashMap<Corner, usize>,
+    triangles: Vec<[usize; 3]>
+}
+
+impl MeshBuilder {
+
+    fn new(name: String, material: Option<String>) -> MeshBuilder {
+        MeshBuilder {
+            name,
+            material,
+            corners: Vec::new(),
+            vertex_indices: HashMap::new(),
+            triangles: Vec::new()
+        }
+    }
+
+    fn vertex(&mut self, corner: Corner) -> usize {
+        let corners = &mut self.corners;
+        *self.vertex_indices.entry(corner).or_insert_with(|| {
+            corners.push(corner);
+            corners.len() - 1
+        })
+    }
+
+    fn add_polygon(&mut self, corners: &[Corner]) {
+        let first = self.vertex(corners[0]);
+        for pair in corners[1..].windows(2) {
+            let b = self.vertex(pair[0]);
+            let c = self.vertex(pair[1]);
+            self.triangles.push([first, b, c]);
+        }
+    }
+
+    /// Creates mesh. Normals and uvs are only kept if every vertex has them.
+    fn build(
+        self,
+        positions: &[Vector],
+        normals: &[Vector],
+        uvs: &[[f64; 2]],
+        materials: &HashMap<String, Arc<Material>>,
+        default_material: &Arc<Material>
+    ) -> ObjObject {
+        let mesh_positions = self.corners.iter().map(|c| positions[c.0]).collect();
+        let mesh_uvs = self.corners.iter().map(|c| c.1.map(|i| uvs[i])).collect();
+        let mesh_normals = self.corners.iter().map(|c| c.2.map(|i| normals[i])).collect();
+        let material = self.material
+            .as_ref()
+            .and_then(|name| materials.get(name))
+            .unwrap_or(default_material)
+            .clone();
+        let mesh = Mesh::new(
+            mesh_positions,
+            mesh_normals,
+            mesh_uvs,
+            self.triangles,
+            material
+        );
+        ObjObject { name: self.name, material: self.material, mesh }
+    }
+}
+
+/// Splits a line into whitespace-separated tokens, ignoring comments, and reports errors against its line number.
+struct LineParser<'a> {
+    file: &'a str,
+    line: usize,
+    tokens: SplitWhitespace<'a>
+}
+
+impl<'a> LineParser<'a> {
+
+    fn new(file: &'a str, line: usize, text: &'a str) -> LineParser<'a> {
+        let text = match text.find('#') {
+            Some(i) => &text[..i],
+            None => text
+        };
+        LineParser { file, line, tokens: text.split_whitespace() }
+    }
+
+    fn error(&self, message: String) -> ObjError {
+        ObjError::Parse { file: self.file.to_string(), line: self.line, message }
+    }
+
+    fn next_token(&mut self) -> Option<&'a str> {
+        self.tokens.next()
+    }
+
+    fn rest(&mut self) -> SplitWhitespace<'a> {
+        self.tokens.clone()
+    }
+
+    fn name(&mut self) -> Result<String, ObjError> {
+        let name: Vec<&str> = self.rest().collect();
+        if name.is_empty() { return Err(self.error(String::from("expected a name"))); }
+        Ok(name.join(" "))
+    }
+
+    /// File name of a texture map, the last token on the line. Options before it are ignored.
+    fn map_path(&mut self) -> Result<PathBuf, ObjError> {
+        match self.rest().last() {
+            Some(name) => Ok(PathBuf::from(name)),
+            None => Err(self.error(String::from("expected a file name")))
+        }
+    }
+
+    /// Value following 'option' among the options of a texture map, if given
+    fn map_option(&mut self, option: &str) -> Result<Option<f64>, ObjError> {
+        let mut tokens = self.rest();
+        while let Some(token) = tokens.next() {
+            if token != option { continue; }
+            return match tokens.next().map(str::parse) {
+                Some(Ok(value)) => Ok(Some(value)),
+                _ => Err(self.error(format!("expected a number after {}", option)))
+            };
+        }
+        Ok(None)
+    }
+
+    fn optional_float(&mut self) -> Result<Option<f64>, ObjError> {
+        match self.next_token() {
+            Some(token) => token
+                .parse()
+                .map(Some)
+                .map_err(|_| self.error(format!("expected a number, found '{}'", token))),
+            None => Ok(None)
+        }
+    }
+
+    fn float(&mut self) -> Result<f64, ObjError> {
+        match self.optional_float()? {
+            Some(value) => Ok(value),
+            None => Err(self.error(String::from("expected a number, found end of line")))
+        }
+    }
+
+    fn vector(&mut self) -> Result<Vector, ObjError> {
+        Ok(Vector::new(self.float()?, self.float()?, self.float()?))
+    }
+
+    /// Parses a face vertex of the form v, v/vt, v//vn or v/vt/vn
+    fn face_vertex(&self, token: &str, num_positions: usize, num_uvs: usize, num_normals: usize) -> Result<Corner, ObjError> {
+        let mut parts = token.split('/');
+        let position = match parts.next() {
+            Some(p) if !p.is_empty() => self.index(p, num_positions, "vertex")?,
+            _ => return Err(self.error(format!("malformed face vertex '{}'", token)))
+        };
+        let uv = match parts.next() {
+            Some(p) if !p.is_empty() => Some(self.index(p, num_uvs, "texture coordinate")?),
+            _ => None
+        };
+        let normal = match parts.next() {
+            Some(p) if !p.is_empty() => Some(self.index(p, num_normals, "normal")?),
+            _ => None
+        };
+        if parts.next().is_some() {
+            return Err(self.error(format!("malformed face vertex '{}'", token)));
+        }
+        Ok((position, uv, normal))
+    }
+
+    /// Resolves a 1-based or negative (relative to the end) index into a list of 'len' elements
+    fn index(&self, token: &str, len: usize, what: &str) -> Result<usize, ObjError> {
+        let index: i64 = token
+            .parse()
+            .map_err(|_| self.error(format!("expected a {} index, found '{}'", what, token)))?;
+        let resolved = if index > 0 { index - 1 } else { len as i64 + index };
+        if index == 0 || resolved < 0 || resolved >= len as i64 {
+            return Err(self.error(format!("{} index {} out of range, {} defined so far", what, index, len)));
+        }
+        Ok(resolved as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Vec<ObjObject>, ObjError> {
+        parse_obj(source, "test.obj", |name| parse_mtl("newmtl red\nKd 1 0 0\n", name)).map(|file| file.objects)
+    }
+
+    #[test]
+    fn resolves_negative_indices() {
+        let objects = parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf -3 -2 -1\n").unwrap();
+        let mesh = &objects[0].mesh;
+        assert_eq!(mesh.triangles(), &[[0, 1, 2]]);
+        assert_eq!(mesh.positions()[1].x, 1.0);
+        assert!(parse("v 0 0 0\nf -1 -2 -3\n").is_err());
+        assert!(parse("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n").is_err());
+    }
+
+    #[test]
+    fn reads_every_face_vertex_form() {
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nvn 0 0 1\n";
+        let mesh = |faces: &str| parse(&format!("{}{}", source, faces)).unwrap().remove(0).mesh;
+
+        let plain = mesh("f 1 2 3\n");
+        assert!(plain.uvs().is_none() && plain.normals().is_none());
+
+        let uv = mesh("f 1/1 2/2 3/3\n");
+        assert_eq!(uv.uvs().unwrap()[1], [1.0, 0.0]);
+        assert!(uv.normals().is_none());
+
+        let normal = mesh("f 1//1 2//1 3//1\n");
+        assert!(normal.uvs().is_none());
+        assert_eq!(normal.normals().unwrap()[2].z, 1.0);
+
+        let both = mesh("f 1/1/1 2/2/1 3/3/1\n");
+        assert_eq!(both.uvs().unwrap()[2], [0.0, 1.0]);
+        assert_eq!(both.normals().unwrap()[0].z, 1.0);
+
+        assert!(parse(&format!("{}f 1/1/1/1 2 3\n", source)).is_err());
+    }
+
+    #[test]
+    fn triangulates_polygons_as_fans() {
+        let objects = parse("v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv -1 1 0\nf 1 2 3 4 5\n").unwrap();
+        assert_eq!(objects[0].mesh.triangles(), &[[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+        assert!(parse("v 0 0 0\nv 1 0 0\nf 1 2\n").is_err());
+    }
+
+    #[test]
+    fn splits_groups_by_material() {
+        let source = "mtllib test.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\ng a\nusemtl red\nf 1 2 3\nusemtl missing\nf 1 2 3\n";
+        let objects = parse(source).unwrap();
+        assert_eq!(objects.len(), 2);
+        assert_eq!(objects[0].material.as_ref().unwrap(), "red");
+        assert_eq!(objects[0].mesh.material.color.x, 1.0);
+        assert_eq!(objects[0].mesh.material.color.y, 0.0);
+
+        // Unknown materials fall back to the default
+        assert_eq!(objects[1].mesh.material.color.x, ObjMaterial::default().diffuse.x);
+    }
+
+    #[test]
+    fn warns_of_undefined_materials() {
+        let source = "mtllib test.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl red\nf 1 2 3\nusemtl missing\nf 1 2 3\n";
+        let file = parse_obj(source, "test.obj", |name| parse_mtl("newmtl red\nKd 1 0 0\n", name)).unwrap();
+        assert_eq!(file.warnings, vec!["test.obj:7: undefined material 'missing', using the default"]);
+        assert_eq!(file.objects[1].material.as_ref().unwrap(), "missing");
+
+        let file = parse_obj("v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n", "test.obj", |_| Ok(HashMap::new())).unwrap();
+        assert!(file.warnings.is_empty());
+    }
+}
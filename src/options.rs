@@ -0,0 +1,215 @@
+use std::ops::Range;
+use std::path::PathBuf;
+use std::str::FromStr;
+use ray_tracer::sampling::{SamplePattern, Filter, Adaptive};
+use ray_tracer::integrator::Integrator;
+use ray_tracer::exr::{PixelType, Compression};
+
+pub const USAGE: &str = "\
+Usage: ray_tracer [OPTIONS] [SCENE]
+
+Renders SCENE, a TOML scene file, to a sequence of images, PNG unless the pattern names another format.
+Without a scene file, renders the built-in animated demo.
+
+Options:
+  -W, --width <PIXELS>         Image width [default: 1920]
+  -H, --height <PIXELS>        Image height [default: 1080]
+  -f, --frames <START>..<END>  Frames to render, END excluded. A single number renders one frame.
+                               [default: 0..320 for the demo, 0..1 for a scene file]
+  -o, --output-dir <DIR>       Directory images are written to [default: images]
+  -p, --pattern <PATTERN>      Image file name, where {frame} is replaced by the
+                               zero-padded frame number. Names ending in .hdr or .pfm save unclamped
+                               linear colors, and .exr adds depth (Z), normal and albedo channels for
+                               compositing [default: frame_{frame}.png]
+  -s, --seed <N,...>           Comma-separated seed for the demo's random scene, sample placement and paths
+                               [default: 1,2,3,4]
+  -t, --threads <N>            Number of render threads [default: number of cores]
+  -b, --bounce-limit <N>       Overrides the scene's bounce limit
+  -i, --integrator <NAME>      whitted, for ray tracing with ambient light, or path, for path tracing, which
+                               needs many samples per pixel [default: whitted]
+  -a, --samples <N>            Samples per pixel [default: 1]
+      --sampling <PATTERN>     Sample placement: grid, jittered or rotated_grid [default: grid]
+      --filter <FILTER>        Filter combining samples: box, tent, gaussian or mitchell [default: box]
+      --adaptive <MIN>..<MAX>  Starts each pixel with MIN samples, adding more where the pixel is noisy, up to
+                               MAX. Replaces --samples.
+      --threshold <ERROR>      Noise level above which adaptive sampling adds samples, as the standard error
+                               of a pixel's brightness between 0 and 1 [default: 0.01]
+      --exr-pixels <TYPE>      Pixel type of OpenEXR color, normal and albedo channels: half or float. Depth
+                               is always float [default: half]
+      --exr-compression <NAME> Compression of OpenEXR images: none or zip [default: zip]
+      --heatmap                Also writes an image of the samples spent per pixel, named heatmap_<PATTERN>
+  -h, --help                   Prints this message";
+
+/// Command-line options
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub scene: Option<PathBuf>,
+    pub width: i32,
+    pub height: i32,
+    pub frames: Option<Range<u32>>,
+    pub output_dir: PathBuf,
+    pub pattern: String,
+    pub seed: Vec<usize>,
+    pub threads: Option<usize>,
+    pub bounce_limit: Option<u32>,
+    pub integrator: Integrator,
+    pub samples: u32,
+    pub sampling: SamplePattern,
+    pub filter: Filter,
+    pub adaptive: Option<Range<u32>>,
+    pub threshold: f64,
+    pub heatmap: bool,
+    pub exr_pixels: PixelType,
+    pub exr_compression: Compression
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            scene: None,
+            width: 1920,
+            height: 1080,
+            frames: None,
+            output_dir: PathBuf::from("images"),
+            pattern: String::from("frame_{frame}.png"),
+            seed: vec![1, 2, 3, 4],
+            threads: None,
+            bounce_limit: None,
+            integrator: Integrator::Whitted,
+            samples: 1,
+            sampling: SamplePattern::Grid,
+            filter: Filter::Box,
+            adaptive: None,
+            threshold: 0.01,
+            heatmap: false,
+            exr_pixels: PixelType::Half,
+            exr_compression: Compression::Zip
+        }
+    }
+}
+
+impl Options {
+
+    /// Parses arguments, not including the program name.
+    /// Returns None if help was requested, and a message describing the problem on bad input.
+    pub fn parse(args: &[String]) -> Result<Option<Options>, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| -> Result<&String, String> {
+                args.next().ok_or_else(|| format!("{} needs a value", name))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-W" | "--width" => options.width = positive(arg, value(arg)?)?,
+                "-H" | "--height" => options.height = positive(arg, value(arg)?)?,
+                "-f" | "--frames" => options.frames = Some(frames(arg, value(arg)?)?),
+                "-o" | "--output-dir" => options.output_dir = PathBuf::from(value(arg)?),
+                "-p" | "--pattern" => {
+                    let pattern = value(arg)?;
+                    if !pattern.contains("{frame}") {
+                        return Err(format!("{} must contain {{frame}}", arg));
+                    }
+                    options.pattern = pattern.clone();
+                },
+                "-s" | "--seed" => {
+                    let seed: Result<Vec<usize>, String> = value(arg)?
+                        .split(',')
+                        .map(|n| number(arg, n.trim()))
+                        .collect();
+                    options.seed = seed?;
+                },
+                "-t" | "--threads" => options.threads = Some(positive(arg, value(arg)?)?),
+                "-b" | "--bounce-limit" => options.bounce_limit = Some(number(arg, value(arg)?)?),
+                "-i" | "--integrator" => options.integrator = value(arg)?.parse()?,
+                "-a" | "--samples" => options.samples = positive(arg, value(arg)?)?,
+                "--sampling" => options.sampling = value(arg)?.parse()?,
+                "--filter" => options.filter = value(arg)?.parse()?,
+                "--adaptive" => {
+                    let samples = value(arg)?;
+                    let range = match samples.find("..") {
+                        Some(i) => positive(arg, &samples[..i])?..positive(arg, &samples[i+2..])?,
+                        None => return Err(format!("{} expects <MIN>..<MAX>, found '{}'", arg, samples))
+                    };
+                    if range.start > range.end {
+                        return Err(format!("{} must have MIN no greater than MAX, found '{}'", arg, samples));
+                    }
+                    options.adaptive = Some(range);
+                },
+                "--threshold" => {
+                    let threshold = value(arg)?;
+                    options.threshold = threshold.parse()
+                        .ok()
+                        .filter(|t: &f64| *t >= 0.0)
+                        .ok_or_else(|| format!("{} expects a number no less than 0, found '{}'", arg, threshold))?;
+                },
+                "--heatmap" => options.heatmap = true,
+                "--exr-pixels" => options.exr_pixels = value(arg)?.parse()?,
+                "--exr-compression" => options.exr_compression = value(arg)?.parse()?,
+                _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+                _ => {
+                    if options.scene.is_some() {
+                        return Err(format!("unexpected argument '{}', only one scene can be rendered", arg));
+                    }
+                    options.scene = Some(PathBuf::from(arg));
+                }
+            }
+        }
+        Ok(Some(options))
+    }
+
+    /// Adaptive sampling settings, if requested
+    pub fn adaptive(&self) -> Option<Adaptive> {
+        self.adaptive.as_ref().map(|range| Adaptive::new(range.start, range.end, self.threshold))
+    }
+
+    /// Seed for sample placement and paths, folded from the demo's seed
+    pub fn sample_seed(&self) -> u32 {
+        self.seed.iter().fold(0u32, |h, &n| h.wrapping_mul(31).wrapping_add(n as u32))
+    }
+
+    /// Frames to render, falling back to 0..default_end if none were given
+    pub fn frames(&self, default_end: u32) -> Range<u32> {
+        self.frames.clone().unwrap_or(0..default_end)
+    }
+}
+
+fn number<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} expects a whole number, found '{}'", name, value))
+}
+
+fn positive<T: FromStr + Default + PartialOrd>(name: &str, value: &str) -> Result<T, String> {
+    let n: T = number(name, value)?;
+    if n <= T::default() { return Err(format!("{} must be greater than 0, found '{}'", name, value)); }
+    Ok(n)
+}
+
+/// Parses START..END or a single frame number
+fn frames(name: &str, value: &str) -> Result<Range<u32>, String> {
+    let range = match value.find("..") {
+        Some(i) => number(name, &value[..i])?..number(name, &value[i+2..])?,
+        None => {
+            let frame: u32 = number(name, value)?;
+            let end = frame.checked_add(1)
+                .ok_or_else(|| format!("{} must be less than {}, found '{}'", name, u32::MAX, value))?;
+            frame..end
+        }
+    };
+    if range.start >= range.end {
+        return Err(format!("{} must contain at least one frame, found '{}'", name, value));
+    }
+    Ok(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_frames() {
+        assert_eq!(frames("-f", "3..7"), Ok(3..7));
+        assert_eq!(frames("-f", "5"), Ok(5..6));
+        assert!(frames("-f", "7..3").is_err());
+        assert!(frames("-f", "4294967295").is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use geom::{Vector, Plane};
+use scene::{Scene, vector_to_color};
+use framebuffer::Framebuffer;
+use raster::Image;
+use sampling::{SamplePattern, Filter, Adaptive, pixel_rng};
+use integrator::Integrator;
+use rand::Rng;
+use std::thread;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Rectangular region of the image, in bottom-to-top pixel coordinates
+#[derive(Copy, Clone, Debug, new)]
+pub struct Tile {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32
+}
+
+/// Extra images describing the first surface seen through the center of each pixel, for compositing alongside the
+/// rendered colors. Rows run from the top, like the framebuffer rendered into.
+#[derive(Debug, Clone)]
+pub struct Passes {
+
+    /// Distance from the eye to the surface, in every component, and infinite where nothing is hit
+    pub depth: Framebuffer,
+
+    /// Unit shading normal in world space, facing the camera, and zero where nothing is hit
+    pub normal: Framebuffer,
+
+    /// Color of the surface before it is lit, including its texture, and black where nothing is hit
+    pub albedo: Framebuffer
+}
+
+/// Renders a scene by splitting the image into tiles that are traced in parallel by a pool of worker threads.
+/// Each pixel is traced with 'samples' rays placed according to 'pattern', which are combined using 'filter'.
+/// The light along each ray is found by 'integrator'.
+/// With the Whitted integrator and one sample on a grid with a box filter, every pixel is traced exactly as
+/// Scene::render would, so the output is identical to the single-threaded path.
+/// If 'adaptive' is set, it decides how many samples each pixel gets instead of 'samples'.
+/// Random sample placement and paths depend only on 'seed' and the pixel, so output doesn't depend on the number
+/// of threads.
+#[derive(Copy, Clone, Debug)]
+pub struct Renderer {
+    pub threads: usize,
+    pub tile_size: i32,
+    pub integrator: Integrator,
+    pub samples: u32,
+    pub pattern: SamplePattern,
+    pub filter: Filter,
+    pub adaptive: Option<Adaptive>,
+    pub seed: u32
+}
+
+impl Default for Renderer {
+    fn default() -> Renderer {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        Renderer {
+            threads,
+            tile_size: 32,
+            integrator: Integrator::Whitted,
+            samples: 1,
+            pattern: SamplePattern::Grid,
+            filter: Filter::Box,
+            adaptive: None,
+            seed: 0
+        }
+    }
+}
+
+impl Renderer {
+
+    /// Splits an image into tiles of at most tile_size x tile_size pixels.
+    pub fn tiles(&self, width: i32, height: i32) -> Vec<Tile> {
+        let size = self.tile_size.max(1);
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(size as usize) {
+            for x in (0..width).step_by(size as usize) {
+                let tile_width = size.min(width - x);
+                let tile_height = size.min(height - y);
+                tiles.push(Tile::new(x, y, tile_width, tile_height));
+            }
+        }
+        tiles
+    }
+
+    /// Renders the scene into 'image'.
+    /// Returns the number of samples traced for each pixel, row by row from the top like the image.
+    pub fn render(&self, scene: &Scene, image: &mut Image) -> Vec<u32> {
+        let mut framebuffer = Framebuffer::new(image.width as usize, image.height as usize);
+        let counts = self.render_framebuffer(scene, &mut framebuffer);
+        for (i, color) in framebuffer.pixels.iter().enumerate() {
+            let i = i as i32;
+            image.set_pixel(i % image.width, i / image.width, vector_to_color(color)).unwrap();
+        }
+        counts
+    }
+
+    /// Renders the scene into 'framebuffer', keeping colors brighter than white for saving as HDR images.
+    /// Returns the number of samples traced for each pixel, row by row from the top like the framebuffer.
+    pub fn render_framebuffer(&self, scene: &Scene, framebuffer: &mut Framebuffer) -> Vec<u32> {
+
+        // Gets image size
+        let width = framebuffer.width as i32;
+        let height = framebuffer.height as i32;
+
+        // Gets plane at which to trace rays
+        let plane = scene.camera.near_plane();
+
+        let tiles = self.tiles(width, height);
+        let next_tile = AtomicUsize::new(0);
+        let threads = self.threads.max(1).min(tiles.len().max(1));
+        let (sender, receiver) = mpsc::channel::<(Tile, Vec<(Vector, u32)>)>();
+        let mut counts = vec![0; framebuffer.pixels.len()];
+
+        thread::scope(|scope| {
+
+            // Workers take the next untraced tile until none are left
+            for _ in 0..threads {
+                let sender = sender.clone();
+                let renderer = &self;
+                let tiles = &tiles;
+                let next_tile = &next_tile;
+                let plane = &plane;
+                scope.spawn(move || {
+                    loop {
+                        let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                        if index >= tiles.len() { break; }
+                        let tile = tiles[index];
+                        let pixels = renderer.render_tile(scene, plane, &tile, width, height);
+                        if sender.send((tile, pixels)).is_err() { break; }
+                    }
+                });
+            }
+            drop(sender);
+
+            // Merges finished tiles into the framebuffer as they arrive
+            for (tile, pixels) in receiver {
+                for (i, (color, count)) in pixels.into_iter().enumerate() {
+                    let i = i as i32;
+                    let x = tile.x + i % tile.width;
+                    let y = tile.y + i / tile.width;
+
+                    // Flips upside-down
+                    let y2 = height - y - 1;
+                    framebuffer.set(x as usize, y2 as usize, color);
+                    counts[y2 as usize * width as usize + x as usize] = count;
+                }
+            }
+        });
+        counts
+    }
+
+    /// Finds the extra passes for a width x height image, tracing one ray through each pixel's center
+    pub fn render_passes(&self, scene: &Scene, width: usize, height: usize) -> Passes {
+        let plane = scene.camera.near_plane();
+        let eye = scene.camera.eye.origin;
+        let mut passes = Passes {
+            depth: Framebuffer::new(width, height),
+            normal: Framebuffer::new(width, height),
+            albedo: Framebuffer::new(width, height)
+        };
+        for y in 0..height {
+            for x in 0..width {
+
+                // Flips upside-down, as camera rays count rows from the bottom
+                let (px, py) = (x as f64 + 0.5, (height - y) as f64 - 0.5);
+                let ray = scene.camera_ray(&plane, px, py, width as i32, height as i32);
+                let (depth, normal, albedo) = match scene.intersect(&ray) {
+                    Some(inter) => (
+                        (inter.position - eye).len(),
+                        inter.material.shading_normal(&inter).to_unit(),
+                        inter.material.color_at(&inter)
+                    ),
+                    None => (f64::INFINITY, Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, 0.0))
+                };
+                passes.depth.set(x, y, Vector::new(depth, depth, depth));
+                passes.normal.set(x, y, normal);
+                passes.albedo.set(x, y, albedo);
+            }
+        }
+        passes
+    }
+
+    /// Traces every pixel in a tile, along with the number of samples each took.
+    /// Pixels are stored row by row, bottom row first.
+    fn render_tile(&self, scene: &Scene, plane: &Plane, tile: &Tile, width: i32, height: i32) -> Vec<(Vector, u32)> {
+        let mut pixels = Vec::with_capacity((tile.width * tile.height) as usize);
+        for y in tile.y..tile.y+tile.height {
+            for x in tile.x..tile.x+tile.width {
+                pixels.push(self.render_pixel(scene, plane, x, y, width, height));
+            }
+        }
+        pixels
+    }
+
+    /// Traces samples spread over the filter's footprint around the pixel center, and returns their weighted average
+    /// along with the number of samples traced.
+    pub fn render_pixel(&self, scene: &Scene, plane: &Plane, x: i32, y: i32, width: i32, height: i32) -> (Vector, u32) {
+        let mut rng = pixel_rng(self.seed, x, y);
+        let radius = self.filter.radius();
+        let batch = match self.adaptive {
+            Some(adaptive) => adaptive.min_samples.max(1),
+            None => self.samples
+        };
+
+        let mut total = Vector::new(0.0, 0.0, 0.0);
+        let mut total_weight = 0.0;
+        let mut unweighted = Vector::new(0.0, 0.0, 0.0);
+        let mut count = 0;
+        let (mut sum, mut sum_squared) = (0.0, 0.0);
+        loop {
+
+            // Later batches shift the pattern by a random offset, wrapping around, so they don't retrace earlier samples
+            // The last batch is cut short so as not to pass the maximum
+            let (shift_u, shift_v) = if count == 0 { (0.0, 0.0) } else { (rng.next_f64(), rng.next_f64()) };
+            let size = match self.adaptive {
+                Some(adaptive) if count > 0 => batch.min(adaptive.max_samples - count),
+                _ => batch
+            };
+            for (u, v) in self.pattern.samples(size, &mut rng) {
+                let (u, v) = ((u + shift_u).fract(), (v + shift_v).fract());
+                let dx = (u - 0.5) * 2.0 * radius;
+                let dy = (v - 0.5) * 2.0 * radius;
+                let ray = scene.camera_ray(plane, x as f64 + 0.5 + dx, y as f64 + 0.5 + dy, width, height);
+                let color = self.integrator.trace(scene, &ray, &mut rng);
+                let weight = self.filter.weight(dx, dy);
+                total = total + color * weight;
+                total_weight += weight;
+                unweighted = unweighted + color;
+
+                let luminance = color.clamp().luminance();
+                sum += luminance;
+                sum_squared += luminance * luminance;
+                count += 1;
+            }
+
+            match self.adaptive {
+                Some(adaptive) if adaptive.needs_more(count, sum, sum_squared) => continue,
+                _ => break
+            }
+        }
+
+        // Negative lobes can cancel out the weights of a handful of samples
+        let color = if total_weight > 0.0 { total / total_weight } else { unweighted / count as f64 };
+        (color, count)
+    }
+}
+
+/// Creates an image showing how many samples each pixel took, going from black for none through blue, red and yellow
+/// to white for 'max_samples' or more.
+/// 'counts' holds one count per pixel, row by row from the top, as returned by Renderer::render.
+pub fn heatmap(counts: &[u32], width: i32, height: i32, max_samples: u32) -> Image {
+    let stops = [
+        Vector::new(0.0, 0.0, 0.0),
+        Vector::new(0.0, 0.0, 1.0),
+        Vector::new(1.0, 0.0, 0.0),
+        Vector::new(1.0, 1.0, 0.0),
+        Vector::new(1.0, 1.0, 1.0)
+    ];
+    let mut image = Image::blank(width, height);
+    for (i, &count) in counts.iter().enumerate() {
+        let t = (count as f64 / max_samples.max(1) as f64).min(1.0) * (stops.len() - 1) as f64;
+        let stop = (t.floor() as usize).min(stops.len() - 2);
+        let frac = t - stop as f64;
+        let color = stops[stop] * (1.0 - frac) + stops[stop + 1] * frac;
+        let i = i as i32;
+        image.set_pixel(i % width, i / width, vector_to_color(&color)).unwrap();
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene_file::parse_scene;
+    use std::path::Path;
+
+    fn scene() -> Scene {
+        parse_scene(include_str!("../scenes/shapes.toml"), Path::new("scenes")).unwrap()
+    }
+
+    #[test]
+    fn matches_single_threaded_render() {
+        let scene = scene();
+        let mut expected = Image::blank(64, 36);
+        scene.render(&mut expected);
+        for &threads in &[1, 4] {
+            let renderer = Renderer { threads, tile_size: 8, ..Renderer::default() };
+            let mut image = Image::blank(64, 36);
+            renderer.render(&scene, &mut image);
+            assert!(image.bytes == expected.bytes, "{} threads differ from Scene::render", threads);
+        }
+    }
+
+    #[test]
+    fn random_samples_do_not_depend_on_threads() {
+        let scene = scene();
+        let render = |threads| {
+            let renderer = Renderer {
+                threads,
+                tile_size: 8,
+                integrator: Integrator::Path,
+                samples: 4,
+                pattern: SamplePattern::Jittered,
+                seed: 7,
+                ..Renderer::default()
+            };
+            let mut framebuffer = Framebuffer::new(32, 18);
+            renderer.render_framebuffer(&scene, &mut framebuffer);
+            framebuffer.pixels.iter().map(|c| (c.x, c.y, c.z)).collect::<Vec<_>>()
+        };
+        assert_eq!(render(1), render(3));
+    }
+}
@@ -0,0 +1,328 @@
+use geom::{Vector, Basis};
+use rand::{Rng, SeedableRng, XorShiftRng};
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+/// How samples are placed within a pixel
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplePattern {
+
+    /// Evenly spaced grid. A single sample lands on the pixel center.
+    Grid,
+
+    /// Grid with each sample moved randomly within its cell
+    Jittered,
+
+    /// Grid rotated so that no two samples share a row or column, which handles near-horizontal and
+    /// near-vertical edges better than Grid. A single sample lands on the pixel center.
+    RotatedGrid
+}
+
+impl FromStr for SamplePattern {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SamplePattern, String> {
+        match s {
+            "grid" => Ok(SamplePattern::Grid),
+            "jittered" => Ok(SamplePattern::Jittered),
+            "rotated_grid" => Ok(SamplePattern::RotatedGrid),
+            _ => Err(format!("unknown sample pattern '{}', expected grid, jittered or rotated_grid", s))
+        }
+    }
+}
+
+impl SamplePattern {
+
+    /// Sample positions within the unit square.
+    /// The square is divided into rows of cells, as close to a square grid as 'count' allows, and one sample is placed
+    /// per cell. When 'count' isn't a product of two similar numbers, some rows hold one cell fewer, and are made
+    /// shorter so that every cell has the same area. Each row spans the whole width, so samples cover the square evenly.
+    pub fn samples<R: Rng>(&self, count: u32, rng: &mut R) -> Vec<(f64, f64)> {
+        let count = count.max(1);
+        let rows = ((count as f64).sqrt().round() as u32).max(1);
+        let mut samples = Vec::with_capacity(count as usize);
+        for row in 0..rows {
+
+            // The first rows take the samples left over after sharing them out evenly
+            let cols = count / rows + if row < count % rows { 1 } else { 0 };
+            let bottom = samples.len() as f64 / count as f64;
+            let height = cols as f64 / count as f64;
+            for col in 0..cols {
+                let (du, dv) = match *self {
+                    SamplePattern::Jittered => (rng.next_f64(), rng.next_f64()),
+                    _ => (0.5, 0.5)
+                };
+                let u = (col as f64 + du) / cols as f64;
+                let v = bottom + dv * height;
+                samples.push((u, v));
+            }
+        }
+        if *self == SamplePattern::RotatedGrid {
+            samples = rotate(&samples);
+        }
+        samples
+    }
+}
+
+/// Rotates samples by atan(1/2) about the center of the square, then spreads them out so that each has a row and a
+/// column of its own, keeping the order they fall in along each axis. Every row and column is used once, so the
+/// samples stay centered within the square.
+fn rotate(samples: &[(f64, f64)]) -> Vec<(f64, f64)> {
+    let (sin, cos) = (0.5f64).atan().sin_cos();
+    let rotated: Vec<(f64, f64)> = samples.iter()
+        .map(|&(u, v)| ((u - 0.5)*cos - (v - 0.5)*sin, (u - 0.5)*sin + (v - 0.5)*cos))
+        .collect();
+    let n = samples.len();
+    let mut by_x: Vec<usize> = (0..n).collect();
+    let mut by_y = by_x.clone();
+    by_x.sort_by(|&a, &b| rotated[a].0.total_cmp(&rotated[b].0));
+    by_y.sort_by(|&a, &b| rotated[a].1.total_cmp(&rotated[b].1));
+
+    let mut result = vec![(0.0, 0.0); n];
+    for (rank, (&x, &y)) in by_x.iter().zip(by_y.iter()).enumerate() {
+        let position = (rank as f64 + 0.5) / n as f64;
+        result[x].0 = position;
+        result[y].1 = position;
+    }
+    result
+}
+
+/// Settings for adaptive sampling.
+/// Each pixel starts with 'min_samples' samples. Further batches of 'min_samples' are added while the standard error
+/// of the pixel's mean luminance exceeds 'threshold', until 'max_samples' is reached.
+#[derive(Copy, Clone, Debug, PartialEq, new)]
+pub struct Adaptive {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub threshold: f64
+}
+
+impl Adaptive {
+
+    /// Whether a pixel whose samples have the given luminance sum and sum of squares needs more samples
+    pub fn needs_more(&self, count: u32, sum: f64, sum_squared: f64) -> bool {
+        if count >= self.max_samples { return false; }
+        if count < 2 { return true; }
+        let n = count as f64;
+        let mean = sum / n;
+        let variance = ((sum_squared - mean*sum) / (n - 1.0)).max(0.0);
+        (variance / n).sqrt() > self.threshold
+    }
+}
+
+/// Reconstruction filter that weighs samples by their distance from the pixel center
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Gaussian,
+
+    /// Mitchell-Netravali with B = C = 1/3.
+    /// Has negative lobes, so sharpens slightly.
+    Mitchell
+}
+
+impl FromStr for Filter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Filter, String> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            "gaussian" => Ok(Filter::Gaussian),
+            "mitchell" => Ok(Filter::Mitchell),
+            _ => Err(format!("unknown filter '{}', expected box, tent, gaussian or mitchell", s))
+        }
+    }
+}
+
+impl Filter {
+
+    /// Distance from the pixel center, in pixels, beyond which samples have no weight
+    pub fn radius(&self) -> f64 {
+        match *self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0
+        }
+    }
+
+    /// Weight of a sample offset (dx, dy) pixels from the pixel center
+    pub fn weight(&self, dx: f64, dy: f64) -> f64 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, d: f64) -> f64 {
+        let d = d.abs();
+        let radius = self.radius();
+        if d > radius { return 0.0; }
+        match *self {
+            Filter::Box => 1.0,
+            Filter::Tent => 1.0 - d,
+            Filter::Gaussian => {
+                let alpha = 2.0;
+                (-alpha*d*d).exp() - (-alpha*radius*radius).exp()
+            },
+            Filter::Mitchell => {
+                let (b, c) = (1.0/3.0, 1.0/3.0);
+                let value = if d < 1.0 {
+                    (12.0 - 9.0*b - 6.0*c)*d*d*d + (-18.0 + 12.0*b + 6.0*c)*d*d + (6.0 - 2.0*b)
+                }
+                else {
+                    (-b - 6.0*c)*d*d*d + (6.0*b + 30.0*c)*d*d + (-12.0*b - 48.0*c)*d + (8.0*b + 24.0*c)
+                };
+                value / 6.0
+            }
+        }
+    }
+}
+
+/// Random unit direction in the hemisphere around a unit normal, more likely the closer it is to the normal.
+/// The chance of each direction is proportional to the cosine of its angle to the normal, which cancels out the
+/// cosine term of diffuse reflection.
+pub fn cosine_hemisphere<R: Rng>(normal: &Vector, rng: &mut R) -> Vector {
+    let phi = 2.0 * PI * rng.next_f64();
+    let r2 = rng.next_f64();
+    let r = r2.sqrt();
+    let local = Vector::new(r * phi.cos(), r * phi.sin(), (1.0 - r2).sqrt());
+    Basis::from_w(normal).to_world(&local)
+}
+
+/// Piecewise constant distribution over 0 to 1, split into equal parts in proportion to their weights.
+/// Turns evenly spread numbers into ones bunched up where the weights are high.
+#[derive(Clone, Debug)]
+pub struct Distribution {
+
+    /// Running total of the weights, ending at 1. Empty if the weights add up to nothing, which spreads samples
+    /// evenly instead.
+    cdf: Vec<f64>,
+    parts: usize
+}
+
+impl Distribution {
+
+    /// Weights must not be negative
+    pub fn new(weights: &[f64]) -> Distribution {
+        let total: f64 = weights.iter().sum();
+        let cdf = if total > 0.0 {
+            weights.iter().scan(0.0, |sum, w| { *sum += w / total; Some(*sum) }).collect()
+        } else {
+            Vec::new()
+        };
+        Distribution { cdf, parts: weights.len().max(1) }
+    }
+
+    /// Maps 's', between 0 and 1, to a point between 0 and 1.
+    /// Returns the point, the index of the part it falls in and the density of the distribution there.
+    pub fn sample(&self, s: f64) -> (f64, usize, f64) {
+        let n = self.parts as f64;
+        if self.cdf.is_empty() {
+            let index = ((s * n) as usize).min(self.parts - 1);
+            return (s, index, 1.0);
+        }
+
+        // Finds the first part whose running total passes s, skipping parts of no weight
+        let index = self.cdf.partition_point(|&c| c <= s).min(self.parts - 1);
+        let start = if index == 0 { 0.0 } else { self.cdf[index - 1] };
+        let share = self.cdf[index] - start;
+        let within = if share > 0.0 { ((s - start) / share).clamp(0.0, 1.0) } else { 0.5 };
+        ((index as f64 + within) / n, index, share * n)
+    }
+}
+
+/// Random number generator for a single pixel.
+/// Seeding from the pixel's coordinates keeps results the same no matter which thread traces the pixel, or in what order.
+pub fn pixel_rng(seed: u32, x: i32, y: i32) -> XorShiftRng {
+    let a = hash(seed ^ 0x9e37_79b9);
+    let b = hash(a ^ x as u32);
+    let c = hash(b ^ y as u32);
+    let d = hash(c) | 1;
+    XorShiftRng::from_seed([a, b, c, d])
+}
+
+/// Random number generator for a point in space, for tracers that have no generator of their own.
+/// The same point always gives the same numbers, while neighbouring points give unrelated ones.
+pub fn point_rng(point: &Vector) -> XorShiftRng {
+    let bits = |x: f64| -> u32 {
+        let bits = x.to_bits();
+        (bits as u32) ^ ((bits >> 32) as u32)
+    };
+    let a = hash(bits(point.x) ^ 0x9e37_79b9);
+    let b = hash(a ^ bits(point.y));
+    let c = hash(b ^ bits(point.z));
+    let d = hash(c) | 1;
+    XorShiftRng::from_seed([a, b, c, d])
+}
+
+/// Mixes the bits of a number, so that similar numbers give very different results
+fn hash(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^= h >> 16;
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns_are_centered() {
+        let mut rng = pixel_rng(0, 0, 0);
+        for &pattern in &[SamplePattern::Grid, SamplePattern::RotatedGrid] {
+            for count in 2..17 {
+                let samples = pattern.samples(count, &mut rng);
+                assert_eq!(samples.len(), count as usize);
+                let n = samples.len() as f64;
+                let mean_u = samples.iter().map(|s| s.0).sum::<f64>() / n;
+                let mean_v = samples.iter().map(|s| s.1).sum::<f64>() / n;
+                assert!((mean_u - 0.5).abs() < 1e-9 && (mean_v - 0.5).abs() < 1e-9,
+                    "{:?} with {} samples is centered on ({}, {})", pattern, count, mean_u, mean_v);
+            }
+        }
+    }
+
+    #[test]
+    fn rotated_grid_gives_each_sample_its_own_row_and_column() {
+        for count in 1..17 {
+            let samples = SamplePattern::RotatedGrid.samples(count, &mut pixel_rng(0, 0, 0));
+            let mut us: Vec<u32> = samples.iter().map(|s| (s.0 * count as f64) as u32).collect();
+            let mut vs: Vec<u32> = samples.iter().map(|s| (s.1 * count as f64) as u32).collect();
+            us.sort();
+            vs.sort();
+            let expected: Vec<u32> = (0..count).collect();
+            assert_eq!(us, expected);
+            assert_eq!(vs, expected);
+        }
+    }
+
+    #[test]
+    fn grid_fills_every_row() {
+        let samples = SamplePattern::Grid.samples(3, &mut pixel_rng(0, 0, 0));
+        let mut rows: Vec<f64> = samples.iter().map(|s| s.1).collect();
+        rows.dedup();
+        assert_eq!(rows.len(), 2);
+        assert!(samples.iter().all(|s| s.0 > 0.0 && s.0 < 1.0 && s.1 > 0.0 && s.1 < 1.0));
+    }
+
+    #[test]
+    fn adaptive_sampling_stops_at_the_maximum() {
+        let adaptive = Adaptive::new(4, 16, 0.01);
+
+        // Samples alternating between 0 and 1 are as noisy as they get
+        let noisy = |count: u32| (count as f64 / 2.0, count as f64 / 2.0);
+        for count in 2..16 {
+            let (sum, sum_squared) = noisy(count);
+            assert!(adaptive.needs_more(count, sum, sum_squared), "stopped at {} samples", count);
+        }
+        for &count in &[16, 17, 100] {
+            let (sum, sum_squared) = noisy(count);
+            assert!(!adaptive.needs_more(count, sum, sum_squared), "carried on past {} samples", count);
+        }
+
+        // Pixels that don't vary stop as soon as they can tell, and single samples never can
+        assert!(!adaptive.needs_more(4, 2.0, 1.0));
+        assert!(adaptive.needs_more(1, 0.5, 0.25));
+    }
+}
@@ -1,9 +1,13 @@
-extern crate raster;
-
 use shape::{Shape};
-use geom::{Vector, Ray, Plane, Intersection};
-use self::raster::{Image, Color};
-use std::cmp::Ordering;
+use geom::{Vector, Ray, Plane, Aabb, Intersection};
+use bvh::Bvh;
+use microfacet::Microfacet;
+use light::{Light, LightSample};
+use environment::Environment;
+use sampling::point_rng;
+use rand::Rng;
+use raster::{Image, Color};
+use std::f64::consts::PI;
 
 pub fn vector_to_color(vector: &Vector) -> Color {
     let clamped = vector.clamp();
@@ -55,58 +59,177 @@ impl Camera {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct Light {
-    pub position: Vector,
-    pub color: Vector,
-    pub brightness: f64
-}
-
 #[derive(Debug)]
 pub struct Scene {
     pub color_background: Vector,
     pub color_ambient: Vector,
     pub camera: Camera,
-    pub shapes: Vec<Box<Shape>>,
+
+    /// Kept private so that the acceleration structure, which refers to shapes by index, can't go stale
+    shapes: Vec<Box<dyn Shape>>,
     pub lights: Vec<Light>,
-    pub bounce_limit: u32
+    pub bounce_limit: u32,
+
+    /// If set, seen by rays that miss everything in place of color_background, and lights the scene
+    pub environment: Option<Environment>,
+    bvh: Bvh,
+
+    /// Lights standing in for glowing shapes
+    shape_lights: Vec<Light>,
+
+    /// Whether each shape has lights standing in for it
+    sampled: Vec<bool>
 }
 
 impl Scene {
 
-    /// Writes intersections of a ray with all objects in the scene.
-    fn trace_color(&self, ray: &Ray, bounce_limit: u32) -> Vector {
+    pub fn new(
+        color_background: Vector,
+        color_ambient: Vector,
+        camera: Camera,
+        shapes: Vec<Box<dyn Shape>>,
+        lights: Vec<Light>,
+        bounce_limit: u32
+    ) -> Scene {
+        let mut scene = Scene {
+            color_background,
+            color_ambient,
+            camera,
+            shapes,
+            lights,
+            bounce_limit,
+            environment: None,
+            bvh: Bvh::default(),
+            shape_lights: Vec::new(),
+            sampled: Vec::new()
+        };
+        scene.rebuild();
+        scene
+    }
 
-        // Initializes closest value as nothing.
-        let mut maybe_closest: Option<Intersection> = None;
+    pub fn shapes(&self) -> &[Box<dyn Shape>] { &self.shapes }
 
-        // Finds closest intersection, if any
+    /// Shapes, for moving them. The acceleration structure isn't updated until refit is called.
+    pub fn shapes_mut(&mut self) -> &mut [Box<dyn Shape>] { &mut self.shapes }
+
+    /// Adds a shape, rebuilding the acceleration structure around it.
+    /// Scenes with many shapes are quicker to build all at once through Scene::new.
+    pub fn add_shape(&mut self, shape: Box<dyn Shape>) {
+        self.shapes.push(shape);
+        self.rebuild();
+    }
+
+    /// Removes and returns the shape at 'index', rebuilding the acceleration structure without it
+    pub fn remove_shape(&mut self, index: usize) -> Box<dyn Shape> {
+        let shape = self.shapes.remove(index);
+        self.rebuild();
+        shape
+    }
+
+    /// Rebuilds the acceleration structure and the lights of glowing shapes from scratch
+    pub fn rebuild(&mut self) {
+        let bounds: Vec<Aabb> = self.shapes.iter().map(|s| s.bounds()).collect();
+        self.bvh = Bvh::build(&bounds);
+        self.update_shape_lights();
+    }
+
+    /// Updates the acceleration structure and the lights of glowing shapes after shapes have moved, such as between
+    /// animation frames. Falls back to a rebuild if a shape became or stopped being infinite.
+    pub fn refit(&mut self) {
+        let bounds: Vec<Aabb> = self.shapes.iter().map(|s| s.bounds()).collect();
+        if !self.bvh.refit(&bounds) {
+            self.bvh = Bvh::build(&bounds);
+        }
+        self.update_shape_lights();
+    }
+
+    fn update_shape_lights(&mut self) {
+        self.shape_lights.clear();
+        self.sampled.clear();
         for shape in &self.shapes {
+            let lights = shape.lights();
+            self.sampled.push(!lights.is_empty());
+            self.shape_lights.extend(lights);
+        }
+    }
 
-            // Calculates intersection
-            let maybe_inter: Option<Intersection> = shape.intersect(ray);
-
-            // If intersection found, add that intersection
-            if let Some(new_inter) = maybe_inter {
-                if let Some(closest) = maybe_closest {
-                    if new_inter.t < closest.t {
-                        maybe_closest = maybe_inter;
-                    }
-                }
-                else {
-                    maybe_closest = maybe_inter;
-                }
-            }
+    /// Every light in the scene, followed by the lights standing in for glowing shapes
+    pub fn all_lights(&self) -> impl Iterator<Item = &Light> {
+        self.lights.iter().chain(self.shape_lights.iter())
+    }
+
+    /// Samples every light as seen from 'point', followed by the environment if there is one.
+    /// See Light::sample.
+    pub fn sample_lights<R: Rng>(&self, point: &Vector, rng: &mut R) -> Vec<LightSample> {
+        let reach = self.camera.far_dist;
+        let mut samples: Vec<LightSample> = self.all_lights().flat_map(|light| light.sample(point, reach, rng)).collect();
+        if let Some(ref environment) = self.environment {
+            samples.extend(environment.sample(reach, rng));
         }
+        samples
+    }
+
+    /// Light seen by a ray along 'dir' that misses everything
+    pub fn background(&self, dir: &Vector) -> Vector {
+        match self.environment {
+            Some(ref environment) => environment.radiance(dir),
+            None => self.color_background
+        }
+    }
+
+    /// Finds the closest intersection of a ray with the objects in the scene.
+    pub fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let shapes = &self.shapes;
+        self.bvh.intersect(ray, |i| shapes[i].intersect(ray))
+    }
+
+    /// Finds the closest intersection like Scene::intersect, and whether the shape hit has lights standing in for it.
+    /// Light from such shapes reaches other surfaces through sample_lights, so needn't be picked up by rays hitting them.
+    pub fn intersect_sampled(&self, ray: &Ray) -> Option<(Intersection<'_>, bool)> {
+        let shapes = &self.shapes;
+
+        // Follows the choice of the acceleration structure, which keeps the first of equally close hits
+        let mut closest = (f64::INFINITY, false);
+        let inter = self.bvh.intersect(ray, |i| {
+            let inter = shapes[i].intersect(ray);
+            if let Some(ref inter) = inter {
+                if inter.t < closest.0 { closest = (inter.t, self.sampled[i]); }
+            }
+            inter
+        })?;
+        Some((inter, closest.1))
+    }
+
+    /// Shades what a ray hits, following up to 'bounce_limit' reflections and refractions.
+    /// Light bouncing between diffuse surfaces is stood in for by the ambient color.
+    pub fn trace_color(&self, ray: &Ray, bounce_limit: u32) -> Vector {
+
+        // Finds closest intersection, if any
+        let maybe_closest: Option<Intersection> = self.intersect(ray);
 
         // If intersections were found..
         if let Some(closest) = maybe_closest {
 
+            // Shades with the normal bent by the material's normal and bump maps, for lighting and reflections alike
+            let closest = Intersection { normal: closest.material.shading_normal(&closest), ..closest };
+
+            // Glowing surfaces add their own light on top of what they reflect
+            let glow: Vector = closest.material.emitted(closest.inside);
+
+            // Microfacet materials are shaded by their own model
+            if let Some(ref microfacet) = closest.material.microfacet {
+                let surface_color = self.shade_microfacet(ray, &closest, microfacet, bounce_limit);
+                return self.blend_transparency(ray, &closest, surface_color, glow, bounce_limit);
+            }
+
             // Gets ambient color
             let ambient_color: Vector = self.color_ambient;
 
-            // Gets material color
-            let material_color: Vector = closest.color;
+            // Gets material color, reflectivity and exponent at the point hit
+            let material = closest.material;
+            let material_color: Vector = material.color_at(&closest);
+            let reflectivity: f64 = material.reflectivity_at(&closest);
+            let exponent: f64 = material.exponent_at(&closest);
 
             // Initializes total light color and specular color as zero.
             let mut total_light_color: Vector = Vector::new(0.0, 0.0, 0.0);
@@ -116,21 +239,21 @@ impl Scene {
             let inter_pos: Vector = closest.position;
             let surface_normal_unit = closest.normal.to_unit();
 
-            for light in &self.lights {
+            let mut rng = point_rng(&inter_pos);
+            for light in self.sample_lights(&inter_pos, &mut rng) {
 
                 // Skips this light if it is in the shadow.
-                let light_dir: Vector = light.position - inter_pos;
-                let inter_to_light = Ray {
-                    origin: inter_pos,
-                    dir: light_dir
-                };
-                if self.intersects(&inter_to_light) { continue; }
+                // Transparent objects in the way let some light through, tinted by what they absorb.
+                let light_dir: Vector = light.dir;
+                let transmittance: Vector = self.transmittance(&light.shadow_ray(&inter_pos));
+                if transmittance.len_squared() == 0.0 { continue; }
+                let light_color: Vector = light.color * transmittance;
 
                 // Adds light value
                 let light_dir_unit: Vector = light_dir.to_unit();
                 let cos_angle: f64 = surface_normal_unit.dot(&light_dir_unit);
-                let delta_color = (light.color * cos_angle).clamp();
-                let intensity: f64 = 1.0 / (light_dir.len_squared());
+                let delta_color = (light_color * cos_angle).clamp();
+                let intensity: f64 = light.falloff;
                 total_light_color = total_light_color + delta_color * intensity * light.brightness;
 
                 // Adds specular value
@@ -138,14 +261,13 @@ impl Scene {
                 let bounce: Vector = light_dir_unit - surface_normal_unit * 2.0 * (surface_normal_unit.dot(&light_dir_unit));
                 let bounce_unit = bounce.to_unit();
                 let eye_dir_unit: Vector = -ray.dir.to_unit();
-                let cos_angle = (eye_dir_unit.dot(&bounce_unit));
+                let cos_angle = eye_dir_unit.dot(&bounce_unit);
                 let cos_angle = if cos_angle < 0.0 { 0.0 } else {cos_angle};
-                let specular: f64 = (cos_angle).powf(closest.exponent);
-                total_specular_color = total_specular_color + light.color * specular * closest.reflectivity;
+                let specular: f64 = (cos_angle).powf(exponent);
+                total_specular_color = total_specular_color + light_color * specular * reflectivity * light.weight;
             }
 
             // Recurses if reflection is possible
-            let reflectivity:f64 = closest.reflectivity;
             let mut reflect_color = Vector::new(0.0, 0.0, 0.0);
             if bounce_limit != 0 && reflectivity > 0.0 {
 
@@ -158,42 +280,132 @@ impl Scene {
                 };
 
                 // Gets reflective color
-                reflect_color = reflect_color + self.trace_color(&reflect_ray, bounce_limit - 1) * closest.reflectivity;
+                reflect_color = reflect_color + self.trace_color(&reflect_ray, bounce_limit - 1) * reflectivity;
             }
 
             // Calculates final color and returns it
             let reverse_refl = 1.0-reflectivity;
             let base_color: Vector = (material_color * reverse_refl) + (reflect_color * reflectivity);
-            let shadow_color = (ambient_color + total_light_color);
+            let shadow_color = ambient_color + total_light_color;
             let shadow_color = shadow_color.interp(&Vector::new(1.0, 1.0, 1.0), reflectivity);
-            let final_color: Vector = base_color * shadow_color + total_specular_color;
-            return final_color;
+            let final_color: Vector = base_color * shadow_color;
+            return self.blend_transparency(ray, &closest, final_color, total_specular_color + glow, bounce_limit);
         }
 
         // Default color return
-        return self.color_background;
+        self.background(&ray.dir)
     }
 
-    /// Returns true if ray intersects with any object in the scene
-    fn intersects(&self, ray: &Ray) -> bool {
-        for shape in &self.shapes {
-            if shape.intersects(ray) {
-                return true;
+    /// Blends light passing through the transparent part of a surface with 'surface_color', the color of its opaque
+    /// part, then adds 'highlight_color' on top.
+    /// Rays hitting a surface from inside have travelled through its medium, and are attenuated by it.
+    fn blend_transparency(
+        &self,
+        ray: &Ray,
+        inter: &Intersection,
+        surface_color: Vector,
+        highlight_color: Vector,
+        bounce_limit: u32
+    ) -> Vector {
+        let material = inter.material;
+        let mut final_color = surface_color;
+        let transparency: f64 = material.transparency;
+        if bounce_limit != 0 && transparency > 0.0 {
+            let clear_color = self.trace_dielectric(ray, inter, bounce_limit - 1);
+            final_color = final_color * (1.0 - transparency) + clear_color * transparency;
+        }
+        let final_color = final_color + highlight_color;
+        if inter.inside {
+            return final_color * material.absorb(ray.dir.len() * inter.t);
+        }
+        final_color
+    }
+
+    /// Shades the opaque part of a surface with a microfacet material.
+    /// Rough reflections can't be followed by a single ray, so only the mirror-like share of reflection is traced, and
+    /// ambient light stands in for the rest.
+    fn shade_microfacet(&self, ray: &Ray, inter: &Intersection, microfacet: &Microfacet, bounce_limit: u32) -> Vector {
+        let color = inter.material.color_at(inter);
+        let normal_unit = inter.normal.to_unit();
+        let eye_dir_unit = -ray.dir.to_unit();
+        let mut total = self.color_ambient * microfacet.ambient_reflectance(&normal_unit, &eye_dir_unit, &color);
+
+        // Lights are scaled by PI to match the brightness of Phong's diffuse term
+        let mut rng = point_rng(&inter.position);
+        for light in self.sample_lights(&inter.position, &mut rng) {
+            let transmittance = self.transmittance(&light.shadow_ray(&inter.position));
+            if transmittance.len_squared() == 0.0 { continue; }
+            let reflected = microfacet.eval(&normal_unit, &eye_dir_unit, &light.dir.to_unit(), &color);
+            total = total + light.color * transmittance * reflected * (PI * light.brightness * light.falloff);
+        }
+
+        let mirror = microfacet.mirror_reflectance(&normal_unit, &eye_dir_unit, &color);
+        if bounce_limit != 0 && mirror.len_squared() > 0.0 {
+            let reflect_ray = Ray {
+                origin: inter.position,
+                dir: (-eye_dir_unit).reflect(&normal_unit) * self.camera.far_dist
+            };
+            total = total + self.trace_color(&reflect_ray, bounce_limit - 1) * mirror;
+        }
+        total
+    }
+
+    /// Traces the light reflected off and refracted through a dielectric surface, blended by Fresnel's equations.
+    /// Rays totally internally reflected inside the shape are only reflected.
+    fn trace_dielectric(&self, ray: &Ray, inter: &Intersection, bounce_limit: u32) -> Vector {
+        let material = inter.material;
+        let dir_unit = ray.dir.to_unit();
+        let normal_unit = inter.normal.to_unit();
+        let reflectance = material.fresnel(-normal_unit.dot(&dir_unit), inter.inside);
+
+        let reflect_ray = Ray {
+            origin: inter.position,
+            dir: dir_unit.reflect(&normal_unit) * self.camera.far_dist
+        };
+        let reflect_color = self.trace_color(&reflect_ray, bounce_limit);
+        if reflectance >= 1.0 { return reflect_color; }
+
+        match dir_unit.refract(&normal_unit, material.eta(inter.inside)) {
+            Some(refract_dir) => {
+                let refract_ray = Ray {
+                    origin: inter.position,
+                    dir: refract_dir * self.camera.far_dist
+                };
+                let refract_color = self.trace_color(&refract_ray, bounce_limit);
+                reflect_color * reflectance + refract_color * (1.0 - reflectance)
+            },
+            None => reflect_color
+        }
+    }
+
+    /// Fraction of each color channel of light carried along a ray that makes it to the ray's end.
+    /// Opaque objects block the ray entirely. Transparent ones let their transparency through at each surface, less
+    /// what their medium absorbs between the ray entering and leaving it. Refraction doesn't bend the ray.
+    pub fn transmittance(&self, ray: &Ray) -> Vector {
+        let mut transmittance = Vector::new(1.0, 1.0, 1.0);
+        let mut ray = *ray;
+        while let Some(inter) = self.intersect(&ray) {
+            let material = inter.material;
+            if material.transparency <= 0.0 { return Vector::new(0.0, 0.0, 0.0); }
+            if inter.inside {
+                transmittance = transmittance * material.absorb(ray.dir.len() * inter.t);
             }
+            transmittance = transmittance * material.transparency;
+
+            // Carries on past the surface, towards the same end
+            ray = Ray::new(inter.position, ray.dir * (1.0 - inter.t));
         }
-        false
+        transmittance
     }
 
+    /// Renders the scene one pixel at a time on the calling thread.
+    /// See render::Renderer for the multithreaded equivalent.
     pub fn render(&self, image: &mut Image) {
 
         // Gets image size
         let width: i32 = image.width;
         let height: i32 = image.height;
 
-        // Gets camera and eye_origin position
-        let camera: &Camera = &self.camera;
-        let eye_origin:Vector = camera.eye.origin;
-
         // Gets plane at which to trace rays
         let plane = self.camera.near_plane();
 
@@ -205,26 +417,114 @@ impl Scene {
                 // Flips upside-down
                 let y2 = height - y - 1;
 
-                // Gets coordinate ratios
-                let xr:f64 = (x as f64 + 0.5) / width as f64;
-                let yr:f64 = (y as f64 + 0.5) / height as f64;
+                let color: Vector = self.trace_pixel(&plane, x, y, width, height);
 
-                // Gets associated point on the plane
-                let plane_pos: Vector = plane.interp(xr, yr);
+                // Sets current pixel to that color
+                image.set_pixel(x, y2, vector_to_color(&color)).unwrap();
+            }
+        }
+    }
 
-                // Determines direction of eye_origin to plane position.
-                let eye_dir: Vector = plane_pos - eye_origin;
-                let z_diff: f64 = camera.far_dist / eye_dir.len();
-                let eye_dir = eye_dir * z_diff;
+    /// Traces the ray through the center of pixel (x, y) of a width x height image, where y runs bottom to top.
+    /// 'plane' is the camera's near plane.
+    pub fn trace_pixel(&self, plane: &Plane, x: i32, y: i32, width: i32, height: i32) -> Vector {
+        self.trace_point(plane, x as f64 + 0.5, y as f64 + 0.5, width, height)
+    }
 
-                // Defines the ray to cast through that position
-                let ray = Ray { origin: plane_pos, dir: eye_dir };
+    /// Traces the ray through point (px, py) of a width x height image, measured in pixels from its bottom-left corner.
+    /// 'plane' is the camera's near plane.
+    pub fn trace_point(&self, plane: &Plane, px: f64, py: f64, width: i32, height: i32) -> Vector {
+        let ray = self.camera_ray(plane, px, py, width, height);
+        self.trace_color(&ray, self.bounce_limit)
+    }
 
-                let color: Vector = self.trace_color(&ray, self.bounce_limit);
+    /// Ray from the camera through point (px, py) of a width x height image, as traced by trace_point
+    pub fn camera_ray(&self, plane: &Plane, px: f64, py: f64, width: i32, height: i32) -> Ray {
 
-                // Sets current pixel to that color
-                image.set_pixel(x, y2, vector_to_color(&color)).unwrap();
-            }
+        // Gets camera and eye_origin position
+        let camera: &Camera = &self.camera;
+        let eye_origin:Vector = camera.eye.origin;
+
+        // Gets coordinate ratios
+        let xr:f64 = px / width as f64;
+        let yr:f64 = py / height as f64;
+
+        // Gets associated point on the plane
+        let plane_pos: Vector = plane.interp(xr, yr);
+
+        // Determines direction of eye_origin to plane position.
+        let eye_dir: Vector = plane_pos - eye_origin;
+        let z_diff: f64 = camera.far_dist / eye_dir.len();
+        let eye_dir = eye_dir * z_diff;
+
+        // Defines the ray to cast through that position
+        Ray { origin: plane_pos, dir: eye_dir }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scene_file::parse_scene;
+    use std::path::Path;
+
+    /// Scene of unit spheres along the view axis, given by their z and material, which is either tinted "glass" or
+    /// "opaque"
+    fn spheres(spheres: &[(f64, &str)]) -> Scene {
+        let mut source = String::from(
+            "color_background = [0.0, 0.0, 0.0]\ncolor_ambient = [0.0, 0.0, 0.0]\nbounce_limit = 1\n\
+             [camera]\norigin = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\nup = [0.0, 1.0, 0.0]\n\
+             near_dist = 1.0\nfar_dist = 100.0\nfrust_width = 1.0\nfrust_height = 1.0\n\
+             [materials.glass]\ncolor = [1.0, 1.0, 1.0]\nreflectivity = 0.0\nexponent = 1.0\n\
+             transparency = 0.9\nior = 1.5\nabsorption = [0.5, 1.0, 0.0]\n\
+             [materials.opaque]\ncolor = [1.0, 1.0, 1.0]\nreflectivity = 0.0\nexponent = 1.0\n"
+        );
+        for &(z, material) in spheres {
+            source += &format!(
+                "[[shapes]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, {}]\nradius = 1.0\nmaterial = \"{}\"\n",
+                z, material
+            );
         }
+        parse_scene(&source, Path::new("")).unwrap()
+    }
+
+    /// Color of the shadow cast along the z axis through every sphere, from z = 5 to z = -10
+    fn shadow(scene: &Scene) -> Vector {
+        scene.transmittance(&Ray::new(Vector::new(0.0, 0.0, 5.0), Vector::new(0.0, 0.0, -15.0)))
+    }
+
+    fn assert_close(a: &Vector, b: &Vector) {
+        assert!((*a - *b).len() < 1e-9, "expected {:?}, found {:?}", b, a);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn tints_shadows_through_transparent_shapes() {
+
+        // Each sphere filters by its transparency at both surfaces, and absorbs across its diameter of 2
+        let one = Vector::new((-1.0f64).exp(), (-2.0f64).exp(), 1.0) * (0.9 * 0.9);
+        assert_close(&shadow(&spheres(&[(0.0, "glass")])), &one);
+        assert_close(&shadow(&spheres(&[(0.0, "glass"), (-4.0, "glass")])), &(one * one));
+
+        // Nothing in the way lets all the light through
+        assert_close(&shadow(&spheres(&[])), &Vector::new(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn absorbs_only_the_distance_travelled_inside() {
+
+        // Starting at the center, the ray crosses one radius of glass and one surface
+        let scene = spheres(&[(0.0, "glass")]);
+        let from_center = scene.transmittance(&Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, -10.0)));
+        assert_close(&from_center, &(Vector::new((-0.5f64).exp(), (-1.0f64).exp(), 1.0) * 0.9));
+
+        // Ending before the sphere, nothing is in the way
+        let short = scene.transmittance(&Ray::new(Vector::new(0.0, 0.0, 5.0), Vector::new(0.0, 0.0, -3.0)));
+        assert_close(&short, &Vector::new(1.0, 1.0, 1.0));
+    }
+
+    #[test]
+    fn opaque_shapes_block_shadows() {
+        let scene = spheres(&[(0.0, "glass"), (-4.0, "opaque")]);
+        assert_close(&shadow(&scene), &Vector::new(0.0, 0.0, 0.0));
+    }
+}
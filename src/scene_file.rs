@@ -0,0 +1,1240 @@
+use geom::{Vector, Ray};
+use scene::{Scene, Camera};
+use light::{Light, LightKind};
+use environment::Environment;
+use shape::{Shape, Sphere, Floor, Cuboid, OrientedCuboid, Cylinder, Cone, Triangle, Quad};
+use mesh::Mesh;
+use material::Material;
+use microfacet::Microfacet;
+use texture::{Texture, ColorRamp, ImageTexture, TextureFilter, TextureError, Wrap};
+use obj::{self, ObjError};
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use toml;
+
+/// Error raised while loading or saving a scene file
+#[derive(Debug)]
+pub enum SceneFileError {
+
+    /// File could not be read or written
+    Io(PathBuf, io::Error),
+
+    /// File is not valid TOML or does not have the expected structure
+    Syntax(String),
+
+    /// A field holds a value that makes no sense, such as a negative radius.
+    /// 'field' is the path to the value, such as "shapes[2].radius".
+    Invalid { field: String, message: String },
+
+    /// OBJ file referenced by the scene failed to load
+    Obj(String, ObjError),
+
+    /// Image texture referenced by the scene failed to load
+    Texture(String, TextureError),
+
+    /// Scene could not be written out
+    Serialize(String)
+}
+
+impl fmt::Display for SceneFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SceneFileError::Io(ref path, ref err) => write!(f, "{}: {}", path.display(), err),
+            SceneFileError::Syntax(ref message) => write!(f, "{}", message),
+            SceneFileError::Invalid { ref field, ref message } => write!(f, "{}: {}", field, message),
+            SceneFileError::Obj(ref field, ref err) => write!(f, "{}: {}", field, err),
+            SceneFileError::Texture(ref field, ref err) => write!(f, "{}: {}", field, err),
+            SceneFileError::Serialize(ref message) => write!(f, "could not write scene: {}", message)
+        }
+    }
+}
+
+impl Error for SceneFileError {}
+
+/// Loads a scene from a TOML scene file.
+/// Paths inside the file are relative to the file's directory.
+/// Problems that don't stop the scene loading are ignored. See load_scene_with_warnings.
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Scene, SceneFileError> {
+    load_scene_with_warnings(path).map(|(scene, _)| scene)
+}
+
+/// Loads a scene like load_scene, along with warnings of problems that loading carried on past, such as OBJ files
+/// using materials they don't define, for the caller to report.
+pub fn load_scene_with_warnings<P: AsRef<Path>>(path: P) -> Result<(Scene, Vec<String>), SceneFileError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| SceneFileError::Io(path.to_path_buf(), e))?;
+    let dir = path.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    parse_scene_with_warnings(&source, &dir)
+        .map_err(|e| match e {
+            SceneFileError::Syntax(message) => SceneFileError::Syntax(format!("{}: {}", path.display(), message)),
+            other => other
+        })
+}
+
+/// Parses a scene from the contents of a TOML scene file.
+/// Paths inside the source are relative to 'base_dir'.
+/// Problems that don't stop the scene loading are ignored. See parse_scene_with_warnings.
+pub fn parse_scene(source: &str, base_dir: &Path) -> Result<Scene, SceneFileError> {
+    parse_scene_with_warnings(source, base_dir).map(|(scene, _)| scene)
+}
+
+/// Parses a scene like parse_scene, along with warnings of problems that parsing carried on past
+pub fn parse_scene_with_warnings(source: &str, base_dir: &Path) -> Result<(Scene, Vec<String>), SceneFileError> {
+    let desc: SceneDesc = toml::from_str(source).map_err(|e| SceneFileError::Syntax(e.to_string()))?;
+    let mut warnings = Vec::new();
+    let scene = desc.build(base_dir, &mut warnings)?;
+    Ok((scene, warnings))
+}
+
+/// Writes a scene out to a TOML scene file.
+pub fn save_scene<P: AsRef<Path>>(scene: &Scene, path: P) -> Result<(), SceneFileError> {
+    let path = path.as_ref();
+    let source = scene_to_string(scene)?;
+    fs::write(path, source).map_err(|e| SceneFileError::Io(path.to_path_buf(), e))
+}
+
+/// Writes a scene out as the contents of a TOML scene file.
+/// Meshes are written out in full, even if they were loaded from an OBJ file.
+/// Image textures refer to the path they were loaded from, so are best loaded through absolute paths if the file is
+/// written elsewhere. Images made in memory are written out with an empty path.
+pub fn scene_to_string(scene: &Scene) -> Result<String, SceneFileError> {
+    let desc = SceneDesc::from_scene(scene)?;
+    toml::to_string(&desc).map_err(|e| SceneFileError::Serialize(e.to_string()))
+}
+
+/// Contents of a scene file.
+/// Vectors and colors are written as [x, y, z] arrays.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneDesc {
+    pub color_background: [f64; 3],
+    pub color_ambient: [f64; 3],
+    pub bounce_limit: u32,
+    pub camera: CameraDesc,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub environment: Option<EnvironmentDesc>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub lights: Vec<LightDesc>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub shapes: Vec<ShapeDesc>
+}
+
+/// Camera at 'origin', facing along 'direction' or towards 'look_at'. Exactly one of the two must be given.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    pub origin: [f64; 3],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction: Option<[f64; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub look_at: Option<[f64; 3]>,
+    pub up: [f64; 3],
+    pub near_dist: f64,
+    pub far_dist: f64,
+    pub frust_width: f64,
+    pub frust_height: f64
+}
+
+/// Equirectangular image surrounding the scene, seen by rays that miss everything in place of 'color_background' and
+/// lighting the scene through 'samples' shadow rays per point. See Environment.
+/// The image is loaded relative to the scene file, and taken to hold sRGB colors unless 'srgb' says otherwise.
+/// Radiance .hdr and PFM images are always linear, and can hold a sun far brighter than the sky around it.
+/// Its colors are scaled by 'intensity', and it is turned by 'rotation' degrees about the y axis.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentDesc {
+    pub path: String,
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub intensity: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub rotation: f64,
+    #[serde(default = "default_environment_samples")]
+    pub samples: u32,
+    #[serde(default)]
+    pub filter: TextureFilter,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub srgb: Option<bool>
+}
+
+/// Light, a point light unless 'type' says otherwise.
+/// Rect lights need edges 'u' and 'v' leaving their center, triangle lights need edges 'u' and 'v' leaving their
+/// position, disk lights need 'normal' and 'radius', and sphere lights need 'radius'.
+/// These area lights may set the number of shadow 'samples' traced towards them.
+/// Directional lights need a 'direction' but no position, and spot lights need a 'direction', 'inner_angle' and
+/// 'outer_angle'.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightDesc {
+    #[serde(rename = "type", default, skip_serializing_if = "LightType::is_point")]
+    pub kind: LightType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub position: Option<[f64; 3]>,
+    pub color: [f64; 3],
+    pub brightness: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub u: Option<[f64; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<[f64; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal: Option<[f64; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub radius: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub direction: Option<[f64; 3]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inner_angle: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outer_angle: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub samples: Option<u32>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LightType {
+    #[default]
+    Point,
+    Rect,
+    Disk,
+    Sphere,
+    Triangle,
+    Directional,
+    Spot
+}
+
+impl LightType {
+    fn is_point(&self) -> bool { *self == LightType::Point }
+}
+
+/// Material, following the Phong model unless metallic or roughness is given, in which case it follows the
+/// microfacet model and reflectivity and exponent are unused.
+/// Materials with an 'emission' glow, and may go above 1 to light the scene more brightly.
+/// A 'texture' varies the color over the surface, replacing 'color'. Likewise, a 'reflectivity_texture' replaces
+/// 'reflectivity' with the mean of its color channels, while an 'exponent_texture' scales 'exponent' by it.
+/// A 'normal_map' or 'bump_map' bends the normal the surface is shaded with, to show detail the shape doesn't have.
+/// Bump maps raise the surface by the mean of their color channels times 'bump_height'. See Material.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDesc {
+    pub color: [f64; 3],
+    #[serde(default)]
+    pub reflectivity: f64,
+    #[serde(default = "default_exponent")]
+    pub exponent: f64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub transparency: f64,
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub ior: f64,
+    #[serde(default, skip_serializing_if = "is_zero_vector")]
+    pub absorption: [f64; 3],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metallic: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roughness: Option<f64>,
+    #[serde(default, skip_serializing_if = "is_zero_vector")]
+    pub emission: [f64; 3],
+    #[serde(default = "one", skip_serializing_if = "is_one")]
+    pub bump_height: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub texture: Option<TextureDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reflectivity_texture: Option<TextureDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exponent_texture: Option<TextureDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normal_map: Option<TextureDesc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bump_map: Option<TextureDesc>
+}
+
+/// Texture, tagged by its "type" field.
+/// Image textures load PNG, JPEG or GIF files, relative to the scene file. Unless 'srgb' says otherwise, images used
+/// as a 'texture' are taken to hold sRGB colors, and others to hold linear values. Radiance .hdr and PFM files are
+/// also loaded, and are always linear.
+/// Solid textures take a ramp of 'colors', spread evenly along it. Their 'octaves' default to 1, giving plain noise.
+/// See Texture.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum TextureDesc {
+    UvChecker {
+        scale: f64,
+        colors: [[f64; 3]; 2]
+    },
+    Image {
+        path: String,
+        #[serde(default)]
+        filter: TextureFilter,
+        #[serde(default)]
+        wrap: Wrap,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        srgb: Option<bool>
+    },
+    Checker {
+        scale: f64,
+        colors: [[f64; 3]; 2]
+    },
+    Noise {
+        scale: f64,
+        #[serde(default = "one_octave")]
+        octaves: u32,
+        colors: Vec<[f64; 3]>
+    },
+    Turbulence {
+        scale: f64,
+        #[serde(default = "one_octave")]
+        octaves: u32,
+        colors: Vec<[f64; 3]>
+    },
+    Marble {
+        scale: f64,
+        #[serde(default = "one_octave")]
+        octaves: u32,
+        turbulence: f64,
+        colors: Vec<[f64; 3]>
+    },
+    Wood {
+        scale: f64,
+        #[serde(default = "one_octave")]
+        octaves: u32,
+        turbulence: f64,
+        colors: Vec<[f64; 3]>
+    }
+}
+
+/// Shape, tagged by its "type" field.
+/// Shapes refer to materials by their name in the scene's 'materials' table.
+/// Angles are in degrees.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ShapeDesc {
+    Sphere {
+        center: [f64; 3],
+        radius: f64,
+        material: String
+    },
+    Floor {
+        position: [f64; 3],
+        material: String
+    },
+    Cuboid {
+        center: [f64; 3],
+        size: [f64; 3],
+        material: String
+    },
+    OrientedCuboid {
+        center: [f64; 3],
+        size: [f64; 3],
+        rotation: [f64; 3],
+        material: String
+    },
+    Cylinder {
+        center: [f64; 3],
+        axis: [f64; 3],
+        height: f64,
+        radius: f64,
+        material: String
+    },
+    Cone {
+        center: [f64; 3],
+        axis: [f64; 3],
+        height: f64,
+        base_radius: f64,
+        top_radius: f64,
+        material: String
+    },
+    Triangle {
+        vertices: [[f64; 3]; 3],
+        material: String
+    },
+    Quad {
+        center: [f64; 3],
+        u: [f64; 3],
+        v: [f64; 3],
+        material: String
+    },
+    Mesh {
+        position: [f64; 3],
+        positions: Vec<[f64; 3]>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        normals: Option<Vec<[f64; 3]>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        uvs: Option<Vec<[f64; 2]>>,
+        triangles: Vec<[usize; 3]>,
+        material: String
+    },
+
+    /// Every object in an OBJ file, using the materials from its MTL files rather than the scene's
+    Obj {
+        path: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        position: Option<[f64; 3]>
+    }
+}
+
+impl SceneDesc {
+
+    /// Describes an existing scene.
+    /// Fails if the scene holds a kind of shape or light that scene files can't describe.
+    pub fn from_scene(scene: &Scene) -> Result<SceneDesc, SceneFileError> {
+        let camera = &scene.camera;
+        let mut materials = MaterialNames::default();
+        let shapes = scene.shapes()
+            .iter()
+            .enumerate()
+            .map(|(i, shape)| ShapeDesc::from_shape(&**shape, &mut materials).ok_or_else(|| {
+                SceneFileError::Serialize(format!("shapes[{}] is a kind of shape scene files can't describe", i))
+            }))
+            .collect::<Result<_, _>>()?;
+        let lights = scene.lights
+            .iter()
+            .enumerate()
+            .map(|(i, light)| LightDesc::from_light(light).ok_or_else(|| {
+                SceneFileError::Serialize(format!("lights[{}] is a kind of light scene files can't describe", i))
+            }))
+            .collect::<Result<_, _>>()?;
+        Ok(SceneDesc {
+            color_background: array(&scene.color_background),
+            color_ambient: array(&scene.color_ambient),
+            bounce_limit: scene.bounce_limit,
+            camera: CameraDesc {
+                origin: array(&camera.eye.origin),
+                direction: Some(array(&camera.eye.dir)),
+                look_at: None,
+                up: array(&camera.up),
+                near_dist: camera.near_dist,
+                far_dist: camera.far_dist,
+                frust_width: camera.frust_width,
+                frust_height: camera.frust_height
+            },
+            environment: scene.environment.as_ref().map(EnvironmentDesc::from_environment),
+            lights,
+            materials: materials.descs,
+            shapes
+        })
+    }
+
+    /// Validates the description and builds the scene it describes.
+    /// Paths are relative to 'base_dir'. Problems that building carries on past are added to 'warnings'.
+    pub fn build(&self, base_dir: &Path, warnings: &mut Vec<String>) -> Result<Scene, SceneFileError> {
+        let camera = self.camera.build()?;
+        check_color("color_background", &self.color_background)?;
+        check_color("color_ambient", &self.color_ambient)?;
+
+        let mut lights = Vec::new();
+        for (i, light) in self.lights.iter().enumerate() {
+            lights.push(light.build(&format!("lights[{}]", i))?);
+        }
+
+        let mut materials = HashMap::new();
+        for (name, material) in &self.materials {
+            let material = material.build(&format!("materials.{}", name), base_dir)?;
+            materials.insert(name.clone(), Arc::new(material));
+        }
+
+        let mut shapes = Vec::new();
+        for (i, shape) in self.shapes.iter().enumerate() {
+            shape.build(&format!("shapes[{}]", i), base_dir, &materials, &mut shapes, warnings)?;
+        }
+
+        let mut scene = Scene::new(
+            vector(&self.color_background),
+            vector(&self.color_ambient),
+            camera,
+            shapes,
+            lights,
+            self.bounce_limit
+        );
+        if let Some(ref environment) = self.environment {
+            scene.environment = Some(environment.build(base_dir)?);
+        }
+        Ok(scene)
+    }
+}
+
+impl EnvironmentDesc {
+
+    fn build(&self, base_dir: &Path) -> Result<Environment, SceneFileError> {
+        check_non_negative("environment.intensity", self.intensity)?;
+        if !self.rotation.is_finite() { return Err(invalid("environment.rotation", "must be a number")); }
+        let srgb = self.srgb.unwrap_or(true);
+        let image = ImageTexture::load(base_dir.join(&self.path), self.filter, Wrap::Repeat, srgb)
+            .map_err(|e| SceneFileError::Texture(String::from("environment.path"), e))?;
+        Ok(Environment::new(image, self.intensity, self.rotation.to_radians(), self.samples))
+    }
+
+    fn from_environment(environment: &Environment) -> EnvironmentDesc {
+        let image = environment.image();
+        EnvironmentDesc {
+            path: image.path.as_ref().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default(),
+            intensity: environment.intensity,
+            rotation: environment.rotation.to_degrees(),
+            samples: environment.samples,
+            filter: image.filter,
+            srgb: Some(image.srgb)
+        }
+    }
+}
+
+impl CameraDesc {
+
+    fn build(&self) -> Result<Camera, SceneFileError> {
+        let origin = vector(&self.origin);
+        let dir = match (self.direction, self.look_at) {
+            (Some(dir), None) => vector(&dir),
+            (None, Some(point)) => vector(&point) - origin,
+            _ => return Err(invalid("camera", "exactly one of 'direction' and 'look_at' must be given"))
+        };
+        if dir.len_squared() == 0.0 { return Err(invalid("camera.direction", "camera must face somewhere")); }
+        if vector(&self.up).cross(&dir).len_squared() == 0.0 {
+            return Err(invalid("camera.up", "must be non-zero and not parallel to the camera direction"));
+        }
+        check_positive("camera.near_dist", self.near_dist)?;
+        check_positive("camera.far_dist", self.far_dist)?;
+        check_positive("camera.frust_width", self.frust_width)?;
+        check_positive("camera.frust_height", self.frust_height)?;
+        Ok(Camera {
+            up: vector(&self.up),
+            near_dist: self.near_dist,
+            far_dist: self.far_dist,
+            eye: Ray::new(origin, dir),
+            frust_width: self.frust_width,
+            frust_height: self.frust_height
+        })
+    }
+}
+
+impl LightDesc {
+
+    /// Number of shadow samples taken of area lights, unless given
+    const DEFAULT_SAMPLES: u32 = 16;
+
+    fn build(&self, field: &str) -> Result<Light, SceneFileError> {
+        let f = |name: &str| format!("{}.{}", field, name);
+        check_color(&f("color"), &self.color)?;
+        check_non_negative(&f("brightness"), self.brightness)?;
+
+        // Fields the light's type needs must be given, and others must not be
+        let kind_name = format!("{:?}", self.kind).to_lowercase();
+        let required = |name: &str, value: Option<[f64; 3]>| -> Result<[f64; 3], SceneFileError> {
+            value.ok_or_else(|| invalid(&f(name), &format!("must be given for {} lights", kind_name)))
+        };
+        let required_number = |name: &str, value: Option<f64>| -> Result<f64, SceneFileError> {
+            value.ok_or_else(|| invalid(&f(name), &format!("must be given for {} lights", kind_name)))
+        };
+        let kind = match self.kind {
+            LightType::Point => LightKind::Point,
+            LightType::Rect | LightType::Triangle => {
+                let (u, v) = (required("u", self.u)?, required("v", self.v)?);
+                check_edges(&f("v"), &u, &v)?;
+                let (u, v) = (vector(&u), vector(&v));
+                if self.kind == LightType::Rect { LightKind::Rect { u, v } } else { LightKind::Triangle { u, v } }
+            },
+            LightType::Disk => {
+                let normal = required("normal", self.normal)?;
+                check_axis(&f("normal"), &normal)?;
+                let radius = required_number("radius", self.radius)?;
+                check_positive(&f("radius"), radius)?;
+                LightKind::Disk { normal: vector(&normal), radius }
+            },
+            LightType::Sphere => {
+                let radius = required_number("radius", self.radius)?;
+                check_positive(&f("radius"), radius)?;
+                LightKind::Sphere { radius }
+            },
+            LightType::Directional => {
+                let direction = required("direction", self.direction)?;
+                check_axis(&f("direction"), &direction)?;
+                LightKind::Directional { direction: vector(&direction) }
+            },
+            LightType::Spot => {
+                let direction = required("direction", self.direction)?;
+                check_axis(&f("direction"), &direction)?;
+                let inner_angle = required_number("inner_angle", self.inner_angle)?;
+                let outer_angle = required_number("outer_angle", self.outer_angle)?;
+                if !(0.0..=180.0).contains(&outer_angle) {
+                    return Err(invalid(&f("outer_angle"), &format!("must be between 0 and 180, found {}", outer_angle)));
+                }
+                if !(0.0..=outer_angle).contains(&inner_angle) {
+                    return Err(invalid(&f("inner_angle"), &format!("must be between 0 and outer_angle, found {}", inner_angle)));
+                }
+                LightKind::Spot {
+                    direction: vector(&direction),
+                    inner_angle: inner_angle.to_radians(),
+                    outer_angle: outer_angle.to_radians()
+                }
+            }
+        };
+
+        let used: &[&str] = match self.kind {
+            LightType::Point => &["position"],
+            LightType::Rect => &["position", "u", "v", "samples"],
+            LightType::Disk => &["position", "normal", "radius", "samples"],
+            LightType::Sphere => &["position", "radius", "samples"],
+            LightType::Triangle => &["position", "u", "v", "samples"],
+            LightType::Directional => &["direction"],
+            LightType::Spot => &["position", "direction", "inner_angle", "outer_angle"]
+        };
+        let given = [
+            ("position", self.position.is_some()),
+            ("u", self.u.is_some()),
+            ("v", self.v.is_some()),
+            ("normal", self.normal.is_some()),
+            ("radius", self.radius.is_some()),
+            ("direction", self.direction.is_some()),
+            ("inner_angle", self.inner_angle.is_some()),
+            ("outer_angle", self.outer_angle.is_some()),
+            ("samples", self.samples.is_some())
+        ];
+        if let Some(&(name, _)) = given.iter().find(|&&(name, given)| given && !used.contains(&name)) {
+            return Err(invalid(&f(name), &format!("is not used by {} lights", kind_name)));
+        }
+
+        let position = match kind {
+            LightKind::Directional { .. } => Vector::new(0.0, 0.0, 0.0),
+            _ => vector(&required("position", self.position)?)
+        };
+        let samples = match self.samples {
+            Some(0) => return Err(invalid(&f("samples"), "must be greater than 0, found 0")),
+            Some(samples) => samples,
+            None if used.contains(&"samples") => LightDesc::DEFAULT_SAMPLES,
+            None => 1
+        };
+        Ok(Light::new(position, vector(&self.color), self.brightness, kind, samples))
+    }
+
+    /// Description of a light, or None for lights standing in for shapes made of many triangles
+    fn from_light(light: &Light) -> Option<LightDesc> {
+        let mut desc = LightDesc {
+            kind: LightType::Point,
+            position: Some(array(&light.position)),
+            color: array(&light.color),
+            brightness: light.brightness,
+            u: None,
+            v: None,
+            normal: None,
+            radius: None,
+            direction: None,
+            inner_angle: None,
+            outer_angle: None,
+            samples: if light.is_area() { Some(light.samples) } else { None }
+        };
+        match light.kind {
+            LightKind::Point => {},
+            LightKind::Rect { u, v } => {
+                desc.kind = LightType::Rect;
+                desc.u = Some(array(&u));
+                desc.v = Some(array(&v));
+            },
+            LightKind::Disk { normal, radius } => {
+                desc.kind = LightType::Disk;
+                desc.normal = Some(array(&normal));
+                desc.radius = Some(radius);
+            },
+            LightKind::Sphere { radius } => {
+                desc.kind = LightType::Sphere;
+                desc.radius = Some(radius);
+            },
+            LightKind::Triangle { u, v } => {
+                desc.kind = LightType::Triangle;
+                desc.u = Some(array(&u));
+                desc.v = Some(array(&v));
+            },
+            LightKind::Directional { direction } => {
+                desc.kind = LightType::Directional;
+                desc.position = None;
+                desc.direction = Some(array(&direction));
+            },
+            LightKind::Spot { direction, inner_angle, outer_angle } => {
+                desc.kind = LightType::Spot;
+                desc.direction = Some(array(&direction));
+                desc.inner_angle = Some(inner_angle.to_degrees());
+                desc.outer_angle = Some(outer_angle.to_degrees());
+            },
+            LightKind::Triangles(_) => return None
+        }
+        Some(desc)
+    }
+}
+
+impl MaterialDesc {
+
+    fn build(&self, field: &str, base_dir: &Path) -> Result<Material, SceneFileError> {
+        check_color(&format!("{}.color", field), &self.color)?;
+        check_fraction(&format!("{}.reflectivity", field), self.reflectivity)?;
+        check_non_negative(&format!("{}.exponent", field), self.exponent)?;
+        check_fraction(&format!("{}.transparency", field), self.transparency)?;
+        check_positive(&format!("{}.ior", field), self.ior)?;
+        check_color(&format!("{}.absorption", field), &self.absorption)?;
+        check_color(&format!("{}.emission", field), &self.emission)?;
+        let texture = |name: &str, texture: &Option<TextureDesc>, srgb: bool| match *texture {
+            Some(ref texture) => texture.build(&format!("{}.{}", field, name), base_dir, srgb).map(Some),
+            None => Ok(None)
+        };
+
+        // Either of metallic or roughness switches to the microfacet model
+        let microfacet = match (self.metallic, self.roughness) {
+            (None, None) => None,
+            (metallic, roughness) => {
+                let metallic = metallic.unwrap_or(0.0);
+                let roughness = roughness.unwrap_or(0.5);
+                check_fraction(&format!("{}.metallic", field), metallic)?;
+                check_fraction(&format!("{}.roughness", field), roughness)?;
+                Some(Microfacet::new(metallic, roughness))
+            }
+        };
+        Ok(Material {
+            transparency: self.transparency,
+            ior: self.ior,
+            absorption: vector(&self.absorption),
+            microfacet,
+            emission: vector(&self.emission),
+            texture: texture("texture", &self.texture, true)?,
+            reflectivity_texture: texture("reflectivity_texture", &self.reflectivity_texture, false)?,
+            exponent_texture: texture("exponent_texture", &self.exponent_texture, false)?,
+            normal_map: texture("normal_map", &self.normal_map, false)?,
+            bump_map: texture("bump_map", &self.bump_map, false)?,
+            bump_height: self.bump_height,
+            ..Material::new(vector(&self.color), self.reflectivity, self.exponent)
+        })
+    }
+
+    fn from_material(material: &Material) -> MaterialDesc {
+        MaterialDesc {
+            color: array(&material.color),
+            reflectivity: material.reflectivity,
+            exponent: material.exponent,
+            transparency: material.transparency,
+            ior: material.ior,
+            absorption: array(&material.absorption),
+            metallic: material.microfacet.map(|m| m.metallic),
+            roughness: material.microfacet.map(|m| m.roughness),
+            emission: array(&material.emission),
+            bump_height: material.bump_height,
+            texture: material.texture.as_ref().map(TextureDesc::from_texture),
+            reflectivity_texture: material.reflectivity_texture.as_ref().map(TextureDesc::from_texture),
+            exponent_texture: material.exponent_texture.as_ref().map(TextureDesc::from_texture),
+            normal_map: material.normal_map.as_ref().map(TextureDesc::from_texture),
+            bump_map: material.bump_map.as_ref().map(TextureDesc::from_texture)
+        }
+    }
+}
+
+impl TextureDesc {
+
+    /// Paths are relative to 'base_dir'. Images are taken to hold sRGB colors if 'srgb' is set, unless the
+    /// description says otherwise.
+    fn build(&self, field: &str, base_dir: &Path, srgb: bool) -> Result<Texture, SceneFileError> {
+        let f = |name: &str| format!("{}.{}", field, name);
+        match *self {
+            TextureDesc::UvChecker { scale, colors } => {
+                check_positive(&f("scale"), scale)?;
+                check_color(&f("colors[0]"), &colors[0])?;
+                check_color(&f("colors[1]"), &colors[1])?;
+                Ok(Texture::UvChecker { scale, colors: [vector(&colors[0]), vector(&colors[1])] })
+            },
+            TextureDesc::Image { ref path, filter, wrap, srgb: given_srgb } => {
+                let srgb = given_srgb.unwrap_or(srgb);
+                let image = ImageTexture::load(base_dir.join(path), filter, wrap, srgb)
+                    .map_err(|e| SceneFileError::Texture(f("path"), e))?;
+                Ok(Texture::Image(image))
+            },
+            TextureDesc::Checker { scale, colors } => {
+                check_positive(&f("scale"), scale)?;
+                check_color(&f("colors[0]"), &colors[0])?;
+                check_color(&f("colors[1]"), &colors[1])?;
+                Ok(Texture::Checker { scale, colors: [vector(&colors[0]), vector(&colors[1])] })
+            },
+            TextureDesc::Noise { scale, octaves, ref colors } => {
+                check_positive(&f("scale"), scale)?;
+                check_octaves(&f("octaves"), octaves)?;
+                Ok(Texture::Noise { scale, octaves, ramp: ramp(&f("colors"), colors)? })
+            },
+            TextureDesc::Turbulence { scale, octaves, ref colors } => {
+                check_positive(&f("scale"), scale)?;
+                check_octaves(&f("octaves"), octaves)?;
+                Ok(Texture::Turbulence { scale, octaves, ramp: ramp(&f("colors"), colors)? })
+            },
+            TextureDesc::Marble { scale, octaves, turbulence, ref colors } => {
+                check_positive(&f("scale"), scale)?;
+                check_octaves(&f("octaves"), octaves)?;
+                check_non_negative(&f("turbulence"), turbulence)?;
+                Ok(Texture::Marble { scale, octaves, turbulence, ramp: ramp(&f("colors"), colors)? })
+            },
+            TextureDesc::Wood { scale, octaves, turbulence, ref colors } => {
+                check_positive(&f("scale"), scale)?;
+                check_octaves(&f("octaves"), octaves)?;
+                check_non_negative(&f("turbulence"), turbulence)?;
+                Ok(Texture::Wood { scale, octaves, turbulence, ramp: ramp(&f("colors"), colors)? })
+            }
+        }
+    }
+
+    fn from_texture(texture: &Texture) -> TextureDesc {
+        match *texture {
+            Texture::UvChecker { scale, colors } => {
+                TextureDesc::UvChecker { scale, colors: [array(&colors[0]), array(&colors[1])] }
+            },
+            Texture::Image(ref image) => TextureDesc::Image {
+                path: image.path.as_ref().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default(),
+                filter: image.filter,
+                wrap: image.wrap,
+                srgb: Some(image.srgb)
+            },
+            Texture::Checker { scale, colors } => {
+                TextureDesc::Checker { scale, colors: [array(&colors[0]), array(&colors[1])] }
+            },
+            Texture::Noise { scale, octaves, ref ramp } => {
+                TextureDesc::Noise { scale, octaves, colors: ramp.colors.iter().map(array).collect() }
+            },
+            Texture::Turbulence { scale, octaves, ref ramp } => {
+                TextureDesc::Turbulence { scale, octaves, colors: ramp.colors.iter().map(array).collect() }
+            },
+            Texture::Marble { scale, octaves, turbulence, ref ramp } => {
+                TextureDesc::Marble { scale, octaves, turbulence, colors: ramp.colors.iter().map(array).collect() }
+            },
+            Texture::Wood { scale, octaves, turbulence, ref ramp } => {
+                TextureDesc::Wood { scale, octaves, turbulence, colors: ramp.colors.iter().map(array).collect() }
+            }
+        }
+    }
+}
+
+/// Names the materials of a scene being written out, so that shapes sharing a material refer to it by the same name.
+#[derive(Debug, Default)]
+struct MaterialNames {
+    names: HashMap<*const Material, String>,
+    descs: BTreeMap<String, MaterialDesc>
+}
+
+impl MaterialNames {
+
+    /// Name of the material, registering it if it hasn't been seen before
+    fn name(&mut self, material: &Arc<Material>) -> String {
+        let descs = &mut self.descs;
+        self.names
+            .entry(Arc::as_ptr(material))
+            .or_insert_with(|| {
+                let name = format!("material_{}", descs.len());
+                descs.insert(name.clone(), MaterialDesc::from_material(material));
+                name
+            })
+            .clone()
+    }
+}
+
+impl ShapeDesc {
+
+    /// Validates the description and adds the shapes it describes to 'shapes'.
+    /// 'field' is the path of this shape in the file, used in errors and warnings.
+    fn build(
+        &self,
+        field: &str,
+        base_dir: &Path,
+        materials: &HashMap<String, Arc<Material>>,
+        shapes: &mut Vec<Box<dyn Shape>>,
+        warnings: &mut Vec<String>
+    ) -> Result<(), SceneFileError> {
+        let f = |name: &str| format!("{}.{}", field, name);
+        let find_material = |name: &String| -> Result<Arc<Material>, SceneFileError> {
+            materials
+                .get(name)
+                .cloned()
+                .ok_or_else(|| invalid(&f("material"), &format!("undefined material '{}'", name)))
+        };
+        match *self {
+            ShapeDesc::Sphere { center, radius, ref material } => {
+                check_positive(&f("radius"), radius)?;
+                shapes.push(Box::new(Sphere {
+                    center: vector(&center),
+                    radius,
+                    material: find_material(material)?
+                }));
+            },
+            ShapeDesc::Floor { position, ref material } => {
+                shapes.push(Box::new(Floor {
+                    position: vector(&position),
+                    material: find_material(material)?
+                }));
+            },
+            ShapeDesc::Cuboid { center, size, ref material } => {
+                check_size(&f("size"), &size)?;
+                shapes.push(Box::new(Cuboid {
+                    center: vector(&center),
+                    size: vector(&size),
+                    material: find_material(material)?
+                }));
+            },
+            ShapeDesc::OrientedCuboid { center, size, rotation, ref material } => {
+                check_size(&f("size"), &size)?;
+                let radians = vector(&rotation) * (::std::f64::consts::PI / 180.0);
+                shapes.push(Box::new(OrientedCuboid::from_euler(
+                    vector(&center),
+                    vector(&size),
+                    radians,
+                    find_material(material)?
+                )));
+            },
+            ShapeDesc::Cylinder { center, axis, height, radius, ref material } => {
+                check_axis(&f("axis"), &axis)?;
+                check_positive(&f("height"), height)?;
+                check_positive(&f("radius"), radius)?;
+                let material = find_material(material)?;
+                shapes.push(Box::new(Cylinder::new(vector(&center), vector(&axis), height, radius, material)));
+            },
+            ShapeDesc::Cone { center, axis, height, base_radius, top_radius, ref material } => {
+                check_axis(&f("axis"), &axis)?;
+                check_positive(&f("height"), height)?;
+                check_non_negative(&f("base_radius"), base_radius)?;
+                check_non_negative(&f("top_radius"), top_radius)?;
+                if base_radius == 0.0 && top_radius == 0.0 {
+                    return Err(invalid(&f("base_radius"), "base_radius and top_radius can't both be 0"));
+                }
+                shapes.push(Box::new(Cone::new(
+                    vector(&center),
+                    vector(&axis),
+                    height,
+                    base_radius,
+                    top_radius,
+                    find_material(material)?
+                )));
+            },
+            ShapeDesc::Triangle { vertices, ref material } => {
+                shapes.push(Box::new(Triangle {
+                    vertices: [vector(&vertices[0]), vector(&vertices[1]), vector(&vertices[2])],
+                    material: find_material(material)?
+                }));
+            },
+            ShapeDesc::Quad { center, u, v, ref material } => {
+                check_edges(&f("v"), &u, &v)?;
+                shapes.push(Box::new(Quad {
+                    center: vector(&center),
+                    u: vector(&u),
+                    v: vector(&v),
+                    material: find_material(material)?
+                }));
+            },
+            ShapeDesc::Mesh { position, ref positions, ref normals, ref uvs, ref triangles, ref material } => {
+                if let Some(ref normals) = *normals {
+                    if normals.len() != positions.len() {
+                        return Err(invalid(&f("normals"), "must hold one normal per position"));
+                    }
+                }
+                if let Some(ref uvs) = *uvs {
+                    if uvs.len() != positions.len() {
+                        return Err(invalid(&f("uvs"), "must hold one uv per position"));
+                    }
+                }
+                for (i, tri) in triangles.iter().enumerate() {
+                    if tri.iter().any(|&index| index >= positions.len()) {
+                        return Err(invalid(&format!("{}.triangles[{}]", field, i), "index out of range"));
+                    }
+                }
+                let mut mesh = Mesh::new(
+                    positions.iter().map(vector).collect(),
+                    normals.as_ref().map(|n| n.iter().map(vector).collect()),
+                    uvs.clone(),
+                    triangles.clone(),
+                    find_material(material)?
+                );
+                mesh.set_position(&vector(&position));
+                shapes.push(Box::new(mesh));
+            },
+            ShapeDesc::Obj { ref path, position } => {
+                let file = obj::load_obj(base_dir.join(path)).map_err(|e| SceneFileError::Obj(f("path"), e))?;
+                warnings.extend(file.warnings.iter().map(|warning| format!("{}: {}", f("path"), warning)));
+                for object in file.objects {
+                    let mut mesh = object.mesh;
+                    if let Some(position) = position {
+                        mesh.set_position(&vector(&position));
+                    }
+                    shapes.push(Box::new(mesh));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Describes an existing shape, naming the materials it refers to in 'materials'.
+    /// Returns None for kinds of shape that scene files have no way to describe.
+    fn from_shape(shape: &dyn Shape, materials: &mut MaterialNames) -> Option<ShapeDesc> {
+        let shape: &dyn Any = shape;
+        if let Some(sphere) = shape.downcast_ref::<Sphere>() {
+            return Some(ShapeDesc::Sphere {
+                center: array(&sphere.center),
+                radius: sphere.radius,
+                material: materials.name(&sphere.material)
+            });
+        }
+        if let Some(floor) = shape.downcast_ref::<Floor>() {
+            return Some(ShapeDesc::Floor {
+                position: array(&floor.position),
+                material: materials.name(&floor.material)
+            });
+        }
+        if let Some(cuboid) = shape.downcast_ref::<Cuboid>() {
+            return Some(ShapeDesc::Cuboid {
+                center: array(&cuboid.center),
+                size: array(&cuboid.size),
+                material: materials.name(&cuboid.material)
+            });
+        }
+        if let Some(cuboid) = shape.downcast_ref::<OrientedCuboid>() {
+            return Some(ShapeDesc::OrientedCuboid {
+                center: array(&cuboid.center),
+                size: array(&cuboid.size),
+                rotation: array(&(cuboid.orientation.euler() * (180.0 / ::std::f64::consts::PI))),
+                material: materials.name(&cuboid.material)
+            });
+        }
+        if let Some(cylinder) = shape.downcast_ref::<Cylinder>() {
+            return Some(ShapeDesc::Cylinder {
+                center: array(&cylinder.center),
+                axis: array(&cylinder.axis),
+                height: cylinder.height,
+                radius: cylinder.radius,
+                material: materials.name(&cylinder.material)
+            });
+        }
+        if let Some(cone) = shape.downcast_ref::<Cone>() {
+            return Some(ShapeDesc::Cone {
+                center: array(&cone.center),
+                axis: array(&cone.axis),
+                height: cone.height,
+                base_radius: cone.base_radius,
+                top_radius: cone.top_radius,
+                material: materials.name(&cone.material)
+            });
+        }
+        if let Some(triangle) = shape.downcast_ref::<Triangle>() {
+            let [a, b, c] = triangle.vertices;
+            return Some(ShapeDesc::Triangle {
+                vertices: [array(&a), array(&b), array(&c)],
+                material: materials.name(&triangle.material)
+            });
+        }
+        if let Some(quad) = shape.downcast_ref::<Quad>() {
+            return Some(ShapeDesc::Quad {
+                center: array(&quad.center),
+                u: array(&quad.u),
+                v: array(&quad.v),
+                material: materials.name(&quad.material)
+            });
+        }
+        if let Some(mesh) = shape.downcast_ref::<Mesh>() {
+            return Some(ShapeDesc::Mesh {
+                position: array(&mesh.get_position()),
+                positions: mesh.positions().iter().map(array).collect(),
+                normals: mesh.normals().map(|n| n.iter().map(array).collect()),
+                uvs: mesh.uvs().map(|uvs| uvs.to_vec()),
+                triangles: mesh.triangles().to_vec(),
+                material: materials.name(&mesh.material)
+            });
+        }
+        None
+    }
+}
+
+/// Converts [x, y, z] array to Vector
+pub fn vector(array: &[f64; 3]) -> Vector {
+    Vector::new(array[0], array[1], array[2])
+}
+
+/// Converts Vector to [x, y, z] array
+pub fn array(vector: &Vector) -> [f64; 3] {
+    [vector.x, vector.y, vector.z]
+}
+
+fn invalid(field: &str, message: &str) -> SceneFileError {
+    SceneFileError::Invalid { field: field.to_string(), message: message.to_string() }
+}
+
+fn check_positive(field: &str, value: f64) -> Result<(), SceneFileError> {
+    if value > 0.0 && value.is_finite() { Ok(()) }
+    else { Err(invalid(field, &format!("must be greater than 0, found {}", value))) }
+}
+
+fn check_non_negative(field: &str, value: f64) -> Result<(), SceneFileError> {
+    if value >= 0.0 && value.is_finite() { Ok(()) }
+    else { Err(invalid(field, &format!("must not be negative, found {}", value))) }
+}
+
+fn check_fraction(field: &str, value: f64) -> Result<(), SceneFileError> {
+    if (0.0..=1.0).contains(&value) { Ok(()) }
+    else { Err(invalid(field, &format!("must be between 0 and 1, found {}", value))) }
+}
+
+fn check_color(field: &str, color: &[f64; 3]) -> Result<(), SceneFileError> {
+    if color.iter().all(|&c| c >= 0.0 && c.is_finite()) { Ok(()) }
+    else { Err(invalid(field, "color components must not be negative")) }
+}
+
+fn check_size(field: &str, size: &[f64; 3]) -> Result<(), SceneFileError> {
+    if size.iter().all(|&c| c > 0.0 && c.is_finite()) { Ok(()) }
+    else { Err(invalid(field, "every dimension must be greater than 0")) }
+}
+
+fn check_axis(field: &str, axis: &[f64; 3]) -> Result<(), SceneFileError> {
+    if vector(axis).len_squared() > 0.0 { Ok(()) }
+    else { Err(invalid(field, "axis must not be zero")) }
+}
+
+fn check_edges(field: &str, u: &[f64; 3], v: &[f64; 3]) -> Result<(), SceneFileError> {
+    if vector(u).cross(&vector(v)).len_squared() > 0.0 { Ok(()) }
+    else { Err(invalid(field, "edges must be non-zero and not parallel")) }
+}
+
+fn check_octaves(field: &str, octaves: u32) -> Result<(), SceneFileError> {
+    if (1..=16).contains(&octaves) { Ok(()) }
+    else { Err(invalid(field, &format!("must be between 1 and 16, found {}", octaves))) }
+}
+
+/// Color ramp through 'colors', of which there must be at least one
+fn ramp(field: &str, colors: &[[f64; 3]]) -> Result<ColorRamp, SceneFileError> {
+    if colors.is_empty() { return Err(invalid(field, "must hold at least one color")); }
+    for (i, color) in colors.iter().enumerate() {
+        check_color(&format!("{}[{}]", field, i), color)?;
+    }
+    Ok(ColorRamp::new(colors.iter().map(vector).collect()))
+}
+
+fn one() -> f64 { 1.0 }
+fn one_octave() -> u32 { 1 }
+fn default_environment_samples() -> u32 { LightDesc::DEFAULT_SAMPLES }
+fn default_exponent() -> f64 { Material::default().exponent }
+fn is_zero(value: &f64) -> bool { *value == 0.0 }
+fn is_one(value: &f64) -> bool { *value == 1.0 }
+fn is_zero_vector(value: &[f64; 3]) -> bool { value.iter().all(|&c| c == 0.0) }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use geom::Intersection;
+
+    #[test]
+    fn example_scenes_survive_a_round_trip() {
+        let mut count = 0;
+        for entry in fs::read_dir("scenes").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|e| e != "toml") { continue; }
+
+            // Written paths already include the scene's directory, so are read back relative to the working one
+            let written = scene_to_string(&load_scene(&path).unwrap()).unwrap();
+            let reread = parse_scene(&written, Path::new("")).unwrap();
+            assert_eq!(written, scene_to_string(&reread).unwrap(), "{} changed on a round trip", path.display());
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    #[test]
+    fn round_trip_keeps_values() {
+        let source = r#"
+            color_background = [0.1, 0.2, 0.3]
+            color_ambient = [0.0, 0.0, 0.0]
+            bounce_limit = 4
+
+            [camera]
+            origin = [0.0, 1.0, 5.0]
+            look_at = [0.0, 0.0, 0.0]
+            up = [0.0, 1.0, 0.0]
+            near_dist = 1.0
+            far_dist = 100.0
+            frust_width = 1.6
+            frust_height = 0.9
+
+            [materials.red]
+            color = [1.0, 0.0, 0.0]
+            reflectivity = 0.25
+            exponent = 20.0
+
+            [[shapes]]
+            type = "sphere"
+            center = [1.0, 2.0, 3.0]
+            radius = 0.5
+            material = "red"
+
+            [[shapes]]
+            type = "oriented_cuboid"
+            center = [0.0, 0.0, 0.0]
+            size = [1.0, 2.0, 3.0]
+            rotation = [10.0, 20.0, 30.0]
+            material = "red"
+        "#;
+        let scene = parse_scene(source, Path::new("")).unwrap();
+        let reread = parse_scene(&scene_to_string(&scene).unwrap(), Path::new("")).unwrap();
+        assert_eq!(reread.bounce_limit, 4);
+        assert_eq!(reread.color_background.y, 0.2);
+        assert_eq!(reread.shapes().len(), 2);
+
+        let desc = SceneDesc::from_scene(&reread).unwrap();
+        assert_eq!(desc.materials.len(), 1, "shapes sharing a material should still share it");
+        match desc.shapes[0] {
+            ShapeDesc::Sphere { center, radius, .. } => assert_eq!((center, radius), ([1.0, 2.0, 3.0], 0.5)),
+            ref other => panic!("expected a sphere, found {:?}", other)
+        }
+        match desc.shapes[1] {
+            ShapeDesc::OrientedCuboid { rotation, .. } => {
+                for (a, b) in rotation.iter().zip(&[10.0, 20.0, 30.0]) { assert!((a - b).abs() < 1e-9); }
+            },
+            ref other => panic!("expected an oriented cuboid, found {:?}", other)
+        }
+    }
+
+    #[test]
+    fn reports_invalid_fields() {
+        let source = "color_background = [0.0, 0.0, 0.0]\ncolor_ambient = [0.0, 0.0, 0.0]\nbounce_limit = 1\n\
+            [camera]\norigin = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\nup = [0.0, 1.0, 0.0]\nnear_dist = 1.0\n\
+            far_dist = 10.0\nfrust_width = 1.0\nfrust_height = 1.0\n\
+            [materials.m]\ncolor = [1.0, 1.0, 1.0]\nreflectivity = 0.0\nexponent = 1.0\n\
+            [[shapes]]\ntype = \"sphere\"\ncenter = [0.0, 0.0, 0.0]\nradius = -1.0\nmaterial = \"m\"\n";
+        match parse_scene(source, Path::new("")) {
+            Err(SceneFileError::Invalid { ref field, .. }) => assert_eq!(field, "shapes[0].radius"),
+            other => panic!("expected an invalid radius, found {:?}", other.map(|_| ()))
+        }
+    }
+
+    #[test]
+    fn passes_on_obj_warnings() {
+        let dir = ::std::env::temp_dir().join(format!("ray_tracer_obj_warnings_{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("model.obj"), "v 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl missing\nf 1 2 3\n").unwrap();
+        let source = "color_background = [0.0, 0.0, 0.0]\ncolor_ambient = [0.0, 0.0, 0.0]\nbounce_limit = 1\n\
+            [camera]\norigin = [0.0, 0.0, 5.0]\nlook_at = [0.0, 0.0, 0.0]\nup = [0.0, 1.0, 0.0]\nnear_dist = 1.0\n\
+            far_dist = 10.0\nfrust_width = 1.0\nfrust_height = 1.0\n\
+            [[shapes]]\ntype = \"obj\"\npath = \"model.obj\"\n";
+        let result = parse_scene_with_warnings(source, &dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let (scene, warnings) = result.unwrap();
+        assert_eq!(scene.shapes().len(), 1);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("shapes[0].path: "), "{}", warnings[0]);
+        assert!(warnings[0].ends_with("model.obj:4: undefined material 'missing', using the default"), "{}", warnings[0]);
+    }
+
+    /// Shape the scene file format knows nothing about
+    #[derive(Debug)]
+    struct Unknown;
+
+    impl Shape for Unknown {
+        fn set_position(&mut self, _: &Vector) {}
+        fn get_position(&self) -> Vector { Vector::new(0.0, 0.0, 0.0) }
+        fn bounds(&self) -> ::geom::Aabb { ::geom::Aabb::infinite() }
+        fn intersect(&self, _: &Ray) -> Option<Intersection<'_>> { None }
+    }
+
+    #[test]
+    fn refuses_to_write_unknown_shapes() {
+        let mut scene = parse_scene(include_str!("../scenes/shapes.toml"), Path::new("scenes")).unwrap();
+        scene.add_shape(Box::new(Unknown));
+        assert!(matches!(scene_to_string(&scene), Err(SceneFileError::Serialize(_))));
+    }
+}
@@ -1,24 +1,39 @@
+use std::any::Any;
 use std::fmt::Debug;
-use geom::{Vector, Ray, Intersection};
+use std::f64::consts::PI;
+use geom::{Vector, Ray, Aabb, Basis, Intersection};
+use material::Material;
+use light::{Light, LightKind, SURFACE_SAMPLES};
+use std::sync::Arc;
 
 const EPSILON: f64 = 0.000001;
 
-pub trait Shape : Debug {
+/// Shapes are shared between render threads, so they must be Send + Sync.
+/// They are Any so that code needing to know the concrete type, such as the scene file writer, can downcast them.
+pub trait Shape : Any + Debug + Send + Sync {
     fn set_position(&mut self, pos: &Vector);
     fn get_position(&self) -> Vector;
-    fn intersect(&self, ray: &Ray) -> Option<Intersection>;
+
+    /// Box enclosing the shape. Shapes of infinite extent return Aabb::infinite().
+    fn bounds(&self) -> Aabb;
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>>;
     fn intersects(&self, ray: &Ray) -> bool {
         self.intersect(ray).is_some()
     }
+
+    /// Lights standing in for the shape's surface, if its material glows.
+    /// Shapes that can't be sampled as lights return none, and only show their glow to rays that hit them.
+    fn lights(&self) -> Vec<Light> {
+        Vec::new()
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Sphere {
     pub center: Vector,
     pub radius: f64,
-    pub color: Vector,
-    pub reflectivity: f64,
-    pub exponent: f64
+    pub material: Arc<Material>
 }
 
 impl Shape for Sphere {
@@ -29,7 +44,19 @@ impl Shape for Sphere {
         self.center = *pos;
     }
 
-    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+    fn bounds(&self) -> Aabb {
+        let r = Vector::new(self.radius, self.radius, self.radius);
+        Aabb::new(self.center - r, self.center + r)
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        if !self.material.is_emissive() { return Vec::new(); }
+        let kind = LightKind::Sphere { radius: self.radius };
+        let outline = PI * self.radius * self.radius;
+        vec![Light::surface(self.center, kind, self.material.emission, outline, SURFACE_SAMPLES)]
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
 
         let xd: f64 = ray.dir.x;
         let yd: f64 = ray.dir.y;
@@ -70,45 +97,765 @@ impl Shape for Sphere {
         let point_on_sphere: Vector = ray.interp(t);
         let normal = (point_on_sphere - self.center) * switch;
 
+        // Wraps u around the vertical axis, starting and ending behind the sphere, and runs v from bottom to top
+        let local = (point_on_sphere - self.center) / sr;
+        let u = 0.5 + local.x.atan2(local.z) / (2.0 * PI);
+        let v = 0.5 + local.y.clamp(-1.0, 1.0).asin() / PI;
+        let rho = (local.x*local.x + local.z*local.z).sqrt();
+        let dpdv = if rho > 0.0 { Vector::new(-local.y * local.x / rho, rho, -local.y * local.z / rho) * (PI * sr) }
+            else { Vector::new(0.0, 0.0, 0.0) };
+
         let inter = Intersection {
             t,
             position: point_on_sphere,
             normal,
-            reflectivity: self.reflectivity,
-            color: self.color,
-            exponent: self.exponent
+            material: &self.material,
+            inside: switch < 0.0,
+            uv: [u, v],
+            dpdu: Vector::new(local.z, 0.0, -local.x) * (2.0 * PI * sr),
+            dpdv
         };
         Some(inter)
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Floor {
     pub position: Vector,
-    pub color: Vector,
-    pub reflectivity: f64,
-    pub exponent: f64
+    pub material: Arc<Material>
 }
 
 impl Shape for Floor {
 
     fn get_position(&self) -> Vector { self.position }
     fn set_position(&mut self, pos: &Vector) { self.position = *pos; }
+    fn bounds(&self) -> Aabb { Aabb::infinite() }
 
-    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
 
         let t: f64 = (self.position.y - ray.origin.y) / ray.dir.y;
-        if t < EPSILON { return None }
-        else if t > 1.0 { return None};
+        if !(EPSILON..=1.0).contains(&t) { return None; }
+
+        // Rays from below hit the floor from inside, and see it facing down
+        let inside = ray.dir.y > 0.0;
+        let position = ray.interp(t);
+        let inter = Intersection {
+            t,
+            position,
+            normal: Vector::new(0.0, if inside { -1.0 } else { 1.0 }, 0.0),
+            material: &self.material,
+            inside,
+            uv: [position.x - self.position.x, self.position.z - position.z],
+            dpdu: Vector::new(1.0, 0.0, 0.0),
+            dpdv: Vector::new(0.0, 0.0, -1.0)
+        };
+        Some(inter)
+    }
+}
+
+/// Box whose faces are aligned with the world axes
+#[derive(Debug, Clone)]
+pub struct Cuboid {
+    pub center: Vector,
+    pub size: Vector,
+    pub material: Arc<Material>
+}
+
+impl Shape for Cuboid {
+
+    fn get_position(&self) -> Vector { self.center }
+    fn set_position(&mut self, pos: &Vector) { self.center = *pos; }
+
+    fn bounds(&self) -> Aabb {
+        let half = self.size * 0.5;
+        Aabb::new(self.center - half, self.center + half)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let origin = ray.origin - self.center;
+        let (t, axis, sign, inside) = intersect_box(&origin, &ray.dir, &(self.size * 0.5))?;
+        let mut normal = Vector::new(0.0, 0.0, 0.0);
+        match axis {
+            0 => normal.x = sign,
+            1 => normal.y = sign,
+            _ => normal.z = sign
+        }
+        let (uv, dpdu, dpdv) = box_uv(&(origin + ray.dir * t), &self.size, axis);
+        let inter = Intersection {
+            t,
+            position: ray.interp(t),
+            normal,
+            material: &self.material,
+            inside,
+            uv,
+            dpdu,
+            dpdv
+        };
+        Some(inter)
+    }
+}
+
+/// Box rotated arbitrarily about its center.
+/// The box's width, height and depth run along the u, v and w axes of 'orientation'.
+#[derive(Debug, Clone)]
+pub struct OrientedCuboid {
+    pub center: Vector,
+    pub size: Vector,
+    pub orientation: Basis,
+    pub material: Arc<Material>
+}
+
+impl OrientedCuboid {
+
+    /// Box rotated by euler angles in radians, applied about x, then y, then z.
+    pub fn from_euler(center: Vector, size: Vector, rotation: Vector, material: Arc<Material>) -> OrientedCuboid {
+        OrientedCuboid {
+            center,
+            size,
+            orientation: Basis::from_euler(&rotation),
+            material
+        }
+    }
+}
+
+impl Shape for OrientedCuboid {
+
+    fn get_position(&self) -> Vector { self.center }
+    fn set_position(&mut self, pos: &Vector) { self.center = *pos; }
+
+    fn bounds(&self) -> Aabb {
+        let half = self.size * 0.5;
+        let abs = |v: Vector| Vector::new(v.x.abs(), v.y.abs(), v.z.abs());
+        let extent =
+            abs(self.orientation.u) * half.x +
+            abs(self.orientation.v) * half.y +
+            abs(self.orientation.w) * half.z;
+        Aabb::new(self.center - extent, self.center + extent)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+
+        // Intersects in the box's local space, where it is axis-aligned
+        let origin = self.orientation.to_local(&(ray.origin - self.center));
+        let dir = self.orientation.to_local(&ray.dir);
+        let (t, axis, sign, inside) = intersect_box(&origin, &dir, &(self.size * 0.5))?;
+        let (uv, dpdu, dpdv) = box_uv(&(origin + dir * t), &self.size, axis);
+        let inter = Intersection {
+            t,
+            position: ray.interp(t),
+            normal: self.orientation.axis(axis) * sign,
+            material: &self.material,
+            inside,
+            uv,
+            dpdu: self.orientation.to_world(&dpdu),
+            dpdv: self.orientation.to_world(&dpdv)
+        };
+        Some(inter)
+    }
+}
+
+/// Capped cylinder.
+/// Its axis runs through 'center' along 'axis', with the caps 'height' apart.
+/// A zero length axis, or a height or radius that isn't positive, gives it no surface and NaN bounds, which
+/// Cylinder::new checks for.
+#[derive(Debug, Clone)]
+pub struct Cylinder {
+    pub center: Vector,
+    pub axis: Vector,
+    pub height: f64,
+    pub radius: f64,
+    pub material: Arc<Material>
+}
+
+impl Cylinder {
+
+    /// Panics if the axis has no length, or if the height or radius isn't positive, as the cylinder would have no
+    /// side to hit. Building the struct directly skips these checks.
+    pub fn new(center: Vector, axis: Vector, height: f64, radius: f64, material: Arc<Material>) -> Cylinder {
+        assert!(axis.len_squared() > 0.0, "Cylinder axis must have a length");
+        assert!(height > 0.0, "Cylinder height must be positive");
+        assert!(radius > 0.0, "Cylinder radius must be positive");
+        Cylinder { center, axis, height, radius, material }
+    }
+}
+
+impl Shape for Cylinder {
+
+    fn get_position(&self) -> Vector { self.center }
+    fn set_position(&mut self, pos: &Vector) { self.center = *pos; }
+
+    fn bounds(&self) -> Aabb {
+        frustum_bounds(&self.center, &self.axis, self.height, self.radius, self.radius)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        intersect_frustum(&self.center, &self.axis, self.height, self.radius, self.radius, &self.material, ray)
+    }
+}
+
+/// Capped cone.
+/// Its axis runs through 'center' along 'axis'. The base cap is 'height/2' behind the center and the top cap
+/// 'height/2' ahead of it. A top_radius of 0 gives a pointed cone, anything larger gives a truncated cone.
+/// A zero length axis, a height that isn't positive, or radii that are negative or both 0, give it no surface and NaN
+/// bounds, which Cone::new checks for.
+#[derive(Debug, Clone)]
+pub struct Cone {
+    pub center: Vector,
+    pub axis: Vector,
+    pub height: f64,
+    pub base_radius: f64,
+    pub top_radius: f64,
+    pub material: Arc<Material>
+}
+
+impl Cone {
+
+    /// Panics if the axis has no length, if the height isn't positive, or if a radius is negative or both are 0, as
+    /// the cone would have no side to hit. Building the struct directly skips these checks.
+    pub fn new(
+        center: Vector,
+        axis: Vector,
+        height: f64,
+        base_radius: f64,
+        top_radius: f64,
+        material: Arc<Material>
+    ) -> Cone {
+        assert!(axis.len_squared() > 0.0, "Cone axis must have a length");
+        assert!(height > 0.0, "Cone height must be positive");
+        assert!(base_radius >= 0.0 && top_radius >= 0.0, "Cone radii can't be negative");
+        assert!(base_radius > 0.0 || top_radius > 0.0, "Cone radii can't both be 0");
+        Cone { center, axis, height, base_radius, top_radius, material }
+    }
+}
+
+impl Shape for Cone {
+
+    fn get_position(&self) -> Vector { self.center }
+    fn set_position(&mut self, pos: &Vector) { self.center = *pos; }
+
+    fn bounds(&self) -> Aabb {
+        frustum_bounds(&self.center, &self.axis, self.height, self.base_radius, self.top_radius)
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let (base_radius, top_radius) = (self.base_radius, self.top_radius);
+        intersect_frustum(&self.center, &self.axis, self.height, base_radius, top_radius, &self.material, ray)
+    }
+}
+
+/// Single triangle, visible from both sides
+#[derive(Debug, Clone)]
+pub struct Triangle {
+    pub vertices: [Vector; 3],
+    pub material: Arc<Material>
+}
+
+impl Shape for Triangle {
+
+    fn get_position(&self) -> Vector {
+        (self.vertices[0] + self.vertices[1] + self.vertices[2]) / 3.0
+    }
+
+    fn set_position(&mut self, pos: &Vector) {
+        let offset = *pos - self.get_position();
+        for vertex in &mut self.vertices {
+            *vertex = *vertex + offset;
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        let [a, b, c] = self.vertices;
+        Aabb::new(a, a).grow(&b).grow(&c)
+    }
 
+    fn lights(&self) -> Vec<Light> {
+        if !self.material.is_emissive() { return Vec::new(); }
+        let [a, b, c] = self.vertices;
+        vec![triangle_light(&a, &b, &c, &self.material, SURFACE_SAMPLES)]
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+        let [a, b, c] = self.vertices;
+        let (t, u, v) = intersect_triangle(ray, &a, &b, &c)?;
+        let normal = (b - a).cross(&(c - a));
+        let inside = normal.dot(&ray.dir) > 0.0;
         let inter = Intersection {
             t,
             position: ray.interp(t),
-            normal: Vector::new(0.0, 1.0, 0.0),
-            reflectivity: self.reflectivity,
-            color: self.color,
-            exponent: self.exponent
+            normal: if inside { -normal } else { normal },
+            material: &self.material,
+            inside,
+            uv: [u, v],
+            dpdu: b - a,
+            dpdv: c - a
         };
         Some(inter)
     }
-}
\ No newline at end of file
+}
+
+/// Parallelogram centered on 'center' with edges 'u' and 'v', visible from both sides.
+/// Its front faces the way u x v does, and is the side that glows.
+#[derive(Debug, Clone)]
+pub struct Quad {
+    pub center: Vector,
+    pub u: Vector,
+    pub v: Vector,
+    pub material: Arc<Material>
+}
+
+impl Shape for Quad {
+
+    fn get_position(&self) -> Vector { self.center }
+    fn set_position(&mut self, pos: &Vector) { self.center = *pos; }
+
+    fn bounds(&self) -> Aabb {
+        let corner = self.center - self.u / 2.0 - self.v / 2.0;
+        Aabb::new(corner, corner)
+            .grow(&(corner + self.u))
+            .grow(&(corner + self.v))
+            .grow(&(corner + self.u + self.v))
+    }
+
+    fn lights(&self) -> Vec<Light> {
+        if !self.material.is_emissive() { return Vec::new(); }
+        let kind = LightKind::Rect { u: self.u, v: self.v };
+        let area = self.u.cross(&self.v).len();
+        vec![Light::surface(self.center, kind, self.material.emission, area, SURFACE_SAMPLES)]
+    }
+
+    fn intersect(&self, ray: &Ray) -> Option<Intersection<'_>> {
+
+        // Finds where the ray crosses the quad's plane
+        let normal = self.u.cross(&self.v);
+        let denom = normal.dot(&ray.dir);
+        if denom == 0.0 { return None; }
+        let corner = self.center - self.u / 2.0 - self.v / 2.0;
+        let t = normal.dot(&(corner - ray.origin)) / denom;
+        if !(EPSILON..=1.0).contains(&t) { return None; }
+
+        // Measures the point along both edges from the corner
+        let position = ray.interp(t);
+        let offset = position - corner;
+        let w = normal / normal.len_squared();
+        let along_u = w.dot(&offset.cross(&self.v));
+        let along_v = w.dot(&self.u.cross(&offset));
+        if !(0.0..=1.0).contains(&along_u) || !(0.0..=1.0).contains(&along_v) { return None; }
+
+        let inside = denom > 0.0;
+        let inter = Intersection {
+            t,
+            position,
+            normal: if inside { -normal } else { normal },
+            material: &self.material,
+            inside,
+            uv: [along_u, along_v],
+            dpdu: self.u,
+            dpdv: self.v
+        };
+        Some(inter)
+    }
+}
+
+/// Möller–Trumbore ray/triangle intersection.
+/// Returns the ray length of the hit and the barycentric coordinates (u, v) of the hit point,
+/// where the point is a*(1-u-v) + b*u + c*v.
+pub fn intersect_triangle(ray: &Ray, a: &Vector, b: &Vector, c: &Vector) -> Option<(f64, f64, f64)> {
+    let edge1 = *b - *a;
+    let edge2 = *c - *a;
+    let p = ray.dir.cross(&edge2);
+    let det = edge1.dot(&p);
+    if det.abs() < f64::EPSILON { return None; }
+    let inv_det = 1.0 / det;
+
+    let s = ray.origin - *a;
+    let u = s.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&u) { return None; }
+
+    let q = s.cross(&edge1);
+    let v = ray.dir.dot(&q) * inv_det;
+    if v < 0.0 || u + v > 1.0 { return None; }
+
+    let t = edge2.dot(&q) * inv_det;
+    if !(EPSILON..=1.0).contains(&t) { return None; }
+    Some((t, u, v))
+}
+
+/// Light standing in for triangle (a, b, c) made of glowing 'material', glowing on the side it winds anticlockwise
+pub fn triangle_light(a: &Vector, b: &Vector, c: &Vector, material: &Material, samples: u32) -> Light {
+    let (u, v) = (*b - *a, *c - *a);
+    let area = u.cross(&v).len() / 2.0;
+    Light::surface(*a, LightKind::Triangle { u, v }, material.emission, area, samples)
+}
+
+/// Bounds of a capped frustum, which are the bounds of its two cap disks.
+fn frustum_bounds(center: &Vector, axis: &Vector, height: f64, base_radius: f64, top_radius: f64) -> Aabb {
+    let n = axis.to_unit();
+    let disk_extent = |r: f64| Vector::new(
+        r * (1.0 - n.x*n.x).max(0.0).sqrt(),
+        r * (1.0 - n.y*n.y).max(0.0).sqrt(),
+        r * (1.0 - n.z*n.z).max(0.0).sqrt()
+    );
+    let base = *center - n * (height/2.0);
+    let top = *center + n * (height/2.0);
+    let base_extent = disk_extent(base_radius);
+    let top_extent = disk_extent(top_radius);
+    Aabb::new(base - base_extent, base + base_extent)
+        .union(&Aabb::new(top - top_extent, top + top_extent))
+}
+
+/// Intersects a ray with a capped frustum of 'material' whose radius varies linearly from base_radius to top_radius.
+/// As with Sphere, the normal is flipped to face the ray when the ray starts inside.
+/// The side wraps u around the axis and runs v from base to top, while the caps are mapped flat.
+fn intersect_frustum<'a>(
+    center: &Vector,
+    axis: &Vector,
+    height: f64,
+    base_radius: f64,
+    top_radius: f64,
+    material: &'a Material,
+    ray: &Ray
+) -> Option<Intersection<'a>> {
+
+    // Intersects in local space, where the axis runs along z from -height/2 to height/2
+    let basis = Basis::from_w(axis);
+    let o = basis.to_local(&(ray.origin - *center));
+    let d = basis.to_local(&ray.dir);
+    let half = height / 2.0;
+
+    // Radius at z is a + k*z
+    let k = (top_radius - base_radius) / height;
+    let a = (base_radius + top_radius) / 2.0;
+
+    let mut best: Option<(f64, Vector, bool)> = None;
+    let mut consider = |t: f64, normal: Vector, cap: bool| {
+        if !(EPSILON..=1.0).contains(&t) { return; }
+        match best {
+            Some((best_t, _, _)) if best_t <= t => {},
+            _ => best = Some((t, normal, cap))
+        }
+    };
+
+    // Side, solving (ox + t*dx)^2 + (oy + t*dy)^2 = (a + k*(oz + t*dz))^2
+    let r0 = a + k*o.z;
+    let qa = d.x*d.x + d.y*d.y - k*k*d.z*d.z;
+    let qb = 2.0 * (o.x*d.x + o.y*d.y - k*d.z*r0);
+    let qc = o.x*o.x + o.y*o.y - r0*r0;
+    let side_normal = |t: f64| -> Option<Vector> {
+        let p = o + d*t;
+        if p.z < -half || p.z > half { return None; }
+
+        // The side has no slope at the point of a cone, which is taken to face along the axis, away from the base
+        let normal = Vector::new(p.x, p.y, -k * (a + k*p.z));
+        Some(if normal.len_squared() > 0.0 { normal } else { Vector::new(0.0, 0.0, -k.signum()) })
+    };
+    if qa.abs() > EPSILON {
+        let discrim = qb*qb - 4.0*qa*qc;
+        if discrim >= 0.0 {
+            let sqrt_discrim = discrim.sqrt();
+            for &t in &[(-qb - sqrt_discrim) / (2.0*qa), (-qb + sqrt_discrim) / (2.0*qa)] {
+                if let Some(normal) = side_normal(t) { consider(t, normal, false); }
+            }
+        }
+    }
+    else if qb != 0.0 {
+        let t = -qc / qb;
+        if let Some(normal) = side_normal(t) { consider(t, normal, false); }
+    }
+
+    // Caps
+    if d.z != 0.0 {
+        for &(z, radius, nz) in &[(-half, base_radius, -1.0), (half, top_radius, 1.0)] {
+            let t = (z - o.z) / d.z;
+            let p = o + d*t;
+            if p.x*p.x + p.y*p.y <= radius*radius {
+                consider(t, Vector::new(0.0, 0.0, nz), true);
+            }
+        }
+    }
+
+    let (t, local_normal, cap) = best?;
+    let normal = basis.to_world(&local_normal);
+    let inside = normal.dot(&ray.dir) > 0.0;
+
+    let p = o + d*t;
+    let (uv, dpdu, dpdv) = if cap {
+        let radius = base_radius.max(top_radius);
+        let uv = [0.5 + p.x / (2.0*radius), 0.5 + p.y / (2.0*radius)];
+        (uv, Vector::new(2.0*radius, 0.0, 0.0), Vector::new(0.0, 2.0*radius, 0.0))
+    }
+    else {
+        let uv = [0.5 + p.y.atan2(p.x) / (2.0*PI), (p.z + half) / height];
+
+        // Moving up the side also moves out or in as the radius changes
+        let rho = (p.x*p.x + p.y*p.y).sqrt();
+        let outwards = if rho > 0.0 { Vector::new(p.x / rho, p.y / rho, 0.0) } else { Vector::new(0.0, 0.0, 0.0) };
+        (uv, Vector::new(-p.y, p.x, 0.0) * (2.0*PI), (outwards * k + Vector::new(0.0, 0.0, 1.0)) * height)
+    };
+    let inter = Intersection {
+        t,
+        position: ray.interp(t),
+        normal: if inside { -normal } else { normal },
+        material,
+        inside,
+        uv,
+        dpdu: basis.to_world(&dpdu),
+        dpdv: basis.to_world(&dpdv)
+    };
+    Some(inter)
+}
+
+/// Texture coordinates of 'point' on the face of a box of size 'size' centered on the origin, at the end of 'axis',
+/// and how the point moves per unit of u and of v.
+/// Each face is mapped onto 0 to 1, with v running up the sides.
+fn box_uv(point: &Vector, size: &Vector, axis: usize) -> ([f64; 2], Vector, Vector) {
+    let (u_axis, v_axis) = match axis {
+        0 => (2, 1),
+        1 => (0, 2),
+        _ => (0, 1)
+    };
+    let edge = |axis: usize| {
+        let mut edge = Vector::new(0.0, 0.0, 0.0);
+        match axis {
+            0 => edge.x = size.x,
+            1 => edge.y = size.y,
+            _ => edge.z = size.z
+        }
+        edge
+    };
+    let uv = [0.5 + point.axis(u_axis) / size.axis(u_axis), 0.5 + point.axis(v_axis) / size.axis(v_axis)];
+    (uv, edge(u_axis), edge(v_axis))
+}
+
+/// Intersects a ray with an axis-aligned box centered on the origin.
+/// Returns the ray length of the hit, the axis of the face hit, the sign of that face's normal and whether the ray
+/// started inside the box.
+/// When the ray starts inside the box, the far face is hit and its normal is flipped to face the ray, as with Sphere.
+fn intersect_box(origin: &Vector, dir: &Vector, half: &Vector) -> Option<(f64, usize, f64, bool)> {
+    let mut t_near = f64::NEG_INFINITY;
+    let mut t_far = f64::INFINITY;
+    let mut near_axis = 0;
+    let mut far_axis = 0;
+    for axis in 0..3 {
+        let o = origin.axis(axis);
+        let d = dir.axis(axis);
+        let h = half.axis(axis);
+        if d == 0.0 {
+            if o < -h || o > h { return None; }
+            continue;
+        }
+        let t0 = (-h - o) / d;
+        let t1 = (h - o) / d;
+        let (t0, t1) = if t0 < t1 { (t0, t1) } else { (t1, t0) };
+        if t0 > t_near { t_near = t0; near_axis = axis; }
+        if t1 < t_far { t_far = t1; far_axis = axis; }
+        if t_near > t_far { return None; }
+    }
+
+    let (t, axis, inside) = if t_near >= EPSILON { (t_near, near_axis, false) }
+        else if t_far >= EPSILON { (t_far, far_axis, true) }
+        else { return None; };
+    if t > 1.0 { return None; }
+
+    // Entering faces and flipped exiting faces both point back along the ray
+    let sign = if dir.axis(axis) > 0.0 { -1.0 } else { 1.0 };
+    Some((t, axis, sign, inside))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn material() -> Arc<Material> {
+        Arc::new(Material::new(Vector::new(1.0, 1.0, 1.0), 0.0, 1.0))
+    }
+
+    fn assert_close(a: &Vector, b: &Vector) {
+        assert!((*a - *b).len() < 1e-9, "expected {:?}, found {:?}", b, a);
+    }
+
+    #[test]
+    fn cuboid_faces_face_outwards() {
+        let cuboid = Cuboid { center: Vector::new(1.0, 2.0, 3.0), size: Vector::new(2.0, 4.0, 6.0), material: material() };
+        for axis in 0..3 {
+            for &sign in &[-1.0, 1.0] {
+                let mut outwards = Vector::new(0.0, 0.0, 0.0);
+                match axis {
+                    0 => outwards.x = sign,
+                    1 => outwards.y = sign,
+                    _ => outwards.z = sign
+                }
+                let origin = cuboid.center + outwards * 10.0;
+                let inter = cuboid.intersect(&Ray::new(origin, outwards * -20.0)).unwrap();
+                let half = cuboid.size.axis(axis) / 2.0;
+                assert!((inter.t - (10.0 - half) / 20.0).abs() < 1e-9);
+                assert_close(&inter.normal, &outwards);
+                assert!(!inter.inside);
+                assert!((inter.uv[0] - 0.5).abs() < 1e-9 && (inter.uv[1] - 0.5).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn cuboid_maps_each_face_onto_the_unit_square() {
+        let cuboid = Cuboid { center: Vector::new(1.0, 2.0, 3.0), size: Vector::new(2.0, 4.0, 6.0), material: material() };
+
+        // Hits the +x face, where u runs along z and v up y
+        let ray = Ray::new(Vector::new(5.0, 3.0, 4.5), Vector::new(-10.0, 0.0, 0.0));
+        let inter = cuboid.intersect(&ray).unwrap();
+        assert!((inter.uv[0] - 0.75).abs() < 1e-9 && (inter.uv[1] - 0.75).abs() < 1e-9, "uv is {:?}", inter.uv);
+        assert_close(&inter.dpdu, &Vector::new(0.0, 0.0, 6.0));
+        assert_close(&inter.dpdv, &Vector::new(0.0, 4.0, 0.0));
+
+        // Hits the top face, where u runs along x and v along z
+        let ray = Ray::new(Vector::new(0.5, 10.0, 1.5), Vector::new(0.0, -10.0, 0.0));
+        let inter = cuboid.intersect(&ray).unwrap();
+        assert!((inter.uv[0] - 0.25).abs() < 1e-9 && (inter.uv[1] - 0.25).abs() < 1e-9, "uv is {:?}", inter.uv);
+        assert_close(&inter.dpdu, &Vector::new(2.0, 0.0, 0.0));
+        assert_close(&inter.dpdv, &Vector::new(0.0, 0.0, 6.0));
+    }
+
+    #[test]
+    fn cuboid_hit_from_inside_faces_the_ray() {
+        let cuboid = Cuboid { center: Vector::new(0.0, 0.0, 0.0), size: Vector::new(2.0, 2.0, 2.0), material: material() };
+        let inter = cuboid.intersect(&Ray::new(Vector::new(0.0, 0.5, 0.0), Vector::new(4.0, 0.0, 0.0))).unwrap();
+        assert!(inter.inside);
+        assert!((inter.t - 0.25).abs() < 1e-9);
+        assert_close(&inter.normal, &Vector::new(-1.0, 0.0, 0.0));
+
+        // Rays leaving the box behind them miss it
+        assert!(cuboid.intersect(&Ray::new(Vector::new(2.0, 0.0, 0.0), Vector::new(4.0, 0.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn oriented_cuboid_faces_follow_its_rotation() {
+
+        // A quarter turn about z lays the box's width along y
+        let rotation = Vector::new(0.0, 0.0, PI / 2.0);
+        let cuboid = OrientedCuboid::from_euler(Vector::new(0.0, 0.0, 0.0), Vector::new(2.0, 4.0, 6.0), rotation, material());
+        let inter = cuboid.intersect(&Ray::new(Vector::new(0.0, 10.0, 0.0), Vector::new(0.0, -20.0, 0.0))).unwrap();
+        assert!((inter.t - 9.0 / 20.0).abs() < 1e-9);
+        assert_close(&inter.normal, &Vector::new(0.0, 1.0, 0.0));
+        assert!(!inter.inside);
+
+        let inter = cuboid.intersect(&Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(10.0, 0.0, 0.0))).unwrap();
+        assert!((inter.t - 0.2).abs() < 1e-9);
+        assert_close(&inter.normal, &Vector::new(-1.0, 0.0, 0.0));
+        assert!(inter.inside);
+    }
+
+    fn unit_cylinder() -> Cylinder {
+        Cylinder::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 2.0, 1.0, material())
+    }
+
+    #[test]
+    fn cylinder_side_and_caps_face_outwards() {
+        let cylinder = unit_cylinder();
+        let inter = cylinder.intersect(&Ray::new(Vector::new(5.0, 0.5, 0.0), Vector::new(-10.0, 0.0, 0.0))).unwrap();
+        assert!((inter.t - 0.4).abs() < 1e-9);
+        assert_close(&inter.normal.to_unit(), &Vector::new(1.0, 0.0, 0.0));
+        assert!(!inter.inside);
+        assert!((inter.uv[1] - 0.75).abs() < 1e-9);
+
+        let inter = cylinder.intersect(&Ray::new(Vector::new(0.5, 5.0, 0.0), Vector::new(0.0, -10.0, 0.0))).unwrap();
+        assert!((inter.t - 0.4).abs() < 1e-9);
+        assert_close(&inter.normal.to_unit(), &Vector::new(0.0, 1.0, 0.0));
+
+        let inter = cylinder.intersect(&Ray::new(Vector::new(0.0, -5.0, 0.5), Vector::new(0.0, 10.0, 0.0))).unwrap();
+        assert_close(&inter.normal.to_unit(), &Vector::new(0.0, -1.0, 0.0));
+
+        // Passes beside the cylinder, and over it
+        assert!(cylinder.intersect(&Ray::new(Vector::new(5.0, 0.0, 1.5), Vector::new(-10.0, 0.0, 0.0))).is_none());
+        assert!(cylinder.intersect(&Ray::new(Vector::new(5.0, 1.5, 0.0), Vector::new(-10.0, 0.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn cylinder_hit_from_inside_faces_the_ray() {
+        let cylinder = unit_cylinder();
+        let inter = cylinder.intersect(&Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(4.0, 0.0, 0.0))).unwrap();
+        assert!(inter.inside);
+        assert!((inter.t - 0.25).abs() < 1e-9);
+        assert_close(&inter.normal.to_unit(), &Vector::new(-1.0, 0.0, 0.0));
+
+        let inter = cylinder.intersect(&Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 4.0, 0.0))).unwrap();
+        assert!(inter.inside);
+        assert_close(&inter.normal.to_unit(), &Vector::new(0.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn cone_side_slopes_towards_its_point() {
+        let cone = Cone::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 2.0, 1.0, 0.0, material());
+
+        // Halfway up, the radius is 0.5 and the side leans back at 1 in 2
+        let inter = cone.intersect(&Ray::new(Vector::new(5.0, 0.0, 0.0), Vector::new(-10.0, 0.0, 0.0))).unwrap();
+        assert!((inter.t - 0.45).abs() < 1e-9);
+        assert_close(&inter.normal.to_unit(), &Vector::new(2.0, 1.0, 0.0).to_unit());
+
+        let inter = cone.intersect(&Ray::new(Vector::new(0.0, 0.0, 0.0), Vector::new(-4.0, 0.0, 0.0))).unwrap();
+        assert!(inter.inside);
+        assert_close(&inter.normal.to_unit(), &Vector::new(2.0, -1.0, 0.0).to_unit());
+    }
+
+    #[test]
+    fn cone_point_faces_along_the_axis() {
+        let cone = Cone::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 2.0, 1.0, 0.0, material());
+        let inter = cone.intersect(&Ray::new(Vector::new(0.0, 5.0, 0.0), Vector::new(0.0, -10.0, 0.0))).unwrap();
+        assert!((inter.t - 0.4).abs() < 1e-9);
+        assert_close(&inter.normal.to_unit(), &Vector::new(0.0, 1.0, 0.0));
+        assert!((inter.uv[1] - 1.0).abs() < 1e-9);
+
+        // Upside down, the point is at the bottom
+        let cone = Cone::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 2.0, 0.0, 1.0, material());
+        let inter = cone.intersect(&Ray::new(Vector::new(0.0, -5.0, 0.0), Vector::new(0.0, 10.0, 0.0))).unwrap();
+        assert_close(&inter.normal.to_unit(), &Vector::new(0.0, -1.0, 0.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "height must be positive")]
+    fn cylinder_needs_a_height() {
+        Cylinder::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0), 0.0, 1.0, material());
+    }
+
+    #[test]
+    #[should_panic(expected = "axis must have a length")]
+    fn cone_needs_an_axis() {
+        Cone::new(Vector::new(0.0, 0.0, 0.0), Vector::new(0.0, 0.0, 0.0), 1.0, 1.0, 0.0, material());
+    }
+
+    #[test]
+    fn triangle_hits_carry_barycentric_coordinates() {
+        let a = Vector::new(0.0, 0.0, 0.0);
+        let b = Vector::new(1.0, 0.0, 0.0);
+        let c = Vector::new(0.0, 1.0, 0.0);
+        let (t, u, v) = intersect_triangle(&Ray::new(Vector::new(0.6, 0.3, 5.0), Vector::new(0.0, 0.0, -10.0)), &a, &b, &c)
+            .unwrap();
+        assert!((t - 0.5).abs() < 1e-9 && (u - 0.6).abs() < 1e-9 && (v - 0.3).abs() < 1e-9);
+
+        // Just outside the long edge
+        assert!(intersect_triangle(&Ray::new(Vector::new(0.6, 0.41, 5.0), Vector::new(0.0, 0.0, -10.0)), &a, &b, &c)
+            .is_none());
+
+        // Rays in the triangle's plane never hit it
+        assert!(intersect_triangle(&Ray::new(Vector::new(-1.0, 0.25, 0.0), Vector::new(10.0, 0.0, 0.0)), &a, &b, &c)
+            .is_none());
+    }
+
+    #[test]
+    fn triangle_is_seen_from_both_sides() {
+        let triangle = Triangle {
+            vertices: [Vector::new(0.0, 0.0, 0.0), Vector::new(1.0, 0.0, 0.0), Vector::new(0.0, 1.0, 0.0)],
+            material: material()
+        };
+        let inter = triangle.intersect(&Ray::new(Vector::new(0.25, 0.5, 5.0), Vector::new(0.0, 0.0, -10.0))).unwrap();
+        assert!((inter.t - 0.5).abs() < 1e-9);
+        assert!(!inter.inside);
+        assert_close(&inter.normal.to_unit(), &Vector::new(0.0, 0.0, 1.0));
+        assert!((inter.uv[0] - 0.25).abs() < 1e-9 && (inter.uv[1] - 0.5).abs() < 1e-9);
+
+        let inter = triangle.intersect(&Ray::new(Vector::new(0.25, 0.5, -5.0), Vector::new(0.0, 0.0, 10.0))).unwrap();
+        assert!(inter.inside);
+        assert_close(&inter.normal.to_unit(), &Vector::new(0.0, 0.0, -1.0));
+
+        // Grazing rays slip past
+        assert!(triangle.intersect(&Ray::new(Vector::new(-1.0, 0.25, 0.0), Vector::new(10.0, 0.0, 0.0))).is_none());
+    }
+}
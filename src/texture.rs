@@ -0,0 +1,354 @@
+use geom::{Vector, Intersection};
+use framebuffer::Framebuffer;
+use hdr::{self, HdrFormat};
+use noise::{fbm, turbulence};
+use raster::{self, Image};
+use raster::error::RasterError;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Pattern varying a property of a material over a surface.
+/// Solid textures, from Checker on, fill space rather than being wrapped over the surface, so are looked up at the point
+/// hit. Shapes carve their surface out of them like a sculpture out of stone, with no seams or stretching, but
+/// moving shapes slide through the pattern. 'scale' sets how many features fit in a unit of distance, and 'octaves'
+/// how many ever finer layers of noise are added on top of each other.
+#[derive(Debug, Clone)]
+pub enum Texture {
+
+    /// Squares alternating between two colors in texture space, 'scale' squares to a unit of u and v.
+    /// Shows how shapes map their surface onto texture coordinates.
+    UvChecker { scale: f64, colors: [Vector; 2] },
+
+    /// Image stretched over texture coordinates 0 to 1
+    Image(ImageTexture),
+
+    /// Cubes alternating between two colors, 'scale' cubes to a unit of distance.
+    /// Surfaces lying on the faces between cubes, such as a floor at a height of 0, flicker between both colors.
+    Checker { scale: f64, colors: [Vector; 2] },
+
+    /// Smooth, cloudy Perlin noise, spread over the ramp
+    Noise { scale: f64, octaves: u32, ramp: ColorRamp },
+
+    /// Noise with sharp creases, like billowing smoke, running from the start of the ramp at the creases to its end
+    Turbulence { scale: f64, octaves: u32, ramp: ColorRamp },
+
+    /// Veins running across the x axis, going back and forth along the ramp, bent by 'turbulence'
+    Marble { scale: f64, octaves: u32, turbulence: f64, ramp: ColorRamp },
+
+    /// Rings around the y axis, each running through the ramp from its start to its end, bent by 'turbulence'
+    Wood { scale: f64, octaves: u32, turbulence: f64, ramp: ColorRamp }
+}
+
+/// Colors spread evenly from 0 to 1, blended between
+#[derive(Debug, Clone, new)]
+pub struct ColorRamp {
+    pub colors: Vec<Vector>
+}
+
+impl ColorRamp {
+
+    /// Color at 't', clamped to between 0 and 1. Empty ramps are black.
+    pub fn at(&self, t: f64) -> Vector {
+        match self.colors.len() {
+            0 => Vector::new(0.0, 0.0, 0.0),
+            1 => self.colors[0],
+            len => {
+                let x = t.clamp(0.0, 1.0) * (len - 1) as f64;
+                let i = (x.floor() as usize).min(len - 2);
+                self.colors[i].interp(&self.colors[i + 1], x - i as f64)
+            }
+        }
+    }
+}
+
+impl Texture {
+
+    /// Color of the texture at the point hit
+    pub fn color(&self, inter: &Intersection) -> Vector {
+        match *self {
+            Texture::UvChecker { scale, colors } => {
+                let [u, v] = inter.uv;
+                let parity = (u * scale).floor() + (v * scale).floor();
+                colors[(parity.rem_euclid(2.0)) as usize]
+            },
+            Texture::Image(ref image) => image.sample(inter.uv),
+            Texture::Checker { scale, colors } => {
+                let p = inter.position * scale;
+                let parity = p.x.floor() + p.y.floor() + p.z.floor();
+                colors[(parity.rem_euclid(2.0)) as usize]
+            },
+            Texture::Noise { scale, octaves, ref ramp } => {
+                ramp.at(0.5 + 0.5 * fbm(&(inter.position * scale), octaves))
+            },
+            Texture::Turbulence { scale, octaves, ref ramp } => {
+                ramp.at(turbulence(&(inter.position * scale), octaves))
+            },
+            Texture::Marble { scale, octaves, turbulence: strength, ref ramp } => {
+                let p = inter.position * scale;
+                ramp.at(0.5 + 0.5 * (PI * (p.x + strength * turbulence(&p, octaves))).sin())
+            },
+            Texture::Wood { scale, octaves, turbulence: strength, ref ramp } => {
+                let p = inter.position * scale;
+
+                // Bends the rings with noise that goes both ways, unlike turbulence, so that they stay centered
+                let ring = (p.x*p.x + p.z*p.z).sqrt() + strength * fbm(&p, octaves);
+                ramp.at(ring - ring.floor())
+            }
+        }
+    }
+
+    /// Single value of the texture at the point hit, the mean of its color channels
+    pub fn value(&self, inter: &Intersection) -> f64 {
+        let color = self.color(inter);
+        (color.x + color.y + color.z) / 3.0
+    }
+}
+
+/// How image textures blend the texels around a point
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextureFilter {
+
+    /// Takes the closest texel, showing texels as sharp squares up close
+    Nearest,
+
+    /// Blends the four closest texels
+    #[default]
+    Bilinear
+}
+
+/// How image textures continue past texture coordinates 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Wrap {
+
+    /// Tiles the image
+    #[default]
+    Repeat,
+
+    /// Stretches the edges of the image outwards
+    Clamp,
+
+    /// Tiles the image, flipping every other tile so that tiles meet seamlessly
+    Mirror
+}
+
+impl Wrap {
+
+    /// Texel index standing in for 'index' in a row or column of 'size' texels
+    fn apply(&self, index: i64, size: i64) -> usize {
+        let wrapped = match *self {
+            Wrap::Repeat => index.rem_euclid(size),
+            Wrap::Clamp => index.clamp(0, size - 1),
+            Wrap::Mirror => {
+                let i = index.rem_euclid(2 * size);
+                if i < size { i } else { 2 * size - 1 - i }
+            }
+        };
+        wrapped as usize
+    }
+}
+
+/// Image sampled as a texture.
+/// Texels are kept as linear color, converted from sRGB on load if 'srgb' is set, as it should be for images of
+/// colors. Images holding other values, such as reflectivity, are usually stored linear.
+/// HDR images are always linear, and may hold colors brighter than white.
+/// Cloning shares the texels.
+#[derive(Debug, Clone)]
+pub struct ImageTexture {
+
+    /// File the image was loaded from, if any. Scene files written out refer to the image by this path.
+    pub path: Option<PathBuf>,
+    pub filter: TextureFilter,
+    pub wrap: Wrap,
+    pub srgb: bool,
+    width: usize,
+    height: usize,
+    texels: Arc<Vec<[f32; 3]>>
+}
+
+/// Error raised while loading an image texture
+#[derive(Debug)]
+pub struct TextureError {
+    pub path: PathBuf,
+    pub message: String
+}
+
+impl fmt::Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.message)
+    }
+}
+
+impl Error for TextureError {}
+
+impl ImageTexture {
+
+    /// Loads a PNG, JPEG or GIF file as a texture. Alpha is ignored.
+    /// Radiance .hdr and PFM files are loaded as they are, ignoring 'srgb'.
+    pub fn load<P: AsRef<Path>>(path: P, filter: TextureFilter, wrap: Wrap, srgb: bool) -> Result<ImageTexture, TextureError> {
+        let path = path.as_ref();
+        if HdrFormat::from_path(path).is_some() {
+            let image = hdr::load(path).map_err(|e| TextureError { path: path.to_path_buf(), message: e.to_string() })?;
+            return Ok(ImageTexture { path: Some(path.to_path_buf()), ..ImageTexture::from_framebuffer(&image, filter, wrap) });
+        }
+        let image = raster::open(&path.to_string_lossy()).map_err(|e| TextureError {
+            path: path.to_path_buf(),
+            message: match e {
+                RasterError::Io(err) => err.to_string(),
+                RasterError::UnsupportedFormat(ext) => format!("unsupported image format '{}'", ext),
+                RasterError::Decode(_, message) => message,
+                other => format!("{:?}", other)
+            }
+        })?;
+        Ok(ImageTexture { path: Some(path.to_path_buf()), ..ImageTexture::from_image(&image, filter, wrap, srgb) })
+    }
+
+    /// Texture showing an image held in memory. Alpha is ignored.
+    /// Empty images give a black texture.
+    pub fn from_image(image: &Image, filter: TextureFilter, wrap: Wrap, srgb: bool) -> ImageTexture {
+        let decode: Vec<f32> = (0..256)
+            .map(|byte| {
+                let c = byte as f32 / 255.0;
+                if !srgb { c }
+                else if c <= 0.04045 { c / 12.92 }
+                else { ((c + 0.055) / 1.055).powf(2.4) }
+            })
+            .collect();
+        let texels: Vec<[f32; 3]> = image.bytes
+            .chunks(4)
+            .map(|p| [decode[p[0] as usize], decode[p[1] as usize], decode[p[2] as usize]])
+            .collect();
+        let (width, height) = if texels.is_empty() { (0, 0) } else { (image.width as usize, image.height as usize) };
+        ImageTexture { path: None, filter, wrap, srgb, width, height, texels: Arc::new(texels) }
+    }
+
+    /// Texture showing linear colors held in memory, such as a loaded HDR image.
+    /// Empty framebuffers give a black texture.
+    pub fn from_framebuffer(image: &Framebuffer, filter: TextureFilter, wrap: Wrap) -> ImageTexture {
+        let texels: Vec<[f32; 3]> = image.pixels
+            .iter()
+            .map(|c| [c.x as f32, c.y as f32, c.z as f32])
+            .collect();
+        let (width, height) = if texels.is_empty() { (0, 0) } else { (image.width, image.height) };
+        ImageTexture { path: None, filter, wrap, srgb: false, width, height, texels: Arc::new(texels) }
+    }
+
+    /// Color at texture coordinates (u, v), where (0, 0) is the image's bottom-left corner and (1, 1) its top-right
+    pub fn sample(&self, uv: [f64; 2]) -> Vector {
+        if self.texels.is_empty() { return Vector::new(0.0, 0.0, 0.0); }
+
+        // Texel space, running down the image from its top-left corner
+        let x = uv[0] * self.width as f64;
+        let y = (1.0 - uv[1]) * self.height as f64;
+        match self.filter {
+            TextureFilter::Nearest => self.texel(x.floor() as i64, y.floor() as i64),
+            TextureFilter::Bilinear => {
+
+                // Blends between the centers of the four closest texels
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let top = self.texel(x0, y0).interp(&self.texel(x0 + 1, y0), tx);
+                let bottom = self.texel(x0, y0 + 1).interp(&self.texel(x0 + 1, y0 + 1), tx);
+                top.interp(&bottom, ty)
+            }
+        }
+    }
+
+    pub fn width(&self) -> usize { self.width }
+    pub fn height(&self) -> usize { self.height }
+
+    /// Texel at column x and row y, counting from the top-left corner, wrapped onto the image
+    pub fn texel(&self, x: i64, y: i64) -> Vector {
+        let x = self.wrap.apply(x, self.width as i64);
+        let y = self.wrap.apply(y, self.height as i64);
+        let [r, g, b] = self.texels[y * self.width + x];
+        Vector::new(r as f64, g as f64, b as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raster::Color;
+
+    #[test]
+    fn wraps_indices_past_the_edges() {
+        let indices = [-5, -4, -1, 0, 3, 4, 7, 8, 9];
+        let wrapped = |wrap: Wrap| indices.iter().map(|&i| wrap.apply(i, 4)).collect::<Vec<_>>();
+        assert_eq!(wrapped(Wrap::Repeat), vec![3, 0, 3, 0, 3, 0, 3, 0, 1]);
+        assert_eq!(wrapped(Wrap::Clamp), vec![0, 0, 0, 0, 3, 3, 3, 3, 3]);
+        assert_eq!(wrapped(Wrap::Mirror), vec![3, 3, 0, 0, 3, 3, 0, 0, 1]);
+    }
+
+    /// 4 x 1 texture whose texels hold 0, 1, 2 and 3 from left to right
+    fn ramp(filter: TextureFilter, wrap: Wrap) -> ImageTexture {
+        let mut image = Framebuffer::new(4, 1);
+        for x in 0..4 {
+            image.set(x, 0, Vector::new(x as f64, 0.0, 0.0));
+        }
+        ImageTexture::from_framebuffer(&image, filter, wrap)
+    }
+
+    #[test]
+    fn wraps_texture_coordinates_outside_0_to_1() {
+        let red = |texture: &ImageTexture, u: f64| texture.sample([u, 0.5]).x;
+        let repeat = ramp(TextureFilter::Nearest, Wrap::Repeat);
+        let clamp = ramp(TextureFilter::Nearest, Wrap::Clamp);
+        let mirror = ramp(TextureFilter::Nearest, Wrap::Mirror);
+        assert_eq!((red(&repeat, -0.1), red(&repeat, 1.1), red(&repeat, 2.6)), (3.0, 0.0, 2.0));
+        assert_eq!((red(&clamp, -0.1), red(&clamp, 1.1), red(&clamp, -3.0)), (0.0, 3.0, 0.0));
+        assert_eq!((red(&mirror, -0.1), red(&mirror, 1.1), red(&mirror, -1.1)), (0.0, 3.0, 3.0));
+    }
+
+    #[test]
+    fn filters_between_texel_centers() {
+        let red = |texture: &ImageTexture, u: f64| texture.sample([u, 0.5]).x;
+
+        // Texel centers give the texel itself, and points between them blend linearly
+        let texture = ramp(TextureFilter::Bilinear, Wrap::Clamp);
+        for x in 0..4 {
+            assert!((red(&texture, (x as f64 + 0.5) / 4.0) - x as f64).abs() < 1e-9);
+        }
+        assert!((red(&texture, 0.25) - 0.5).abs() < 1e-9);
+        assert!((red(&texture, 0.3) - 0.7).abs() < 1e-9);
+
+        // Between the outer centers and the edges, clamping holds the edge texel, while repeating blends with the
+        // other side of the image
+        assert!((red(&texture, 0.0) - 0.0).abs() < 1e-9);
+        assert!((red(&texture, 1.0) - 3.0).abs() < 1e-9);
+        let texture = ramp(TextureFilter::Bilinear, Wrap::Repeat);
+        assert!((red(&texture, 0.0) - 1.5).abs() < 1e-9);
+        assert!((red(&texture, 1.0) - 1.5).abs() < 1e-9);
+
+        // Rows blend the same way, with v running up the image
+        let mut image = Framebuffer::new(1, 2);
+        image.set(0, 0, Vector::new(1.0, 1.0, 1.0));
+        let texture = ImageTexture::from_framebuffer(&image, TextureFilter::Bilinear, Wrap::Clamp);
+        assert!((texture.sample([0.5, 0.75]).y - 1.0).abs() < 1e-9);
+        assert!((texture.sample([0.5, 0.5]).y - 0.5).abs() < 1e-9);
+        assert!((texture.sample([0.5, 0.25]).y - 0.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn decodes_srgb_images() {
+        let mut image = Image::blank(3, 1);
+        for (x, &byte) in [0u8, 128, 255].iter().enumerate() {
+            image.set_pixel(x as i32, 0, Color { r: byte, g: 10, b: 255, a: 255 }).unwrap();
+        }
+        let srgb = ImageTexture::from_image(&image, TextureFilter::Nearest, Wrap::Clamp, true);
+        let linear = ImageTexture::from_image(&image, TextureFilter::Nearest, Wrap::Clamp, false);
+        assert_eq!(srgb.texel(0, 0).x, 0.0);
+        assert!((srgb.texel(1, 0).x - 0.2158605).abs() < 1e-6);
+        assert!((srgb.texel(2, 0).x - 1.0).abs() < 1e-6);
+
+        // Dark values fall on the linear segment of the curve
+        assert!((srgb.texel(0, 0).y - 10.0 / 255.0 / 12.92).abs() < 1e-6);
+        assert!((linear.texel(1, 0).x - 128.0 / 255.0).abs() < 1e-6);
+    }
+}